@@ -0,0 +1,143 @@
+// Peer availability state machine used by `lightdash fees`.
+//
+// A peer is healthy, degraded or disabled. It moves down as soon as its
+// availability falls below `degraded_availability` or `min_availability`, and
+// moves back up only once it is `availability_recovery_margin` above the
+// threshold it crossed, so a peer hovering around a threshold does not flap.
+// The state is kept per peer in the datastore under
+// `lightdash/availability/<peer_id>`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fee_policy::FeeSettings;
+
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AvailabilityState {
+    /// Forwarding without limits.
+    #[default]
+    Healthy,
+    /// Maximum HTLC capped in proportion to the availability.
+    Degraded,
+    /// Forwarding disabled with 1 msat HTLC limits.
+    Disabled,
+}
+
+/// The persisted state of one peer.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AvailabilityRecord {
+    pub state: AvailabilityState,
+    /// RFC 3339 time the peer entered `state`.
+    pub since: String,
+    /// Availability ratio that caused the transition, when known.
+    pub availability: Option<f64>,
+}
+
+impl AvailabilityState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Disabled => "disabled",
+        }
+    }
+}
+
+impl fmt::Display for AvailabilityState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Next state of a peer. Without an availability ratio the previous state is
+/// kept, so a gap in the availability data neither disables nor re-enables.
+pub fn next_state(
+    previous: Option<AvailabilityState>,
+    availability: Option<f64>,
+    settings: &FeeSettings,
+) -> AvailabilityState {
+    let previous = previous.unwrap_or_default();
+    let Some(availability) = availability else {
+        return previous;
+    };
+    let margin = settings.availability_recovery_margin;
+    let entered = if availability < settings.min_availability {
+        AvailabilityState::Disabled
+    } else if availability < settings.degraded_availability {
+        AvailabilityState::Degraded
+    } else {
+        AvailabilityState::Healthy
+    };
+    let recovered = if availability >= settings.degraded_availability + margin {
+        AvailabilityState::Healthy
+    } else if availability >= settings.min_availability + margin {
+        AvailabilityState::Degraded
+    } else {
+        AvailabilityState::Disabled
+    };
+    if entered > previous {
+        entered
+    } else if recovered < previous {
+        recovered
+    } else {
+        previous
+    }
+}
+
+/// Share of the local balance a degraded peer may use as maximum HTLC: 0 at
+/// `min_availability`, rising linearly to 1 at `degraded_availability`.
+pub fn degraded_htlc_max_factor(availability: Option<f64>, settings: &FeeSettings) -> f64 {
+    let Some(availability) = availability else {
+        return 1.0;
+    };
+    let range = settings.degraded_availability - settings.min_availability;
+    if range <= 0.0 {
+        return 1.0;
+    }
+    ((availability - settings.min_availability) / range).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use AvailabilityState::{Degraded, Disabled, Healthy};
+
+    #[test]
+    fn states_degrade_immediately_below_the_thresholds() {
+        let settings = FeeSettings::default();
+        assert_eq!(next_state(None, Some(0.95), &settings), Healthy);
+        assert_eq!(next_state(Some(Healthy), Some(0.85), &settings), Degraded);
+        assert_eq!(next_state(Some(Healthy), Some(0.5), &settings), Disabled);
+        assert_eq!(next_state(Some(Degraded), Some(0.79), &settings), Disabled);
+    }
+
+    #[test]
+    fn recovery_requires_the_margin_above_the_threshold() {
+        let settings = FeeSettings::default();
+        // just above the disable threshold is not enough to leave disabled
+        assert_eq!(next_state(Some(Disabled), Some(0.81), &settings), Disabled);
+        assert_eq!(next_state(Some(Disabled), Some(0.85), &settings), Degraded);
+        assert_eq!(next_state(Some(Degraded), Some(0.91), &settings), Degraded);
+        assert_eq!(next_state(Some(Degraded), Some(0.95), &settings), Healthy);
+        assert_eq!(next_state(Some(Disabled), Some(0.99), &settings), Healthy);
+    }
+
+    #[test]
+    fn missing_availability_keeps_the_previous_state() {
+        let settings = FeeSettings::default();
+        assert_eq!(next_state(Some(Disabled), None, &settings), Disabled);
+        assert_eq!(next_state(None, None, &settings), Healthy);
+    }
+
+    #[test]
+    fn degraded_htlc_max_scales_with_availability() {
+        let settings = FeeSettings::default();
+        assert_eq!(degraded_htlc_max_factor(Some(0.8), &settings), 0.0);
+        assert!((degraded_htlc_max_factor(Some(0.85), &settings) - 0.5).abs() < 1e-9);
+        assert_eq!(degraded_htlc_max_factor(Some(0.92), &settings), 1.0);
+        assert_eq!(degraded_htlc_max_factor(None, &settings), 1.0);
+    }
+}
@@ -0,0 +1,467 @@
+// Replay the fee controller over processed history to compare what it would
+// have done with what actually happened.
+//
+// Every simulated day looks at the previous 24 hours of archived settled
+// forwards, the local balance from the liquidity history and the simulated
+// ppm, and steps that ppm with the same `fee_state`/`forward_activity`/
+// `adjusted_ppm` functions `lightdash fees` uses. Forwarded volume under the
+// simulated ppm is assumed to scale with (simulated / actual)^-elasticity;
+// balances are taken as they were, since rebalancing is not simulated.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::Forward;
+use crate::fee_policy::FeePolicy;
+use crate::fees::{adjusted_ppm, fee_state, forward_activity, FeeState, ForwardActivity};
+use crate::{forwards, history};
+
+const DAY_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+struct PolicyRecord {
+    observed_at: String,
+    short_channel_id: String,
+    direction: String,
+    base_fee_msat: u64,
+    fee_ppm: u64,
+}
+
+#[derive(Deserialize)]
+struct LiquidityRecord {
+    observed_at: String,
+    peer_id: String,
+    state: String,
+    short_channel_id: Option<String>,
+    local_balance_msat: u64,
+    capacity_msat: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+    base_fee_msat: u64,
+    fee_ppm: u64,
+}
+
+struct Liquidity {
+    peer_id: String,
+    normal: bool,
+    local_balance_msat: u64,
+    capacity_msat: u64,
+}
+
+struct SettledForward {
+    resolved_time: i64,
+    out_msat: u64,
+    fee_msat: u64,
+    payment_hash: Option<String>,
+}
+
+/// History of one channel, as timelines sorted by observation time.
+#[derive(Default)]
+struct ChannelHistory {
+    policy: Vec<(i64, Policy)>,
+    liquidity: Vec<(i64, Liquidity)>,
+    forwards: Vec<SettledForward>,
+}
+
+#[derive(Serialize)]
+pub struct ChannelBacktest {
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub days: Vec<BacktestDay>,
+    pub actual_fees_msat: u64,
+    pub simulated_fees_msat: f64,
+}
+
+#[derive(Serialize)]
+pub struct BacktestDay {
+    pub date: String,
+    pub state: FeeState,
+    pub activity: ForwardActivity,
+    /// Actual ppm at the end of the day.
+    pub actual_ppm: u64,
+    /// Simulated ppm after the day's step.
+    pub simulated_ppm: u64,
+    pub routed_msat: u64,
+    pub actual_fees_msat: u64,
+    pub simulated_fees_msat: f64,
+}
+
+pub fn run_backtest(
+    history_directory: &str,
+    days: u32,
+    elasticity: f64,
+    policy: &FeePolicy,
+    output: Option<&str>,
+) -> Result<(), String> {
+    let directory = Path::new(history_directory);
+    let (node_id, policies): (String, Vec<PolicyRecord>) =
+        history::read_processed_dataset(directory, "channel_policy_history")?;
+    let (_, liquidity): (String, Vec<LiquidityRecord>) =
+        history::read_processed_dataset(directory, "channel_liquidity_history")?;
+    let forwards = forwards::read_archived_forwards(&node_id)?;
+    log::info!(
+        "Backtesting {} policy and {} liquidity change points with {} archived forwards",
+        policies.len(),
+        liquidity.len(),
+        forwards.len()
+    );
+
+    let (channels, end) = channel_histories(policies, liquidity, &forwards)?;
+    let Some(end) = end else {
+        return Err("processed history has no local channel policies".to_string());
+    };
+    let start = end - i64::from(days) * DAY_SECONDS;
+    let results = simulate(&channels, start, end, elasticity, policy);
+    print_report(&results, elasticity);
+
+    if let Some(output) = output {
+        let json = serde_json::to_string_pretty(&results)
+            .map_err(|e| format!("serializing backtest failed: {e}"))?;
+        fs::write(output, json + "\n")
+            .map_err(|e| format!("writing backtest `{output}` failed: {e}"))?;
+        log::info!("Wrote backtest trajectories to {output}");
+    }
+    Ok(())
+}
+
+/// Group the datasets by channel, returning the last local policy observation.
+fn channel_histories(
+    policies: Vec<PolicyRecord>,
+    liquidity: Vec<LiquidityRecord>,
+    forwards: &[Forward],
+) -> Result<(BTreeMap<String, ChannelHistory>, Option<i64>), String> {
+    let mut channels: BTreeMap<String, ChannelHistory> = BTreeMap::new();
+    let mut end = None;
+    for record in policies {
+        if record.direction != "local" {
+            continue;
+        }
+        let observed_at = parse_timestamp(&record.observed_at)?;
+        end = end.max(Some(observed_at));
+        channels
+            .entry(record.short_channel_id)
+            .or_default()
+            .policy
+            .push((
+                observed_at,
+                Policy {
+                    base_fee_msat: record.base_fee_msat,
+                    fee_ppm: record.fee_ppm,
+                },
+            ));
+    }
+    for record in liquidity {
+        let Some(channel) = record
+            .short_channel_id
+            .as_ref()
+            .and_then(|short_channel_id| channels.get_mut(short_channel_id))
+        else {
+            continue;
+        };
+        channel.liquidity.push((
+            parse_timestamp(&record.observed_at)?,
+            Liquidity {
+                peer_id: record.peer_id,
+                normal: record.state == "CHANNELD_NORMAL",
+                local_balance_msat: record.local_balance_msat,
+                capacity_msat: record.capacity_msat,
+            },
+        ));
+    }
+    for forward in forwards
+        .iter()
+        .filter(|forward| forward.status == "settled")
+    {
+        let (Some(out_channel), Some(resolved_time)) =
+            (&forward.out_channel, forward.resolved_time)
+        else {
+            continue;
+        };
+        if let Some(channel) = channels.get_mut(out_channel) {
+            channel.forwards.push(SettledForward {
+                resolved_time: resolved_time as i64,
+                out_msat: forward.out_msat.unwrap_or_default(),
+                fee_msat: forward.fee_msat.unwrap_or_default(),
+                payment_hash: forward.payment_hash.clone(),
+            });
+        }
+    }
+    for channel in channels.values_mut() {
+        channel.policy.sort_by_key(|(observed_at, _)| *observed_at);
+        channel
+            .liquidity
+            .sort_by_key(|(observed_at, _)| *observed_at);
+        channel
+            .forwards
+            .sort_by_key(|forward| forward.resolved_time);
+    }
+    Ok((channels, end))
+}
+
+fn simulate(
+    channels: &BTreeMap<String, ChannelHistory>,
+    start: i64,
+    end: i64,
+    elasticity: f64,
+    policy: &FeePolicy,
+) -> Vec<ChannelBacktest> {
+    let mut results = Vec::new();
+    for (short_channel_id, history) in channels {
+        let Some(peer_id) = history
+            .liquidity
+            .last()
+            .map(|(_, liquidity)| liquidity.peer_id.clone())
+        else {
+            continue;
+        };
+        let channel_policy = policy.for_channel(&peer_id, short_channel_id);
+        if channel_policy.excluded {
+            continue;
+        }
+        let settings = &channel_policy.settings;
+        let mut simulated_ppm = None;
+        let mut result = ChannelBacktest {
+            short_channel_id: short_channel_id.clone(),
+            peer_id,
+            days: Vec::new(),
+            actual_fees_msat: 0,
+            simulated_fees_msat: 0.0,
+        };
+
+        let mut day_end = start + DAY_SECONDS;
+        while day_end <= end {
+            let day_start = day_end - DAY_SECONDS;
+            let day = day_end;
+            day_end += DAY_SECONDS;
+            let (Some(in_force), Some(actual), Some(liquidity)) = (
+                at(&history.policy, day_start),
+                at(&history.policy, day),
+                at(&history.liquidity, day),
+            ) else {
+                continue;
+            };
+            if !liquidity.normal {
+                continue;
+            }
+            let current_ppm = *simulated_ppm.get_or_insert(in_force.fee_ppm);
+            let volume_factor = if in_force.fee_ppm == 0 {
+                1.0
+            } else {
+                (current_ppm as f64 / in_force.fee_ppm as f64).powf(-elasticity)
+            };
+
+            let first = history
+                .forwards
+                .partition_point(|forward| forward.resolved_time <= day_start);
+            let last = history
+                .forwards
+                .partition_point(|forward| forward.resolved_time <= day);
+            let window = &history.forwards[first..last];
+            let routed_msat = window.iter().map(|forward| forward.out_msat).sum::<u64>();
+            let actual_fees_msat = window.iter().map(|forward| forward.fee_msat).sum::<u64>();
+            let simulated_fees_msat = window
+                .iter()
+                .map(|forward| {
+                    in_force.base_fee_msat as f64
+                        + forward.out_msat as f64 * current_ppm as f64 / 1_000_000.0
+                })
+                .sum::<f64>()
+                * volume_factor;
+
+            let settled_out_msat = history.forwards[..last]
+                .iter()
+                .map(|forward| forward.out_msat)
+                .sum::<u64>();
+            let state = fee_state(
+                liquidity.local_balance_msat / 1000,
+                settled_out_msat,
+                settings,
+            );
+            let activity = forward_activity(
+                distinct_payments(window),
+                (routed_msat as f64 * volume_factor) as u64,
+                liquidity.capacity_msat,
+                settings,
+            );
+            let next_ppm = channel_policy
+                .fixed_ppm
+                .unwrap_or_else(|| adjusted_ppm(current_ppm, state, activity, settings));
+            simulated_ppm = Some(next_ppm);
+
+            result.actual_fees_msat += actual_fees_msat;
+            result.simulated_fees_msat += simulated_fees_msat;
+            result.days.push(BacktestDay {
+                date: DateTime::<Utc>::from_timestamp(day, 0)
+                    .map(|date| date.format("%Y-%m-%d").to_string())
+                    .unwrap_or_default(),
+                state,
+                activity,
+                actual_ppm: actual.fee_ppm,
+                simulated_ppm: next_ppm,
+                routed_msat,
+                actual_fees_msat,
+                simulated_fees_msat,
+            });
+        }
+        if !result.days.is_empty() {
+            results.push(result);
+        }
+    }
+    results
+}
+
+fn distinct_payments(forwards: &[SettledForward]) -> usize {
+    let hashes: HashSet<_> = forwards
+        .iter()
+        .filter_map(|forward| forward.payment_hash.as_deref())
+        .collect();
+    hashes.len()
+        + forwards
+            .iter()
+            .filter(|forward| forward.payment_hash.is_none())
+            .count()
+}
+
+/// The last value observed at or before `timestamp`.
+fn at<T>(timeline: &[(i64, T)], timestamp: i64) -> Option<&T> {
+    let index = timeline.partition_point(|(observed_at, _)| *observed_at <= timestamp);
+    index.checked_sub(1).map(|index| &timeline[index].1)
+}
+
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|datetime| datetime.timestamp())
+        .map_err(|e| format!("invalid observed_at `{value}`: {e}"))
+}
+
+fn print_report(results: &[ChannelBacktest], elasticity: f64) {
+    println!(
+        "{:<18} {:>5} {:>15} {:>15} {:>12} {:>12} {:>8}",
+        "channel", "days", "actual ppm", "simulated ppm", "actual sat", "sim sat", "delta"
+    );
+    let mut actual_total = 0;
+    let mut simulated_total = 0.0;
+    for result in results {
+        let (Some(first), Some(last)) = (result.days.first(), result.days.last()) else {
+            continue;
+        };
+        actual_total += result.actual_fees_msat;
+        simulated_total += result.simulated_fees_msat;
+        println!(
+            "{:<18} {:>5} {:>15} {:>15} {:>12} {:>12.0} {:>8}",
+            result.short_channel_id,
+            result.days.len(),
+            format!("{}->{}", first.actual_ppm, last.actual_ppm),
+            format!("{}->{}", first.simulated_ppm, last.simulated_ppm),
+            result.actual_fees_msat / 1000,
+            result.simulated_fees_msat / 1000.0,
+            delta_percent(result.actual_fees_msat, result.simulated_fees_msat),
+        );
+    }
+    println!(
+        "total: {} channels, actual {} sat, simulated {:.0} sat ({}) at elasticity {elasticity}",
+        results.len(),
+        actual_total / 1000,
+        simulated_total / 1000.0,
+        delta_percent(actual_total, simulated_total)
+    );
+}
+
+fn delta_percent(actual_msat: u64, simulated_msat: f64) -> String {
+    if actual_msat == 0 {
+        return "n/a".to_string();
+    }
+    format!(
+        "{:+.1}%",
+        (simulated_msat / actual_msat as f64 - 1.0) * 100.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY0: i64 = 1_700_000_000;
+
+    fn channel(forwards: Vec<SettledForward>, local_balance_msat: u64) -> ChannelHistory {
+        ChannelHistory {
+            policy: vec![(
+                DAY0,
+                Policy {
+                    base_fee_msat: 0,
+                    fee_ppm: 100,
+                },
+            )],
+            liquidity: vec![(
+                DAY0,
+                Liquidity {
+                    peer_id: "peer".to_string(),
+                    normal: true,
+                    local_balance_msat,
+                    capacity_msat: 2_000_000_000,
+                },
+            )],
+            forwards,
+        }
+    }
+
+    #[test]
+    fn idle_channels_follow_the_decrease_schedule() {
+        let channels = BTreeMap::from([("1x1x1".to_string(), channel(Vec::new(), 1_000_000_000))]);
+        let results = simulate(
+            &channels,
+            DAY0,
+            DAY0 + 3 * DAY_SECONDS,
+            1.0,
+            &FeePolicy::default(),
+        );
+        let ppm: Vec<_> = results[0]
+            .days
+            .iter()
+            .map(|day| day.simulated_ppm)
+            .collect();
+        // Bootstrap channels drop 15% per idle day.
+        assert_eq!(ppm, [85, 72, 61]);
+        assert!(results[0]
+            .days
+            .iter()
+            .all(|day| day.actual_ppm == 100 && day.state == FeeState::Bootstrap));
+    }
+
+    #[test]
+    fn counterfactual_revenue_scales_volume_by_elasticity() {
+        let forwards = (1..=2)
+            .map(|day| SettledForward {
+                resolved_time: DAY0 + day * DAY_SECONDS - 60,
+                out_msat: 10_000_000,
+                fee_msat: 1_000,
+                payment_hash: Some(format!("hash-{day}")),
+            })
+            .collect();
+        let channels = BTreeMap::from([("1x1x1".to_string(), channel(forwards, 1_000_000_000))]);
+        let results = simulate(
+            &channels,
+            DAY0,
+            DAY0 + 2 * DAY_SECONDS,
+            1.0,
+            &FeePolicy::default(),
+        );
+        let days = &results[0].days;
+        // The first day runs at the actual ppm, so the revenue matches.
+        assert_eq!(days[0].actual_fees_msat, 1_000);
+        assert!((days[0].simulated_fees_msat - 1_000.0).abs() < 1e-6);
+        // One payment of 0.5% of the capacity is the light grade.
+        assert_eq!(days[0].activity, ForwardActivity::Light);
+        assert_eq!(days[0].simulated_ppm, 102);
+        // At 102 ppm and elasticity 1 the volume shrinks by 100/102 and the
+        // revenue stays the same.
+        assert!((days[1].simulated_fees_msat - 1_000.0).abs() < 1e-6);
+        assert_eq!(results[0].actual_fees_msat, 2_000);
+    }
+}
@@ -105,7 +105,13 @@ pub fn run_channels(dir: &str, output_dir: &str) {
             let timestamp = only_name.split(".").next().unwrap().parse::<u64>().unwrap();
 
             // Read the channel data from the compressed file
-            let list_channels = cmd::read_xz_channels(&filename);
+            let list_channels = match cmd::read_xz_channels(&filename) {
+                Ok(list_channels) => list_channels,
+                Err(e) => {
+                    log::warn!("Skipping unreadable channel file {filename}: {e}");
+                    continue;
+                }
+            };
             let _channel_count = list_channels.channels.len();
 
             for channel in list_channels.channels {
@@ -1,18 +1,36 @@
 use chrono::{DateTime, Utc};
 use flate2::read::GzDecoder;
-use serde::Deserialize;
+use lzma_rust2::XzReader;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs::{self, File};
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output as ProcessOutput};
 use std::sync::OnceLock;
+use std::time::Duration;
 
-use crate::error_panic;
+use crate::error::LightdashError;
+use crate::replay;
+use crate::rpc::{self, RpcClient, SshTunnel};
 
 static SSH_DESTINATION: OnceLock<String> = OnceLock::new();
+static RPC_CLIENT: OnceLock<RpcClient> = OnceLock::new();
 const DEFAULT_LOCAL_AVAILDB_PATH: &str = ".lightning/bitcoin/summars/availdb.json";
+const DEFAULT_LOCAL_RPC_PATH: &str = ".lightning/bitcoin/lightning-rpc";
 const TEST_AVAILDB_PATH: &str = "test-json/availdb.json";
+/// Share one SSH connection between the commands of a run, and keep it open
+/// briefly for the next run. Concurrent commands that lose the race to become
+/// the master fall back to their own connection.
+const SSH_MULTIPLEXING_OPTIONS: [&str; 6] = [
+    "-o",
+    "ControlMaster=auto",
+    "-o",
+    "ControlPath=%d/.ssh/lightdash-%C",
+    "-o",
+    "ControlPersist=60",
+];
 pub const GETROUTES_LAYERS: [&str; 3] = ["auto.localchans", "auto.sourcefree", "xpay"];
 
 pub fn configure_ssh(destination: Option<String>) -> Result<(), String> {
@@ -31,31 +49,93 @@ pub fn configure_ssh(destination: Option<String>) -> Result<(), String> {
         .map_err(|_| "SSH destination was already configured".to_string())
 }
 
+/// Talk to `lightning-rpc` directly instead of spawning `lightning-cli`.
+///
+/// Without `--ssh` the given socket, or the default one when it exists, is used
+/// locally. With `--ssh` the given remote socket is forwarded through SSH and
+/// the returned tunnel must be kept alive for as long as commands are issued.
+pub fn configure_rpc(
+    rpc_file: Option<String>,
+    timeout: Duration,
+) -> Result<Option<SshTunnel>, String> {
+    let (socket_path, tunnel) = if let Some(destination) = SSH_DESTINATION.get() {
+        let Some(remote_path) = rpc_file else {
+            return Ok(None);
+        };
+        if !remote_path.starts_with('/') {
+            return Err(format!(
+                "--rpc-file `{remote_path}` must be an absolute path when used with --ssh"
+            ));
+        }
+        let tunnel = SshTunnel::open(destination, &remote_path)?;
+        (tunnel.local_path().to_path_buf(), Some(tunnel))
+    } else if let Some(path) = rpc_file {
+        let path = expand_local_home_path(&path)?;
+        if !path.exists() {
+            return Err(format!("RPC socket `{}` does not exist", path.display()));
+        }
+        (path, None)
+    } else {
+        let Some(home) = std::env::var_os("HOME") else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(home).join(DEFAULT_LOCAL_RPC_PATH);
+        if !path.exists() {
+            log::debug!(
+                "RPC socket `{}` not found, using lightning-cli",
+                path.display()
+            );
+            return Ok(None);
+        }
+        (path, None)
+    };
+
+    log::debug!("Using JSON-RPC socket `{}`", socket_path.display());
+    RPC_CLIENT
+        .set(RpcClient::new(socket_path).with_timeout(timeout))
+        .map_err(|_| "RPC socket was already configured".to_string())?;
+    Ok(tunnel)
+}
+
 pub fn using_test_data() -> bool {
-    cfg!(debug_assertions) && SSH_DESTINATION.get().is_none()
+    cfg!(debug_assertions) && SSH_DESTINATION.get().is_none() && !replay::active()
 }
 
 pub(crate) fn using_ssh() -> bool {
     SSH_DESTINATION.get().is_some()
 }
 
-pub(crate) fn remote_command_output(cmd: &str, args: &[&str]) -> Result<Vec<u8>, String> {
+pub(crate) fn remote_command_output(cmd: &str, args: &[&str]) -> Result<Vec<u8>, LightdashError> {
     let destination = SSH_DESTINATION
         .get()
-        .ok_or_else(|| "remote command requested without --ssh".to_string())?;
+        .ok_or_else(|| LightdashError::transport(cmd, "remote command requested without --ssh"))?;
     let (description, result) = execute_ssh_command(destination, cmd, args);
-    let output = result.map_err(|e| format!("executing `{description}` failed: {e}"))?;
+    let output = result.map_err(|e| LightdashError::transport(&description, e))?;
     if !output.status.success() {
-        return Err(format!(
-            "`{description}` exited with status {}: {}",
-            output.status,
-            String::from_utf8_lossy(&output.stderr).trim()
+        return Err(LightdashError::transport(
+            description,
+            format!(
+                "exited with status {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
         ));
     }
     Ok(output.stdout)
 }
 
-pub fn read_availdb_json(path: Option<&str>) -> Result<Value, String> {
+/// The Summars availdb, served from and written to the fixture directory when
+/// replaying or recording.
+pub fn read_availdb_json(path: Option<&str>) -> Result<Value, LightdashError> {
+    if let Some(result) = replay::replayed_availdb() {
+        return result;
+    }
+    let result = read_node_availdb_json(path);
+    replay::record_availdb(&result);
+    result
+}
+
+fn read_node_availdb_json(path: Option<&str>) -> Result<Value, LightdashError> {
     let configured_path = path
         .map(str::to_string)
         .or_else(|| std::env::var("AVAILDB_PATH").ok());
@@ -69,119 +149,182 @@ pub fn read_availdb_json(path: Option<&str>) -> Result<Value, String> {
     }
 
     let local_path = if let Some(path) = configured_path {
-        expand_local_home_path(&path)?
+        expand_local_home_path(&path).map_err(|e| {
+            LightdashError::io(
+                "resolving availdb path",
+                io::Error::new(io::ErrorKind::NotFound, e),
+            )
+        })?
     } else if using_test_data() {
         PathBuf::from(TEST_AVAILDB_PATH)
     } else {
-        let home = std::env::var_os("HOME")
-            .ok_or_else(|| "HOME is not set; pass --availdb explicitly".to_string())?;
+        let home = std::env::var_os("HOME").ok_or_else(|| {
+            LightdashError::io(
+                "resolving availdb path",
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "HOME is not set; pass --availdb explicitly",
+                ),
+            )
+        })?;
         PathBuf::from(home).join(DEFAULT_LOCAL_AVAILDB_PATH)
     };
 
-    let content = fs::read_to_string(&local_path)
-        .map_err(|e| format!("reading availdb `{}` failed: {e}", local_path.display()))?;
+    let content = fs::read_to_string(&local_path).map_err(|e| {
+        LightdashError::io(format!("reading availdb `{}`", local_path.display()), e)
+    })?;
     serde_json::from_str(&content)
-        .map_err(|e| format!("parsing availdb `{}` failed: {e}", local_path.display()))
+        .map_err(|e| LightdashError::json(format!("availdb `{}`", local_path.display()), e))
 }
 
-pub fn list_funds() -> ListFunds {
+pub fn list_funds() -> Result<ListFunds, LightdashError> {
     let v = if using_test_data() {
-        gz_json_file("test-json/listfunds.gz")
+        gz_json_file("test-json/listfunds.gz")?
     } else {
-        cmd_result("lightning-cli", &["listfunds"])
+        cmd_result("lightning-cli", &["listfunds"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("listfunds", v)
 }
 
-pub fn list_nodes() -> ListNodes {
+pub fn list_nodes() -> Result<ListNodes, LightdashError> {
     let v = if using_test_data() {
-        gz_json_file("test-json/listnodes.gz")
+        gz_json_file("test-json/listnodes.gz")?
     } else {
-        cmd_result("lightning-cli", &["listnodes"])
+        cmd_result("lightning-cli", &["listnodes"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("listnodes", v)
 }
 
-pub fn list_channels() -> ListChannels {
+pub fn list_channels() -> Result<ListChannels, LightdashError> {
     let v = if using_test_data() {
-        gz_json_file("test-json/listchannels.gz")
+        gz_json_file("test-json/listchannels.gz")?
     } else {
-        cmd_result("lightning-cli", &["listchannels"])
+        cmd_result("lightning-cli", &["listchannels"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("listchannels", v)
 }
 
-pub fn read_xz_channels(path: &str) -> ListChannels {
-    let v = cmd_result("xzcat", &[path]);
-    serde_json::from_value(v).unwrap()
+/// Unparsed response of a listing command such as `listchannels`, kept
+/// verbatim so archives do not depend on which fields lightdash models.
+pub fn raw_listing(method: &str) -> Result<Value, LightdashError> {
+    if using_test_data() {
+        let gz_path = format!("test-json/{method}.gz");
+        if Path::new(&gz_path).exists() {
+            return gz_json_file(&gz_path);
+        }
+        return read_xz_json(format!("test-json/{method}.xz"));
+    }
+    cmd_result("lightning-cli", &[method])
 }
 
-pub fn read_xz_funds(path: &str) -> ListFunds {
-    let v = cmd_result("xzcat", &[path]);
-    serde_json::from_value(v).unwrap()
+pub fn read_xz_channels(path: &str) -> Result<ListChannels, LightdashError> {
+    read_xz_json(path)
 }
 
-pub fn list_peers() -> ListPeers {
-    let v = if using_test_data() {
-        gz_json_file("test-json/listpeers.gz")
-    } else {
-        cmd_result("lightning-cli", &["listpeers"])
-    };
-    serde_json::from_value(v).unwrap()
+pub fn read_xz_funds(path: &str) -> Result<ListFunds, LightdashError> {
+    read_xz_json(path)
 }
 
-pub fn list_peer_channels() -> ListPeerChannels {
-    let v = if using_test_data() {
-        cmd_result("xzcat", &["test-json/listpeerchannels.xz"])
-    } else {
-        cmd_result("lightning-cli", &["listpeerchannels"])
-    };
-    serde_json::from_value(v).unwrap()
+/// Decode an xz compressed JSON file straight into `T`, without buffering
+/// the decompressed document or spawning `xzcat`.
+pub fn read_xz_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, LightdashError> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .map_err(|e| LightdashError::io(format!("opening `{}`", path.display()), e))?;
+    let decoder = BufReader::new(XzReader::new(BufReader::new(file), true));
+    serde_json::from_reader(decoder)
+        .map_err(|e| LightdashError::json(format!("xz JSON `{}`", path.display()), e))
 }
 
-pub fn list_forwards() -> ListForwards {
+pub fn list_peers() -> Result<ListPeers, LightdashError> {
     let v = if using_test_data() {
-        cmd_result("xzcat", &["test-json/listforwards.xz"])
+        gz_json_file("test-json/listpeers.gz")?
     } else {
-        cmd_result("lightning-cli", &["listforwards"])
+        cmd_result("lightning-cli", &["listpeers"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("listpeers", v)
 }
 
-pub fn list_closed_channels() -> ListClosedChannels {
+pub fn list_peer_channels() -> Result<ListPeerChannels, LightdashError> {
+    if using_test_data() {
+        return read_xz_json("test-json/listpeerchannels.xz");
+    }
+    let v = cmd_result("lightning-cli", &["listpeerchannels"])?;
+    parse_value("listpeerchannels", v)
+}
+
+pub fn list_forwards() -> Result<ListForwards, LightdashError> {
+    if using_test_data() {
+        return read_xz_json("test-json/listforwards.xz");
+    }
+    let v = cmd_result("lightning-cli", &["listforwards"])?;
+    parse_value("listforwards", v)
+}
+
+/// Fetch one page of `listforwards` ordered by `index`, starting at `start`.
+pub fn list_forwards_page(
+    index: ForwardIndex,
+    start: u64,
+    limit: u64,
+) -> Result<Vec<Forward>, LightdashError> {
+    let index_arg = format!("index={}", index.as_str());
+    let start_arg = format!("start={start}");
+    let limit_arg = format!("limit={limit}");
+    let v = cmd_result(
+        "lightning-cli",
+        &["listforwards", "-k", &index_arg, &start_arg, &limit_arg],
+    )?;
+    Ok(parse_value::<ListForwards>("listforwards page", v)?.forwards)
+}
+
+/// Current value of a `listforwards` index. `wait` returns at once for a value
+/// the index already reached.
+pub fn current_forwards_index(index: ForwardIndex) -> Result<u64, LightdashError> {
+    let v = cmd_result("lightning-cli", &["wait", "forwards", index.as_str(), "0"])?;
+    v.get(index.as_str())
+        .and_then(Value::as_u64)
+        .ok_or_else(|| {
+            LightdashError::transport(
+                "wait forwards",
+                format!("response without {}", index.as_str()),
+            )
+        })
+}
+
+pub fn list_closed_channels() -> Result<ListClosedChannels, LightdashError> {
     let v = if using_test_data() {
-        gz_json_file("test-json/listclosedchannels.gz")
+        gz_json_file("test-json/listclosedchannels.gz")?
     } else {
-        cmd_result("lightning-cli", &["listclosedchannels"])
+        cmd_result("lightning-cli", &["listclosedchannels"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("listclosedchannels", v)
 }
 
-pub fn bkpr_list_account_events() -> BkprListAccountEvents {
+pub fn bkpr_list_account_events() -> Result<BkprListAccountEvents, LightdashError> {
     let v = if using_test_data() {
-        gz_json_file("test-json/bkpr-listaccountevents.gz")
+        gz_json_file("test-json/bkpr-listaccountevents.gz")?
     } else {
-        cmd_result("lightning-cli", &["bkpr-listaccountevents"])
+        cmd_result("lightning-cli", &["bkpr-listaccountevents"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("bkpr-listaccountevents", v)
 }
 
-pub fn bkpr_list_income() -> BkprListIncome {
+pub fn bkpr_list_income() -> Result<BkprListIncome, LightdashError> {
     let v = if using_test_data() {
-        cmd_result("cat", &["test-json/bkpr-listincome"])
+        cmd_result("cat", &["test-json/bkpr-listincome"])?
     } else {
-        cmd_result("lightning-cli", &["bkpr-listincome"])
+        cmd_result("lightning-cli", &["bkpr-listincome"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("bkpr-listincome", v)
 }
 
-pub fn get_info() -> GetInfo {
+pub fn get_info() -> Result<GetInfo, LightdashError> {
     let v = if using_test_data() {
-        cmd_result("cat", &["test-json/getinfo"])
+        cmd_result("cat", &["test-json/getinfo"])?
     } else {
-        cmd_result("lightning-cli", &["getinfo"])
+        cmd_result("lightning-cli", &["getinfo"])?
     };
-    serde_json::from_value(v).unwrap()
+    parse_value("getinfo", v)
 }
 
 pub fn get_routes(
@@ -189,14 +332,33 @@ pub fn get_routes(
     destination: &str,
     amount_msat: u64,
     max_fee_msat: u64,
-) -> Result<GetRoutesOutcome, String> {
-    let v = if using_test_data() {
-        Ok(cmd_result("cat", &["test-json/getroutes"]))
+) -> Result<GetRoutesOutcome, LightdashError> {
+    get_routes_with_layers(source, destination, amount_msat, max_fee_msat, &[], 9)
+}
+
+/// Single-part `getroutes` over `GETROUTES_LAYERS` plus `extra_layers`,
+/// delivering `amount_msat` with `final_cltv` blocks left.
+pub fn get_routes_with_layers(
+    source: &str,
+    destination: &str,
+    amount_msat: u64,
+    max_fee_msat: u64,
+    extra_layers: &[&str],
+    final_cltv: u64,
+) -> Result<GetRoutesOutcome, LightdashError> {
+    let result = if using_test_data() {
+        cmd_result("cat", &["test-json/getroutes"])
     } else {
         let amount_msat = format!("{amount_msat}msat");
         let max_fee_msat = format!("{max_fee_msat}msat");
-        let layers = serde_json::to_string(&GETROUTES_LAYERS).expect("route layers serialize");
-        cmd_result_fallible(
+        let layers: Vec<&str> = GETROUTES_LAYERS
+            .iter()
+            .chain(extra_layers)
+            .copied()
+            .collect();
+        let layers = serde_json::to_string(&layers).expect("route layers serialize");
+        let final_cltv = final_cltv.to_string();
+        cmd_result(
             "lightning-cli",
             &[
                 "getroutes",
@@ -205,20 +367,20 @@ pub fn get_routes(
                 &amount_msat,
                 &layers,
                 &max_fee_msat,
-                "9",
+                &final_cltv,
                 "2016",
                 "1",
             ],
         )
-    }?;
-    Ok(parse_get_routes_outcome(v))
+    };
+    match result {
+        Ok(v) => Ok(GetRoutesOutcome::Found(parse_value("getroutes", v)?)),
+        Err(LightdashError::Rpc { message, .. }) => Ok(parse_get_routes_failure(&message)),
+        Err(e) => Err(e),
+    }
 }
 
-fn parse_get_routes_outcome(v: Value) -> GetRoutesOutcome {
-    if let Ok(routes) = serde_json::from_value(v.clone()) {
-        return GetRoutesOutcome::Found(routes);
-    }
-    let message = v.get("message").and_then(Value::as_str).unwrap_or_default();
+fn parse_get_routes_failure(message: &str) -> GetRoutesOutcome {
     if message.contains("timed out") || message.contains("deadline") {
         GetRoutesOutcome::TimedOut
     } else {
@@ -227,33 +389,86 @@ fn parse_get_routes_outcome(v: Value) -> GetRoutesOutcome {
 }
 
 /// Sign a message with the node's key for authentication purposes
-pub fn signmessage(message: &str) -> String {
-    let v = cmd_result("lightning-cli", &["signmessage", message]);
-    serde_json::from_value::<SignMessageResponse>(v)
-        .unwrap()
-        .zbase
-}
-
-pub fn cmd_result(cmd: &str, args: &[impl AsRef<str>]) -> Value {
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let (description, result) = execute_command(cmd, &args);
-    let data = match result {
-        Ok(data) => data,
-        Err(e) => {
-            error_panic!("executing `{description}` returned {e:?}");
-        }
-    };
-    let s = std::str::from_utf8(&data.stdout).unwrap();
-    match serde_json::from_str(s) {
-        Ok(v) => v,
-        Err(e) => {
-            let stderr = std::str::from_utf8(&data.stderr).unwrap_or("<stderr is not utf8>");
-            error_panic!(
-                "executing `{description}` exited with status {} and stdout `{s}` stderr `{stderr}`; parsing json returned {e:?}",
-                data.status
-            );
+pub fn signmessage(message: &str) -> Result<String, LightdashError> {
+    let v = cmd_result("lightning-cli", &["signmessage", message])?;
+    Ok(parse_value::<SignMessageResponse>("signmessage", v)?.zbase)
+}
+
+/// Name of the `setchannel` parameter setting an inbound fee ppm, when the
+/// node's `setchannel` has one according to `help setchannel`.
+pub fn setchannel_inbound_fee_parameter() -> Result<Option<String>, LightdashError> {
+    if using_test_data() {
+        return Ok(None);
+    }
+    let v = cmd_result("lightning-cli", &["help", "setchannel"])?;
+    Ok(inbound_fee_parameter(&parse_value("help", v)?))
+}
+
+fn inbound_fee_parameter(help: &Help) -> Option<String> {
+    help.help
+        .iter()
+        .flat_map(|entry| entry.command.split_whitespace())
+        .map(|token| token.trim_matches(|c| c == '[' || c == ']'))
+        .find(|parameter| parameter.contains("inbound") && parameter.contains("ppm"))
+        .map(str::to_string)
+}
+
+/// Run `cmd` and parse its standard output as JSON.
+///
+/// `lightning-cli` calls are served from recordings with `--replay`, go through
+/// the native JSON-RPC client when configured, and are recorded by
+/// `lightdash record`. A JSON error object printed by a failing `lightning-cli`
+/// is returned as [`LightdashError::Rpc`].
+pub fn cmd_result(cmd: &str, args: &[impl AsRef<str>]) -> Result<Value, LightdashError> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_ref()).collect();
+    if cmd != "lightning-cli" {
+        return execute_json(cmd, &args);
+    }
+    if let Some(result) = replay::replayed_response(&args) {
+        return result;
+    }
+    let result = native_rpc_result(&args).unwrap_or_else(|| execute_json(cmd, &args));
+    replay::record_response(&args, &result);
+    result
+}
+
+fn execute_json(cmd: &str, args: &[&str]) -> Result<Value, LightdashError> {
+    let (description, result) = execute_command(cmd, args);
+    let data = result.map_err(|e| LightdashError::transport(&description, e))?;
+    let stdout = std::str::from_utf8(&data.stdout).map_err(|e| {
+        LightdashError::transport(&description, format!("output is not UTF-8: {e}"))
+    })?;
+    let parsed = serde_json::from_str::<Value>(stdout);
+    if !data.status.success() {
+        if let Some((code, message, data)) = parsed.as_ref().ok().and_then(rpc_error_object) {
+            return Err(LightdashError::Rpc {
+                command: description,
+                code,
+                message,
+                data,
+            });
         }
+        let stderr = String::from_utf8_lossy(&data.stderr);
+        return Err(LightdashError::transport(
+            description,
+            format!("exited with status {}: {}", data.status, stderr.trim()),
+        ));
     }
+    parsed.map_err(|e| LightdashError::json(format!("`{description}` output"), e))
+}
+
+fn rpc_error_object(value: &Value) -> Option<(i64, String, Option<Value>)> {
+    let code = value.get("code")?.as_i64()?;
+    let message = value
+        .get("message")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    Some((code, message.to_string(), value.get("data").cloned()))
+}
+
+fn parse_value<T: DeserializeOwned>(context: &str, value: Value) -> Result<T, LightdashError> {
+    serde_json::from_value(value)
+        .map_err(|e| LightdashError::json(format!("{context} response"), e))
 }
 
 fn execute_command(cmd: &str, args: &[&str]) -> (String, io::Result<ProcessOutput>) {
@@ -273,19 +488,30 @@ fn execute_command(cmd: &str, args: &[&str]) -> (String, io::Result<ProcessOutpu
     (description, result)
 }
 
-fn cmd_result_fallible(cmd: &str, args: &[impl AsRef<str>]) -> Result<Value, String> {
-    let args: Vec<&str> = args.iter().map(|arg| arg.as_ref()).collect();
-    let (description, result) = execute_command(cmd, &args);
-    let data = result.map_err(|error| format!("executing `{description}` failed: {error}"))?;
-    let stdout = std::str::from_utf8(&data.stdout)
-        .map_err(|error| format!("`{description}` returned non-UTF-8 output: {error}"))?;
-    serde_json::from_str(stdout).map_err(|error| {
-        let stderr = std::str::from_utf8(&data.stderr).unwrap_or("<stderr is not utf8>");
-        format!(
-            "executing `{description}` exited with status {} and stderr `{stderr}`; parsing JSON returned {error}",
-            data.status
-        )
-    })
+/// Serve a `lightning-cli` invocation through the native client when configured.
+fn native_rpc_result(args: &[&str]) -> Option<Result<Value, LightdashError>> {
+    let client = RPC_CLIENT.get()?;
+    let command = args.join(" ");
+    let result = match rpc::cli_request(args) {
+        // Parsed from the object `lightning-cli` would print, so both
+        // transports return the same error
+        Ok((method, params)) => client.call(&method, params).map_err(|error| {
+            match error.to_error_object().as_ref().and_then(rpc_error_object) {
+                Some((code, message, data)) => LightdashError::Rpc {
+                    command,
+                    code,
+                    message,
+                    data,
+                },
+                None => LightdashError::transport(
+                    format!("{command} on {}", client.socket_path().display()),
+                    error,
+                ),
+            }
+        }),
+        Err(e) => Err(LightdashError::transport(command, e)),
+    };
+    Some(result)
 }
 
 fn lightning_cli_json_args<'a>(args: &[&'a str]) -> Vec<&'a str> {
@@ -304,26 +530,26 @@ fn execute_ssh_command(
     let description = format!("ssh -C {destination} {remote_command}");
     let result = Command::new("ssh")
         .arg("-C")
+        .args(SSH_MULTIPLEXING_OPTIONS)
         .arg(destination)
         .arg(&remote_command)
         .output();
     (description, result)
 }
 
-fn read_remote_json_file(destination: &str, path: &str) -> Result<Value, String> {
+fn read_remote_json_file(destination: &str, path: &str) -> Result<Value, LightdashError> {
     let (description, result) = execute_ssh_command(destination, "cat", &["--", path]);
-    let data = result.map_err(|e| format!("executing `{description}` failed: {e}"))?;
-    let stdout = std::str::from_utf8(&data.stdout)
-        .map_err(|e| format!("`{description}` returned non-UTF-8 output: {e}"))?;
+    let data = result.map_err(|e| LightdashError::transport(&description, e))?;
     if !data.status.success() {
-        let stderr = std::str::from_utf8(&data.stderr).unwrap_or("<stderr is not utf8>");
-        return Err(format!(
-            "`{description}` exited with status {}: {stderr}",
-            data.status
+        let stderr = String::from_utf8_lossy(&data.stderr);
+        return Err(LightdashError::transport(
+            description,
+            format!("exited with status {}: {}", data.status, stderr.trim()),
         ));
     }
 
-    serde_json::from_str(stdout).map_err(|e| format!("parsing `{description}` output failed: {e}"))
+    serde_json::from_slice(&data.stdout)
+        .map_err(|e| LightdashError::json(format!("`{description}` output"), e))
 }
 
 fn expand_local_home_path(path: &str) -> Result<PathBuf, String> {
@@ -359,14 +585,11 @@ fn shell_quote(value: &str) -> String {
     format!("'{}'", value.replace('\'', "'\\''"))
 }
 
-fn gz_json_file(path: &str) -> Value {
-    let file = File::open(path).unwrap_or_else(|e| {
-        error_panic!("opening `{path}` returned {e:?}");
-    });
+fn gz_json_file(path: &str) -> Result<Value, LightdashError> {
+    let file = File::open(path).map_err(|e| LightdashError::io(format!("opening `{path}`"), e))?;
     let decoder = GzDecoder::new(file);
-    serde_json::from_reader(decoder).unwrap_or_else(|e| {
-        error_panic!("parsing gzip json `{path}` returned {e:?}");
-    })
+    serde_json::from_reader(decoder)
+        .map_err(|e| LightdashError::json(format!("gzip JSON `{path}`"), e))
 }
 
 // fn lcli_named(subcmd: &str, args: &[&str]) -> String {
@@ -385,6 +608,17 @@ pub struct GetInfo {
     pub blockheight: u64,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Help {
+    pub help: Vec<HelpEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HelpEntry {
+    /// Usage line, e.g. `setchannel id [feebase] [feeppm] ...`.
+    pub command: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SignMessageResponse {
     pub zbase: String,
@@ -539,7 +773,7 @@ pub struct ListClosedChannels {
     pub closedchannels: Vec<ClosedChannel>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct BkprListAccountEvents {
     pub events: Vec<BkprAccountEvent>,
 }
@@ -625,8 +859,12 @@ impl ClosedChannel {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Forward {
+    #[serde(default)]
+    pub created_index: Option<u64>,
+    #[serde(default)]
+    pub updated_index: Option<u64>,
     pub in_channel: String,
     pub out_channel: Option<String>,
     pub fee_msat: Option<u64>,
@@ -639,6 +877,9 @@ pub struct Forward {
     pub failreason: Option<String>,
     #[serde(default)]
     pub failcode: Option<u32>,
+    /// Shared by the HTLCs of one (multi-part) payment.
+    #[serde(default)]
+    pub payment_hash: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -660,12 +901,33 @@ pub struct RouteNode {
     pub node_id_out: Option<String>,
     #[serde(default)]
     pub next_node_id: Option<String>,
+    #[serde(default)]
+    pub short_channel_id_dir: Option<String>,
+    #[serde(default)]
+    pub amount_in_msat: Option<u64>,
+    #[serde(default)]
+    pub amount_msat: Option<u64>,
+    #[serde(default)]
+    pub cltv_in: Option<u64>,
+    #[serde(default)]
+    pub delay: Option<u64>,
 }
 
 impl RouteNode {
     pub fn outgoing_node_id(&self) -> Option<&str> {
         self.node_id_out.as_deref().or(self.next_node_id.as_deref())
     }
+
+    /// Amount carried by the channel, `amount_msat` before Core Lightning 25.
+    pub fn channel_amount_msat(&self) -> Option<u64> {
+        self.amount_in_msat.or(self.amount_msat)
+    }
+
+    /// Blocks left when the HTLC crosses the channel, `delay` before Core
+    /// Lightning 25.
+    pub fn channel_cltv(&self) -> Option<u64> {
+        self.cltv_in.or(self.delay)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -718,7 +980,7 @@ pub fn datastore_string(
     key: &[&str],
     value: &str,
     mode: DatastoreMode,
-) -> Result<DatastoreResponse, String> {
+) -> Result<DatastoreResponse, LightdashError> {
     // In debug mode, skip datastore operations
     if using_test_data() {
         log::debug!("Debug mode: Skipping datastore_string for key {:?}", key);
@@ -730,13 +992,11 @@ pub fn datastore_string(
 
     // 1. JSON-encode the key array.
     //    Example: &["lightdash", "last_run"] -> "[\"lightdash\",\"last_run\"]"
-    let key_json = serde_json::to_string(key)
-        .map_err(|e| format!("Failed to serialize key to JSON: {}", e))?;
+    let key_json = serde_json::to_string(key).expect("datastore key serializes");
 
     // 2. JSON-encode the string value. THIS IS THE CRITICAL FIX.
     //    Example: "1760287752" -> "\"1760287752\"" (Note the added quotes)
-    let value_json = serde_json::to_string(value)
-        .map_err(|e| format!("Failed to serialize value to JSON: {}", e))?;
+    let value_json = serde_json::to_string(value).expect("datastore value serializes");
 
     let args: Vec<String> = vec![
         "datastore".to_string(),
@@ -749,23 +1009,38 @@ pub fn datastore_string(
     ];
 
     log::debug!("Executing lightning-cli with args: {:?}", args);
-    let response_value = cmd_result("lightning-cli", &args);
+    let response_value = cmd_result("lightning-cli", &args)?;
     log::debug!("Received response: {:?}", response_value);
 
-    // It's also good practice to check for an error response before parsing
-    if response_value.get("code").is_some() {
-        return Err(format!(
-            "lightning-cli returned an error: {:?}",
-            response_value
-        ));
+    parse_value("datastore", response_value)
+}
+
+/// Append `line` to a datastore log of JSON lines, dropping the oldest lines
+/// beyond `keep_lines`.
+pub fn datastore_append_line(
+    key: &[&str],
+    line: &str,
+    keep_lines: usize,
+) -> Result<(), LightdashError> {
+    let response = datastore_string(key, line, DatastoreMode::CreateOrAppend)?;
+    if let Some(kept) = response
+        .string
+        .as_deref()
+        .and_then(|log| last_lines(log, keep_lines))
+    {
+        datastore_string(key, &kept, DatastoreMode::MustReplace)?;
     }
+    Ok(())
+}
 
-    serde_json::from_value(response_value)
-        .map_err(|e| format!("Failed to parse successful response JSON: {} ", e,))
+/// The last `keep` lines of `log`, or `None` when it has no more than that.
+fn last_lines(log: &str, keep: usize) -> Option<String> {
+    let lines: Vec<&str> = log.lines().collect();
+    (lines.len() > keep).then(|| lines[lines.len() - keep..].join("\n") + "\n")
 }
 
 /// List/retrieve data from the datastore, optionally filtered by key
-pub fn listdatastore(key: Option<&[&str]>) -> Result<ListDatastore, String> {
+pub fn listdatastore(key: Option<&[&str]>) -> Result<ListDatastore, LightdashError> {
     // In debug mode, return empty datastore
     if using_test_data() {
         log::debug!("Debug mode: Skipping listdatastore for key {:?}", key);
@@ -773,17 +1048,17 @@ pub fn listdatastore(key: Option<&[&str]>) -> Result<ListDatastore, String> {
     }
 
     let v = if let Some(k) = key {
-        let key_json = serde_json::to_string(k).map_err(|e| e.to_string())?;
-        cmd_result("lightning-cli", &["listdatastore", &key_json])
+        let key_json = serde_json::to_string(k).expect("datastore key serializes");
+        cmd_result("lightning-cli", &["listdatastore", &key_json])?
     } else {
-        cmd_result("lightning-cli", &["listdatastore"])
+        cmd_result("lightning-cli", &["listdatastore"])?
     };
 
-    serde_json::from_value(v).map_err(|e| format!("Failed to parse response: {}", e))
+    parse_value("listdatastore", v)
 }
 
 /// Delete data from the datastore
-pub fn _deldatastore(key: &[&str]) -> Result<DatastoreResponse, String> {
+pub fn _deldatastore(key: &[&str]) -> Result<DatastoreResponse, LightdashError> {
     // In debug mode, skip datastore operations
     if using_test_data() {
         log::debug!("Debug mode: Skipping _deldatastore for key {:?}", key);
@@ -793,11 +1068,30 @@ pub fn _deldatastore(key: &[&str]) -> Result<DatastoreResponse, String> {
         });
     }
 
-    let key_json = serde_json::to_string(key).map_err(|e| e.to_string())?;
-    let args = vec!["deldatastore", "-k", "key", &key_json];
+    let key_arg = format!(
+        "key={}",
+        serde_json::to_string(key).expect("datastore key serializes")
+    );
+    let args = vec!["deldatastore", "-k", &key_arg];
 
-    let v = cmd_result("lightning-cli", &args);
-    serde_json::from_value(v).map_err(|e| format!("Failed to parse response: {}", e))
+    let v = cmd_result("lightning-cli", &args)?;
+    parse_value("deldatastore", v)
+}
+
+/// Pagination index of `listforwards`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardIndex {
+    Created,
+    Updated,
+}
+
+impl ForwardIndex {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardIndex::Created => "created",
+            ForwardIndex::Updated => "updated",
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -838,10 +1132,36 @@ pub struct ListDatastore {
 #[cfg(test)]
 mod command_tests {
     use super::{
-        build_remote_command, lightning_cli_json_args, normalize_remote_home_path,
-        parse_get_routes_outcome, shell_quote, GetRoutes, GetRoutesOutcome, ListPeerChannels,
+        build_remote_command, cmd_result, gz_json_file, inbound_fee_parameter, last_lines,
+        lightning_cli_json_args, normalize_remote_home_path, parse_get_routes_failure, shell_quote,
+        GetRoutes, GetRoutesOutcome, Help, LightdashError, ListPeerChannels,
     };
 
+    #[test]
+    fn inbound_fee_parameter_is_read_from_setchannel_help() {
+        let help = |command: &str| -> Help {
+            serde_json::from_value(serde_json::json!({
+                "help": [{"command": command, "category": "channels"}]
+            }))
+            .unwrap()
+        };
+        let current = help(
+            "setchannel id [feebase] [feeppm] [htlcmin] [htlcmax] [enforcedelay] [ignorefeelimits]",
+        );
+        assert_eq!(inbound_fee_parameter(&current), None);
+        let inbound = help("setchannel id [feebase] [feeppm] [inboundfeebase] [inboundfeeppm]");
+        assert_eq!(
+            inbound_fee_parameter(&inbound).as_deref(),
+            Some("inboundfeeppm")
+        );
+    }
+
+    #[test]
+    fn datastore_logs_keep_their_last_lines() {
+        assert_eq!(last_lines("a\nb\n", 2), None);
+        assert_eq!(last_lines("a\nb\nc\n", 2).as_deref(), Some("b\nc\n"));
+    }
+
     #[test]
     fn lightning_cli_output_is_json_without_notifications() {
         assert_eq!(
@@ -858,11 +1178,41 @@ mod command_tests {
 
     #[test]
     fn getroutes_deadline_error_is_reported_as_timeout() {
-        let outcome = parse_get_routes_outcome(serde_json::json!({
-            "code": 210,
-            "message": "single_path_routes: timed out after deadline"
-        }));
+        let outcome = parse_get_routes_failure("single_path_routes: timed out after deadline");
         assert!(matches!(outcome, GetRoutesOutcome::TimedOut));
+        assert!(matches!(
+            parse_get_routes_failure("Could not find route"),
+            GetRoutesOutcome::NotFound
+        ));
+    }
+
+    #[test]
+    fn failing_command_errors_are_typed() {
+        let error = cmd_result(
+            "sh",
+            &[
+                "-c",
+                r#"echo '{"code": 204, "message": "failed", "data": {"erring_channel": "1x2x3"}}'; exit 1"#,
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.rpc_code(), Some(204));
+        assert!(matches!(
+            &error,
+            LightdashError::Rpc { data: Some(data), .. } if data["erring_channel"] == "1x2x3"
+        ));
+
+        let error = cmd_result("sh", &["-c", "echo oops >&2; exit 3"]).unwrap_err();
+        assert!(matches!(error, LightdashError::Transport { .. }));
+        assert!(error.to_string().contains("oops"));
+
+        let error = cmd_result("sh", &["-c", "echo not-json"]).unwrap_err();
+        assert!(matches!(error, LightdashError::Json { .. }));
+
+        assert!(matches!(
+            gz_json_file("test-json/does-not-exist.gz"),
+            Err(LightdashError::Io { .. })
+        ));
     }
 
     #[test]
@@ -956,6 +1306,32 @@ mod command_tests {
             Some("current")
         );
     }
+
+    #[test]
+    fn getroutes_accepts_current_and_deprecated_amount_fields_together() {
+        let response: GetRoutes = serde_json::from_str(
+            r#"{
+                "routes": [{
+                    "path": [{
+                        "amount_in_msat": 1000,
+                        "amount_msat": 999,
+                        "cltv_in": 40,
+                        "delay": 39
+                    }, {
+                        "amount_msat": 900,
+                        "delay": 20
+                    }]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let path = &response.routes[0].path;
+        assert_eq!(path[0].channel_amount_msat(), Some(1000));
+        assert_eq!(path[0].channel_cltv(), Some(40));
+        assert_eq!(path[1].channel_amount_msat(), Some(900));
+        assert_eq!(path[1].channel_cltv(), Some(20));
+    }
 }
 
 #[cfg(all(test, feature = "large-fixture-tests"))]
@@ -966,7 +1342,7 @@ mod tests {
 
     #[test]
     fn gz_bkpr_fixture_matches_confirmed_rebalance_totals() {
-        let events = bkpr_list_account_events();
+        let events = bkpr_list_account_events().unwrap();
         let rebalance_events: Vec<_> = events
             .events
             .iter()
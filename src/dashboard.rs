@@ -2918,8 +2918,14 @@ fn create_closed_channels_page(
 }
 
 fn load_current_rebalance_snapshot() -> Option<RebalanceSnapshotFile> {
-    match serde_json::from_value::<Vec<RebalanceSnapshotEntry>>(crate::sling::current_sling_stats())
-    {
+    let stats = match crate::sling::current_sling_stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            log::error!("Failed to fetch current sling stats for dashboard: {}", e);
+            return None;
+        }
+    };
+    match serde_json::from_value::<Vec<RebalanceSnapshotEntry>>(stats) {
         Ok(entries) => Some(RebalanceSnapshotFile {
             file_name: "live sling-stats".to_string(),
             entries,
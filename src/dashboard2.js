@@ -114,9 +114,10 @@
             channel ||= closedChannels.find(row => row.short_channel_id === channelKey || row.channel_id === channelKey);
             if (!channel) throw new Error(`Channel ${channelKey} is not present in this snapshot`);
 
-            const [forwards, rebalances] = await Promise.all([
+            const [forwards, rebalances, feeChanges] = await Promise.all([
                 fetchJsonLines("data/settled-forwards.jsonl"),
-                fetchJsonLines("data/rebalances.jsonl")
+                fetchJsonLines("data/rebalances.jsonl"),
+                fetchJsonLines("data/fee-changes.jsonl")
             ]);
             const channelFields = manifest.datasets?.[isClosed ? "closed_channels" : "channels"]?.fields || {};
             const channelForwards = forwards
@@ -125,12 +126,16 @@
             const channelRebalances = rebalances
                 .filter(row => row.source_channel_id === channel.short_channel_id || row.target_channel_id === channel.short_channel_id)
                 .sort(newestFirst("resolved_at"));
+            const channelFeeChanges = feeChanges
+                .filter(row => row.short_channel_id === channel.short_channel_id)
+                .sort(newestFirst("changed_at"));
 
             renderChannelHeader(channel, isClosed);
             renderChannelMetrics(channel, channelForwards, isClosed);
             renderChannelDetails(channel, channelForwards, channelRebalances, channelFields, isClosed);
             renderForwardTable("channel-forwards", channelForwards, channel);
             renderRebalanceTable("channel-rebalances", channelRebalances, channel);
+            renderFeeChangeTable("channel-fee-changes", channelFeeChanges);
             content.hidden = false;
             try {
                 await renderChannelHistory(manifest, channel);
@@ -204,6 +209,8 @@
         appendDetail(identity, "State", channel.state, fields.state);
         appendDetail(identity, "Connected", channel.connected ? "Yes" : "No", fields.connected);
         appendDetail(identity, "Uptime", channel.uptime_ratio == null ? null : formatNumber(channel.uptime_ratio * 100, 2, "%"), fields.uptime_ratio);
+        const availabilitySince = channel.availability_state_since ? ` since ${channel.availability_state_since}` : "";
+        appendDetail(identity, "Availability state", `${humanize(channel.availability_state || "healthy")}${availabilitySince}`, fields.availability_state);
         appendDetail(identity, "Age", channel.age_days == null ? null : `${formatNumber(channel.age_days, 0)} days`, fields.age_days);
         appendDetail(identity, "Funding outpoint", `${channel.funding_txid}:${channel.funding_output}`, fields.funding_txid, true);
 
@@ -505,6 +512,21 @@
         ]), rows.length, [2, 3, 4]);
     }
 
+    function renderFeeChangeTable(id, rows) {
+        renderSimpleTable(id, ["Changed", "Action", "PPM", "Base", "HTLC min", "HTLC max", "State", "Activity", "Routed since watermark", "Availability"], rows.slice(0, 100).map(row => [
+            row.changed_at,
+            row.action,
+            `${formatPpm(row.old_fee_ppm)} → ${formatPpm(row.new_fee_ppm)}`,
+            `${formatNumber(row.old_base_fee_msat, 0)} → ${formatNumber(row.new_base_fee_msat, 0)} msat`,
+            `${formatNumber(row.old_htlc_min_msat, 0)} → ${formatNumber(row.new_htlc_min_msat, 0)} msat`,
+            `${formatMsat(row.old_htlc_max_msat)} → ${formatMsat(row.new_htlc_max_msat)}`,
+            row.state,
+            row.activity,
+            formatMsat(row.routed_since_watermark_msat),
+            row.availability == null ? "—" : formatNumber(row.availability * 100, 1, "%")
+        ]), rows.length, [2, 3, 4, 5, 8, 9]);
+    }
+
     function renderSimpleTable(id, headings, rows, total, numericColumns = []) {
         const table = document.querySelector(`#${id}`);
         const header = document.createElement("tr");
@@ -789,6 +811,7 @@
             column("local_balance_msat", "Local balance sats", "number", { transform: msatToSat, suffix: " sats", decimals: 0 }),
             column("capacity_msat", "Capacity", "number", { visible: true, transform: msatToSat, suffix: " sats", decimals: 0 }),
             column("uptime_ratio", "Uptime", "number", { visible: true, transform: value => value * 100, suffix: "%", decimals: 1, warningBelow: 0.8 }),
+            column("availability_state", "Availability", "text", { visible: true, badge: true, value: row => row.availability_state || "healthy" }),
             column("outbound_fee_ppm", "My PPM", "number", { visible: true, transform: ppmToInteger, suffix: " ppm", decimals: 0 }),
             column("historical_effective_fee_ppm", "Historical PPM", "number", { visible: true, transform: ppmToInteger, suffix: " ppm", decimals: 0 }),
             column("time_decayed_fee_ppm", "TPPM", "number", { visible: true, transform: ppmToInteger, suffix: " ppm", decimals: 0 }),
@@ -32,6 +32,7 @@ pub fn run_dashboard2(snapshot_directory: &str, output_directory: &str) -> Resul
     let rebalances_path = snapshot_file(snapshot_directory, &manifest.files.rebalances)?;
     let rebalance_status_path =
         snapshot_file(snapshot_directory, &manifest.files.rebalance_status)?;
+    let fee_changes_path = snapshot_file(snapshot_directory, &manifest.files.fee_changes)?;
     let route_runs_dataset = manifest.datasets.get("route_runs");
     let route_candidates_dataset = manifest.datasets.get("route_candidates");
     if route_runs_dataset.is_some() != route_candidates_dataset.is_some() {
@@ -63,6 +64,7 @@ pub fn run_dashboard2(snapshot_directory: &str, output_directory: &str) -> Resul
         &rebalance_status_path,
         &data_directory.join("rebalance-status.json"),
     )?;
+    copy_file(&fee_changes_path, &data_directory.join("fee-changes.jsonl"))?;
     let routes_page_data = if let (Some(runs_dataset), Some(candidates_dataset)) =
         (route_runs_dataset, route_candidates_dataset)
     {
@@ -98,6 +100,7 @@ pub fn run_dashboard2(snapshot_directory: &str, output_directory: &str) -> Resul
         "settled_forwards",
         "rebalances",
         "rebalance_status",
+        "fee_changes",
     ] {
         let dataset = manifest
             .datasets
@@ -396,6 +399,7 @@ fn render_channel_page(manifest: &SnapshotManifest) -> String {
 
                 (channel_activity_table("Settled forwards", "channel-forwards", "No settled forwards involve this channel."))
                 (channel_activity_table("Rebalances", "channel-rebalances", "No rebalances involve this channel."))
+                (channel_activity_table("Fee changes", "channel-fee-changes", "Lightdash has not recorded fee changes for this channel."))
             }
         }
     };
@@ -756,6 +760,7 @@ mod tests {
             other_forwards: "other-forwards.jsonl".to_string(),
             rebalances: "rebalances.jsonl".to_string(),
             rebalance_status: "rebalance-status.json".to_string(),
+            fee_changes: "fee-changes.jsonl".to_string(),
             history_manifest: None,
             routes_manifest: Some("routes-manifest.json".to_string()),
         };
@@ -773,6 +778,7 @@ mod tests {
                     other_forwards: 0,
                     rebalances: 0,
                     rebalance_status: 0,
+                    fee_changes: 0,
                 },
             ),
             files,
@@ -857,6 +863,7 @@ mod tests {
         fs::write(snapshot.join("settled-forwards.jsonl"), b"").unwrap();
         fs::write(snapshot.join("rebalances.jsonl"), b"").unwrap();
         fs::write(snapshot.join("rebalance-status.json"), b"[]").unwrap();
+        fs::write(snapshot.join("fee-changes.jsonl"), b"").unwrap();
         fs::write(
             snapshot.join("route-runs.json"),
             br#"[{"amount_sat":1000,"max_fee_msat":10000,"scanned_nodes":10,"eligible_destinations":8,"processed_destinations":8,"queried_destinations":7,"capacity_filtered_destinations":1,"evaluated_routes":7,"failed_routes":0,"timed_out_routes":0,"budget_exhausted":false,"elapsed_seconds":2.5,"candidate_nodes":1,"recurring_candidate_nodes":1,"average_hops":2.5}]"#,
@@ -898,6 +905,8 @@ mod tests {
         assert!(output.join("data/settled-forwards.jsonl").is_file());
         assert!(output.join("data/rebalances.jsonl").is_file());
         assert!(output.join("data/rebalance-status.json").is_file());
+        assert!(output.join("data/fee-changes.jsonl").is_file());
+        assert!(output.join("data/fee-changes.schema.json").is_file());
         assert!(output.join("data/route-runs.json").is_file());
         assert!(output.join("data/route-candidates.json").is_file());
         assert!(output.join("data/summary.schema.json").is_file());
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io;
+
+use serde_json::Value;
+
+/// Error returned by the `cmd` layer when talking to the node or reading its data.
+#[derive(Debug)]
+pub enum LightdashError {
+    /// The command could not be run or its output could not be received:
+    /// process spawn, SSH, non-zero exit without a JSON error, or socket failure.
+    Transport { command: String, message: String },
+    /// Core Lightning answered with a JSON-RPC error object.
+    Rpc {
+        command: String,
+        code: i64,
+        message: String,
+        /// The error's `data`, such as the erring channel of a failed payment.
+        data: Option<Value>,
+    },
+    /// The output was not JSON or did not have the expected shape.
+    Json {
+        context: String,
+        source: serde_json::Error,
+    },
+    /// A local file could not be read.
+    Io { context: String, source: io::Error },
+}
+
+impl LightdashError {
+    pub(crate) fn transport(command: impl Into<String>, message: impl fmt::Display) -> Self {
+        LightdashError::Transport {
+            command: command.into(),
+            message: message.to_string(),
+        }
+    }
+
+    pub(crate) fn json(context: impl Into<String>, source: serde_json::Error) -> Self {
+        LightdashError::Json {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn io(context: impl Into<String>, source: io::Error) -> Self {
+        LightdashError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// The JSON-RPC error code when Core Lightning rejected the command.
+    pub fn rpc_code(&self) -> Option<i64> {
+        match self {
+            LightdashError::Rpc { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LightdashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LightdashError::Transport { command, message } => {
+                write!(f, "executing `{command}` failed: {message}")
+            }
+            LightdashError::Rpc {
+                command,
+                code,
+                message,
+                ..
+            } => write!(f, "`{command}` returned error {code}: {message}"),
+            LightdashError::Json { context, source } => {
+                write!(f, "parsing {context} failed: {source}")
+            }
+            LightdashError::Io { context, source } => write!(f, "{context} failed: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for LightdashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LightdashError::Json { source, .. } => Some(source),
+            LightdashError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<LightdashError> for String {
+    fn from(error: LightdashError) -> Self {
+        error.to_string()
+    }
+}
+
+impl From<LightdashError> for io::Error {
+    fn from(error: LightdashError) -> Self {
+        io::Error::other(error)
+    }
+}
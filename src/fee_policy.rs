@@ -0,0 +1,458 @@
+// Fee controller policy loaded with `lightdash fees --policy <file>`.
+//
+// The file is TOML, or JSON when its name ends in `.json`. Every key is
+// optional; missing `[defaults]` keys keep the built-in constants of fees.rs:
+//
+// [defaults]
+// ppm_max = 3000
+// min_availability = 0.9
+//
+// [peers.02abc...]
+// ppm_floor = 200
+//
+// [channels."866501x2973x1"]
+// fixed_ppm = 500
+//
+// [channels."925417x701x0"]
+// excluded = true
+//
+// [channels."870000x100x1"]
+// base_fee_msat = 0
+// inbound_discount_ppm = 50
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fees::{
+    AVAILABILITY_RECOVERY_MARGIN, BOOTSTRAP_DECREASE_PERCENT, BOOTSTRAP_EXIT_SETTLED_SAT,
+    DEGRADED_AVAILABILITY, DEPLETED_INCREASE_PERCENT, DEPLETED_LOCAL_BALANCE_SAT, FEE_BASE,
+    FORWARD_INCREASE_PERCENT, INBOUND_DISCOUNT_PPM, INBOUND_ROLE_INDIRECT_PERCENT,
+    INBOUND_ROLE_MIN_INDIRECT_SAT, LIGHT_INCREASE_PERCENT, MAX_UPDATES_PER_HOUR, MIN_AVAILABILITY,
+    MIN_HTLC, MIN_RELATIVE_CHANGE_PERCENT, MIN_ROUTED_SAT, MIN_UPDATE_INTERVAL_HOURS,
+    MODERATE_DISTINCT_PAYMENTS, MODERATE_ROUTED_CAPACITY_PERCENT, NORMAL_DECREASE_PERCENT, PPM_MAX,
+    PPM_MIN, STRONG_DISTINCT_PAYMENTS, STRONG_INCREASE_PERCENT, STRONG_ROUTED_CAPACITY_PERCENT,
+};
+
+/// Controller knobs, defaulting to the constants in fees.rs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSettings {
+    pub ppm_min: u64,
+    pub ppm_max: u64,
+    pub depleted_local_balance_sat: u64,
+    pub min_htlc_msat: u64,
+    pub light_increase_percent: u64,
+    /// Increase of the moderate activity grade.
+    pub forward_increase_percent: u64,
+    pub strong_increase_percent: u64,
+    pub moderate_routed_capacity_percent: f64,
+    pub strong_routed_capacity_percent: f64,
+    pub moderate_distinct_payments: usize,
+    pub strong_distinct_payments: usize,
+    pub depleted_increase_percent: u64,
+    pub bootstrap_decrease_percent: u64,
+    pub normal_decrease_percent: u64,
+    pub fee_base_msat: u64,
+    /// Settled amount since the watermark below which forwards do not count
+    /// as activity.
+    pub min_routed_sat: u64,
+    /// Lifetime settled amount routed out before a channel leaves bootstrap.
+    pub bootstrap_exit_settled_sat: u64,
+    /// Peers below this availability get their channels disabled.
+    pub min_availability: f64,
+    /// Peers below this availability get their maximum HTLC capped.
+    pub degraded_availability: f64,
+    /// How far above a threshold a peer must be to recover from it.
+    pub availability_recovery_margin: f64,
+    /// Inbound discount of channels in the inbound role; 0 keeps it off.
+    pub inbound_discount_ppm: u64,
+    /// Share of a channel's lifetime fees that must be indirect for the
+    /// inbound role.
+    pub inbound_role_indirect_percent: u64,
+    pub inbound_role_min_indirect_sat: u64,
+    /// Updates whose largest relative change is smaller are deferred.
+    pub min_relative_change_percent: f64,
+    /// Hours after a channel's last `setchannel` before it is updated again.
+    pub min_update_interval_hours: u64,
+    /// `setchannel` calls per hour across all channels; 0 means no limit.
+    pub max_updates_per_hour: usize,
+}
+
+/// Per-peer or per-channel exceptions to the defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ppm_floor: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ppm_ceiling: Option<u64>,
+    /// Always propose this ppm instead of adjusting it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixed_ppm: Option<u64>,
+    /// Base fee instead of the default `fee_base_msat`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_fee_msat: Option<u64>,
+    /// Inbound discount regardless of the channel's role; 0 removes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbound_discount_ppm: Option<u64>,
+    /// Leave the channel alone: no fee, HTLC or disable changes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub excluded: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeePolicy {
+    #[serde(default)]
+    pub defaults: FeeSettings,
+    /// Keyed by peer node id.
+    #[serde(default)]
+    pub peers: BTreeMap<String, FeeOverride>,
+    /// Keyed by short channel id; takes precedence over the peer entry.
+    #[serde(default)]
+    pub channels: BTreeMap<String, FeeOverride>,
+}
+
+/// The policy resolved for one channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelPolicy {
+    /// Defaults with `ppm_min`/`ppm_max` narrowed by the floor and ceiling,
+    /// and `fee_base_msat` replaced by the base fee override.
+    pub settings: FeeSettings,
+    pub fixed_ppm: Option<u64>,
+    pub inbound_discount_ppm: Option<u64>,
+    pub excluded: bool,
+}
+
+impl Default for FeeSettings {
+    fn default() -> Self {
+        FeeSettings {
+            ppm_min: PPM_MIN,
+            ppm_max: PPM_MAX,
+            depleted_local_balance_sat: DEPLETED_LOCAL_BALANCE_SAT,
+            min_htlc_msat: MIN_HTLC,
+            light_increase_percent: LIGHT_INCREASE_PERCENT,
+            forward_increase_percent: FORWARD_INCREASE_PERCENT,
+            strong_increase_percent: STRONG_INCREASE_PERCENT,
+            moderate_routed_capacity_percent: MODERATE_ROUTED_CAPACITY_PERCENT,
+            strong_routed_capacity_percent: STRONG_ROUTED_CAPACITY_PERCENT,
+            moderate_distinct_payments: MODERATE_DISTINCT_PAYMENTS,
+            strong_distinct_payments: STRONG_DISTINCT_PAYMENTS,
+            depleted_increase_percent: DEPLETED_INCREASE_PERCENT,
+            bootstrap_decrease_percent: BOOTSTRAP_DECREASE_PERCENT,
+            normal_decrease_percent: NORMAL_DECREASE_PERCENT,
+            fee_base_msat: FEE_BASE,
+            min_routed_sat: MIN_ROUTED_SAT,
+            bootstrap_exit_settled_sat: BOOTSTRAP_EXIT_SETTLED_SAT,
+            min_availability: MIN_AVAILABILITY,
+            degraded_availability: DEGRADED_AVAILABILITY,
+            availability_recovery_margin: AVAILABILITY_RECOVERY_MARGIN,
+            inbound_discount_ppm: INBOUND_DISCOUNT_PPM,
+            inbound_role_indirect_percent: INBOUND_ROLE_INDIRECT_PERCENT,
+            inbound_role_min_indirect_sat: INBOUND_ROLE_MIN_INDIRECT_SAT,
+            min_relative_change_percent: MIN_RELATIVE_CHANGE_PERCENT,
+            min_update_interval_hours: MIN_UPDATE_INTERVAL_HOURS,
+            max_updates_per_hour: MAX_UPDATES_PER_HOUR,
+        }
+    }
+}
+
+impl FeeSettings {
+    /// Smallest non-zero fee step. Increases round up and decreases round
+    /// down, so every step moves the ppm by at least its percent.
+    fn smallest_step_percent(&self) -> Option<u64> {
+        [
+            self.light_increase_percent,
+            self.forward_increase_percent,
+            self.strong_increase_percent,
+            self.depleted_increase_percent,
+            self.bootstrap_decrease_percent,
+            self.normal_decrease_percent,
+        ]
+        .into_iter()
+        .filter(|percent| *percent > 0)
+        .min()
+    }
+}
+
+impl FeePolicy {
+    /// Read and validate a policy file, logging the effective policy.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("reading fee policy `{path}` failed: {e}"))?;
+        let policy = Self::parse(&content, is_json(Path::new(path)))
+            .map_err(|e| format!("fee policy `{path}`: {e}"))?;
+        log::info!("Loaded fee policy from {path}");
+        policy.log();
+        Ok(policy)
+    }
+
+    fn parse(content: &str, json: bool) -> Result<Self, String> {
+        let policy: FeePolicy = if json {
+            serde_json::from_str(content).map_err(|e| format!("parsing failed: {e}"))?
+        } else {
+            toml::from_str(content).map_err(|e| format!("parsing failed: {e}"))?
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let defaults = &self.defaults;
+        if defaults.ppm_min == 0 || defaults.ppm_min > defaults.ppm_max {
+            return Err(format!(
+                "defaults need 1 <= ppm_min <= ppm_max, got {}..{}",
+                defaults.ppm_min, defaults.ppm_max
+            ));
+        }
+        for (name, percent) in [
+            (
+                "bootstrap_decrease_percent",
+                defaults.bootstrap_decrease_percent,
+            ),
+            ("normal_decrease_percent", defaults.normal_decrease_percent),
+        ] {
+            if percent >= 100 {
+                return Err(format!("{name} must be below 100, got {percent}"));
+            }
+        }
+        if defaults.light_increase_percent > defaults.forward_increase_percent
+            || defaults.forward_increase_percent > defaults.strong_increase_percent
+        {
+            return Err(format!(
+                "increases need light_increase_percent <= forward_increase_percent <= strong_increase_percent, got {}, {}, {}",
+                defaults.light_increase_percent,
+                defaults.forward_increase_percent,
+                defaults.strong_increase_percent
+            ));
+        }
+        if !(0.0..=defaults.strong_routed_capacity_percent)
+            .contains(&defaults.moderate_routed_capacity_percent)
+        {
+            return Err(format!(
+                "routed capacity thresholds need 0 <= moderate <= strong, got {} and {}",
+                defaults.moderate_routed_capacity_percent, defaults.strong_routed_capacity_percent
+            ));
+        }
+        if defaults.moderate_distinct_payments == 0
+            || defaults.moderate_distinct_payments > defaults.strong_distinct_payments
+        {
+            return Err(format!(
+                "distinct payment thresholds need 1 <= moderate <= strong, got {} and {}",
+                defaults.moderate_distinct_payments, defaults.strong_distinct_payments
+            ));
+        }
+        if defaults.min_htlc_msat == 0 {
+            return Err("min_htlc_msat must be at least 1".to_string());
+        }
+        if !(0.0..=defaults.degraded_availability).contains(&defaults.min_availability)
+            || defaults.degraded_availability > 1.0
+        {
+            return Err(format!(
+                "availability thresholds need 0 <= min_availability <= degraded_availability <= 1, got {} and {}",
+                defaults.min_availability, defaults.degraded_availability
+            ));
+        }
+        if !(0.0..1.0).contains(&defaults.availability_recovery_margin) {
+            return Err(format!(
+                "availability_recovery_margin must be in 0..1, got {}",
+                defaults.availability_recovery_margin
+            ));
+        }
+        if !(1..=100).contains(&defaults.inbound_role_indirect_percent) {
+            return Err(format!(
+                "inbound_role_indirect_percent must be in 1..=100, got {}",
+                defaults.inbound_role_indirect_percent
+            ));
+        }
+        if defaults.min_relative_change_percent.is_nan()
+            || defaults.min_relative_change_percent < 0.0
+        {
+            return Err(format!(
+                "min_relative_change_percent must be at least 0, got {}",
+                defaults.min_relative_change_percent
+            ));
+        }
+        // Each step starts from the live ppm, so a step below the threshold
+        // would be deferred on every run instead of adding up
+        if let Some(step_percent) = defaults.smallest_step_percent() {
+            if defaults.min_relative_change_percent > step_percent as f64 {
+                return Err(format!(
+                    "min_relative_change_percent must not exceed the smallest increase or decrease percent {step_percent}, got {}",
+                    defaults.min_relative_change_percent
+                ));
+            }
+        }
+        for (peer_id, entry) in &self.peers {
+            if peer_id.len() != 66 || !peer_id.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("`{peer_id}` is not a node id"));
+            }
+            entry
+                .validate()
+                .map_err(|e| format!("peer {peer_id}: {e}"))?;
+        }
+        for (short_channel_id, entry) in &self.channels {
+            let parts: Vec<_> = short_channel_id.split('x').collect();
+            if parts.len() != 3 || parts.iter().any(|part| part.parse::<u64>().is_err()) {
+                return Err(format!("`{short_channel_id}` is not a short channel id"));
+            }
+            entry
+                .validate()
+                .map_err(|e| format!("channel {short_channel_id}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    fn log(&self) {
+        log::info!(
+            "Fee policy defaults: {}",
+            serde_json::to_string(&self.defaults).expect("settings serialize")
+        );
+        for (kind, entries) in [("peer", &self.peers), ("channel", &self.channels)] {
+            for (id, entry) in entries {
+                log::info!(
+                    "Fee policy {kind} {id}: {}",
+                    serde_json::to_string(entry).expect("overrides serialize")
+                );
+            }
+        }
+    }
+
+    /// Resolve the policy of a channel: channel entry, then peer entry, then defaults.
+    pub fn for_channel(&self, peer_id: &str, short_channel_id: &str) -> ChannelPolicy {
+        let peer = self.peers.get(peer_id);
+        let channel = self.channels.get(short_channel_id);
+        let pick = |field: fn(&FeeOverride) -> Option<u64>| {
+            channel.and_then(field).or_else(|| peer.and_then(field))
+        };
+        let mut settings = self.defaults.clone();
+        if let Some(floor) = pick(|entry| entry.ppm_floor) {
+            settings.ppm_min = floor;
+        }
+        if let Some(ceiling) = pick(|entry| entry.ppm_ceiling) {
+            settings.ppm_max = ceiling;
+        }
+        // A channel floor may sit above a peer ceiling; the floor wins.
+        settings.ppm_max = settings.ppm_max.max(settings.ppm_min);
+        if let Some(base_fee_msat) = pick(|entry| entry.base_fee_msat) {
+            settings.fee_base_msat = base_fee_msat;
+        }
+        ChannelPolicy {
+            settings,
+            fixed_ppm: pick(|entry| entry.fixed_ppm),
+            inbound_discount_ppm: pick(|entry| entry.inbound_discount_ppm),
+            excluded: peer.is_some_and(|entry| entry.excluded)
+                || channel.is_some_and(|entry| entry.excluded),
+        }
+    }
+}
+
+impl FeeOverride {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(floor), Some(ceiling)) = (self.ppm_floor, self.ppm_ceiling) {
+            if floor > ceiling {
+                return Err(format!("ppm_floor {floor} is above ppm_ceiling {ceiling}"));
+            }
+        }
+        if self.ppm_floor == Some(0) || self.ppm_ceiling == Some(0) {
+            return Err("ppm_floor and ppm_ceiling must be at least 1".to_string());
+        }
+        if self.fixed_ppm.is_some() && (self.ppm_floor.is_some() || self.ppm_ceiling.is_some()) {
+            return Err("fixed_ppm cannot be combined with ppm_floor or ppm_ceiling".to_string());
+        }
+        if self.fixed_ppm == Some(0) {
+            return Err("fixed_ppm must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "02aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    #[test]
+    fn missing_keys_keep_builtin_defaults() {
+        let policy = FeePolicy::parse("[defaults]\nppm_max = 3000\n", false).unwrap();
+        assert_eq!(policy.defaults.ppm_max, 3000);
+        assert_eq!(policy.defaults.ppm_min, PPM_MIN);
+        assert_eq!(policy.defaults.min_availability, MIN_AVAILABILITY);
+        assert_eq!(FeePolicy::parse("{}", true).unwrap(), FeePolicy::default());
+    }
+
+    #[test]
+    fn channel_overrides_take_precedence_over_peer_overrides() {
+        let policy = FeePolicy::parse(
+            &format!(
+                r#"
+                [peers.{PEER}]
+                ppm_floor = 200
+                ppm_ceiling = 800
+
+                [channels."1x2x3"]
+                ppm_floor = 900
+
+                [channels."4x5x6"]
+                excluded = true
+
+                [channels."7x7x7"]
+                base_fee_msat = 0
+                inbound_discount_ppm = 50
+                "#
+            ),
+            false,
+        )
+        .unwrap();
+
+        let channel = policy.for_channel(PEER, "1x2x3");
+        assert_eq!(
+            (channel.settings.ppm_min, channel.settings.ppm_max),
+            (900, 900)
+        );
+        let peer_only = policy.for_channel(PEER, "7x8x9");
+        assert_eq!(
+            (peer_only.settings.ppm_min, peer_only.settings.ppm_max),
+            (200, 800)
+        );
+        assert!(policy.for_channel("other", "4x5x6").excluded);
+        let inbound = policy.for_channel(PEER, "7x7x7");
+        assert_eq!(inbound.settings.fee_base_msat, 0);
+        assert_eq!(inbound.inbound_discount_ppm, Some(50));
+        assert_eq!(channel.settings.fee_base_msat, FEE_BASE);
+        assert_eq!(
+            policy.for_channel("other", "7x8x9").settings,
+            policy.defaults
+        );
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        for content in [
+            "[defaults]\nppm_min = 10\nppm_max = 5\n",
+            "[defaults]\nmin_availability = 1.5\n",
+            "[defaults]\nmin_availability = 0.95\n",
+            "[defaults]\nlight_increase_percent = 8\n",
+            "[defaults]\nmoderate_distinct_payments = 4\n",
+            "[defaults]\ninbound_role_indirect_percent = 0\n",
+            "[defaults]\nmin_relative_change_percent = -1.0\n",
+            "[defaults]\nmin_relative_change_percent = 3.0\n",
+            "[defaults]\nunknown = 1\n",
+            "[peers.not-a-node]\nexcluded = true\n",
+            "[channels.\"1x2\"]\nexcluded = true\n",
+            "[channels.\"1x2x3\"]\nppm_floor = 10\nppm_ceiling = 5\n",
+            "[channels.\"1x2x3\"]\nfixed_ppm = 10\nppm_floor = 5\n",
+        ] {
+            assert!(FeePolicy::parse(content, false).is_err(), "{content}");
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Gossip-rate limits applied to a fee plan before any `setchannel` runs.
+//
+// Every `setchannel` is broadcast as a channel update, so a plan changing
+// every channel on every run floods gossip. Changes smaller than
+// `min_relative_change_percent` and changes to channels updated less than
+// `min_update_interval_hours` ago are deferred: they stay in the plan with the
+// reason and are decided again by a later run.
+//
+// With a `max_updates_per_hour` budget, the remaining updates are spread over
+// the run: each gets a delay after the start of the apply that keeps every
+// rolling hour within the budget. Updates that would wait an hour or more are
+// deferred as well, so a fresh run decides them from fresh inputs. Disabling a
+// channel is never deferred nor delayed. Inbound discount updates count
+// against the budget and are deferred with the channel's outbound update.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fee_policy::FeeSettings;
+use crate::fees::{ChannelFeePlan, ChannelFees, FeeAction};
+
+/// Longest delay of a paced update, in seconds.
+const PACING_HORIZON_SECS: i64 = 3600;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeferReason {
+    /// Every value changes by less than `min_relative_change_percent`.
+    BelowThreshold,
+    /// The last `setchannel` is less than `min_update_interval_hours` old.
+    MinInterval,
+    /// The `max_updates_per_hour` budget is used by larger changes for the
+    /// next hour.
+    HourlyBudget,
+}
+
+impl DeferReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::BelowThreshold => "below_threshold",
+            Self::MinInterval => "min_interval",
+            Self::HourlyBudget => "hourly_budget",
+        }
+    }
+}
+
+/// Largest relative change, in percent, of the values `setchannel` would send.
+pub fn relative_change_percent(current: &ChannelFees, proposed: &ChannelFees) -> f64 {
+    [
+        (current.base_fee_msat, proposed.base_fee_msat),
+        (current.fee_ppm, proposed.fee_ppm),
+        (current.htlc_min_msat, proposed.htlc_min_msat),
+        (current.htlc_max_msat, proposed.htlc_max_msat),
+    ]
+    .into_iter()
+    .map(|(current, proposed)| current.abs_diff(proposed) as f64 * 100.0 / current.max(1) as f64)
+    .fold(0.0, f64::max)
+}
+
+/// Mark the updates of `plans` that must wait, and delay the others within
+/// the hourly budget. `last_update` returns the unix time of a channel's last
+/// `setchannel`.
+pub fn schedule(
+    plans: &mut [ChannelFeePlan],
+    last_update: impl Fn(&str) -> Option<i64>,
+    now: i64,
+    settings: &FeeSettings,
+) {
+    let min_interval = settings.min_update_interval_hours as i64 * 3600;
+    let mut candidates = Vec::new();
+    for (index, plan) in plans.iter_mut().enumerate() {
+        plan.deferred = None;
+        plan.delay_secs = 0;
+        let inbound = plan.needs_inbound_update();
+        if !plan.needs_update() && !inbound {
+            continue;
+        }
+        // Only reached by a disable that changes the channel
+        let urgent = plan.action == FeeAction::Dis;
+        let change = relative_change_percent(&plan.current, &plan.proposed);
+        let last = last_update(&plan.short_channel_id);
+        // A small outbound change goes out with an inbound update
+        if !urgent && !inbound && change < settings.min_relative_change_percent {
+            plan.deferred = Some(DeferReason::BelowThreshold);
+        } else if !urgent && last.is_some_and(|last| now - last < min_interval) {
+            plan.deferred = Some(DeferReason::MinInterval);
+        } else {
+            candidates.push((index, urgent, change, last));
+        }
+    }
+
+    let budget = settings.max_updates_per_hour;
+    if budget == 0 {
+        return;
+    }
+    let spacing = 3600 / budget as i64;
+    let mut updates: Vec<i64> = plans
+        .iter()
+        .filter_map(|plan| last_update(&plan.short_channel_id))
+        .filter(|last| now - last < 3600)
+        .collect();
+    updates.sort_unstable();
+    // Disabling first, then the largest changes, then the longest waiting
+    candidates.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then(b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal))
+            .then(a.3.cmp(&b.3))
+    });
+    let mut previous = None;
+    for (index, urgent, ..) in candidates {
+        let mut at = now;
+        if !urgent {
+            if let Some(previous) = previous {
+                at = at.max(previous + spacing);
+            }
+            if updates.len() >= budget {
+                // The update `budget` calls back must have left the hour
+                at = at.max(updates[updates.len() - budget] + 3600);
+            }
+            if at - now >= PACING_HORIZON_SECS {
+                plans[index].deferred = Some(DeferReason::HourlyBudget);
+                continue;
+            }
+        }
+        plans[index].delay_secs = (at - now) as u64;
+        updates.push(at);
+        previous = Some(at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fees::{FeeInputs, FeeState, ForwardActivity};
+
+    const NOW: i64 = 1_000_000;
+
+    #[test]
+    fn small_changes_are_deferred() {
+        let settings = FeeSettings::default();
+        // a fixed ppm moving by less than any controller step
+        let mut plans = [plan("1x1x1", 1000, 1005), plan("2x2x2", 1000, 1020)];
+        schedule(&mut plans, |_| None, NOW, &settings);
+        assert_eq!(plans[0].deferred, Some(DeferReason::BelowThreshold));
+        assert_eq!(plans[1].deferred, None);
+    }
+
+    #[test]
+    fn recently_updated_channels_wait_for_the_interval() {
+        let settings = FeeSettings::default();
+        let mut plans = [plan("1x1x1", 100, 110), plan("2x2x2", 100, 110)];
+        let last_update = |scid: &str| (scid == "1x1x1").then_some(NOW - 3600);
+        schedule(&mut plans, last_update, NOW, &settings);
+        assert_eq!(plans[0].deferred, Some(DeferReason::MinInterval));
+        assert_eq!(plans[1].deferred, None);
+
+        let last_update = |_: &str| Some(NOW - settings.min_update_interval_hours as i64 * 3600);
+        schedule(&mut plans, last_update, NOW, &settings);
+        assert_eq!(plans[0].deferred, None);
+    }
+
+    #[test]
+    fn inbound_updates_are_deferred_with_the_channel() {
+        let settings = FeeSettings::default();
+        let mut inbound = plan("1x1x1", 1000, 1000);
+        inbound.inbound_discount_ppm = Some(50);
+        let mut small_with_inbound = plan("2x2x2", 1000, 1005);
+        small_with_inbound.inbound_discount_ppm = Some(50);
+        let mut plans = [inbound, small_with_inbound];
+        schedule(&mut plans, |_| None, NOW, &settings);
+        assert_eq!(plans[0].deferred, None);
+        assert_eq!(plans[1].deferred, None);
+
+        let last_update = |scid: &str| (scid == "1x1x1").then_some(NOW - 3600);
+        schedule(&mut plans, last_update, NOW, &settings);
+        assert_eq!(plans[0].deferred, Some(DeferReason::MinInterval));
+    }
+
+    #[test]
+    fn hourly_budget_keeps_disables_and_the_largest_changes() {
+        let settings = FeeSettings {
+            max_updates_per_hour: 3,
+            min_update_interval_hours: 0,
+            ..FeeSettings::default()
+        };
+        let mut disabled = plan("4x4x4", 100, 100);
+        disabled.action = FeeAction::Dis;
+        disabled.proposed.htlc_max_msat = 1;
+        let mut plans = [
+            plan("1x1x1", 100, 102),
+            plan("2x2x2", 100, 150),
+            plan("3x3x3", 100, 110),
+            disabled,
+        ];
+        // one update already used the budget of this hour
+        let last_update = |scid: &str| (scid == "3x3x3").then_some(NOW - 60);
+        schedule(&mut plans, last_update, NOW, &settings);
+        let scheduled: Vec<_> = plans
+            .iter()
+            .map(|plan| (plan.deferred, plan.delay_secs))
+            .collect();
+        // 20 minutes apart, the third once the earlier update left the hour
+        assert_eq!(
+            scheduled,
+            [
+                (Some(DeferReason::HourlyBudget), 0),
+                (None, 1200),
+                (None, 3540),
+                (None, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_wait_at_most_the_current_hour() {
+        let settings = FeeSettings {
+            max_updates_per_hour: 1,
+            ..FeeSettings::default()
+        };
+        let mut plans = [plan("1x1x1", 100, 150), plan("2x2x2", 100, 110)];
+        schedule(&mut plans, |_| None, NOW, &settings);
+        // the next slot is an hour away, long before the 20h minimum interval
+        assert_eq!(plans[0].deferred, None);
+        assert_eq!(plans[1].deferred, Some(DeferReason::HourlyBudget));
+    }
+
+    fn plan(short_channel_id: &str, current_ppm: u64, proposed_ppm: u64) -> ChannelFeePlan {
+        let current = ChannelFees {
+            base_fee_msat: 1000,
+            fee_ppm: current_ppm,
+            htlc_min_msat: 100_000,
+            htlc_max_msat: 536_870_912,
+        };
+        ChannelFeePlan {
+            short_channel_id: short_channel_id.to_string(),
+            peer_id: "peer".to_string(),
+            alias: "alias".to_string(),
+            action: if proposed_ppm > current_ppm {
+                FeeAction::Inc
+            } else {
+                FeeAction::Equ
+            },
+            inputs: FeeInputs {
+                state: FeeState::Normal,
+                activity: ForwardActivity::None,
+                forwards_since: 0.0,
+                settled_out_msat: 0,
+                settled_forwards: 0,
+                distinct_payments: 0,
+                failed_forwards: 0,
+                routed_since_watermark_msat: 0,
+                local_balance_msat: 1_000_000_000,
+                capacity_msat: 2_000_000_000,
+                availability: None,
+                previous_availability_state: None,
+                availability_state: Default::default(),
+                outbound_fees_sat: 0,
+                indirect_fees_sat: 0,
+                inbound_role: false,
+            },
+            current,
+            proposed: ChannelFees {
+                fee_ppm: proposed_ppm,
+                ..current
+            },
+            watermark: None,
+            current_inbound_discount_ppm: None,
+            inbound_discount_ppm: None,
+            deferred: None,
+            delay_secs: 0,
+        }
+    }
+}
@@ -1,34 +1,71 @@
 use std::cmp::{max, min, Ordering};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
 
-use crate::cmd::Forward;
+use crate::availability::{self, AvailabilityRecord, AvailabilityState};
+use crate::cmd::{self, Channel, Forward, Fund};
+use crate::fee_policy::{ChannelPolicy, FeePolicy, FeeSettings};
+use crate::fee_schedule::{self, DeferReason};
 use crate::store::Store;
 
 pub const PPM_MIN: u64 = 1;
 pub const PPM_MAX: u64 = 5000;
 pub const DEPLETED_LOCAL_BALANCE_SAT: u64 = 50000;
 pub const MIN_HTLC: u64 = 100000; // msat
+pub const LIGHT_INCREASE_PERCENT: u64 = 2;
 pub const FORWARD_INCREASE_PERCENT: u64 = 5;
+pub const STRONG_INCREASE_PERCENT: u64 = 10;
+pub const MODERATE_ROUTED_CAPACITY_PERCENT: f64 = 1.0;
+pub const STRONG_ROUTED_CAPACITY_PERCENT: f64 = 5.0;
+pub const MODERATE_DISTINCT_PAYMENTS: usize = 2;
+pub const STRONG_DISTINCT_PAYMENTS: usize = 3;
 pub const DEPLETED_INCREASE_PERCENT: u64 = 1;
 pub const BOOTSTRAP_DECREASE_PERCENT: u64 = 15;
 pub const NORMAL_DECREASE_PERCENT: u64 = 2;
 pub const FEE_BASE: u64 = 1000; // msat
-pub const MIN_ROUTED_24H_SAT: u64 = 5000;
+pub const MIN_ROUTED_SAT: u64 = 5000;
+/// Lifetime settled amount a channel must route out to leave bootstrap.
+pub const BOOTSTRAP_EXIT_SETTLED_SAT: u64 = 20000;
+pub const MIN_AVAILABILITY: f64 = 0.8;
+pub const DEGRADED_AVAILABILITY: f64 = 0.9;
+pub const AVAILABILITY_RECOVERY_MARGIN: f64 = 0.03;
+/// Forwards counted by a channel's first step, before it has a watermark.
+pub const FIRST_WINDOW_HOURS: i64 = 24;
+/// Inbound discount of channels in the inbound role; 0 keeps it off.
+pub const INBOUND_DISCOUNT_PPM: u64 = 0;
+pub const INBOUND_ROLE_INDIRECT_PERCENT: u64 = 75;
+pub const INBOUND_ROLE_MIN_INDIRECT_SAT: u64 = 1000;
+pub const MIN_RELATIVE_CHANGE_PERCENT: f64 = 1.0;
+pub const MIN_UPDATE_INTERVAL_HOURS: u64 = 20;
+/// `setchannel` calls allowed per hour; 0 means no limit.
+pub const MAX_UPDATES_PER_HOUR: usize = 0;
+/// Fee changes kept in a channel's datastore log, the oldest dropped first.
+const FEE_CHANGES_KEPT: usize = 500;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum FeeState {
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeState {
     Bootstrap,
     Normal,
     Depleted,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum ForwardActivity {
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardActivity {
     None,
     BelowMinimum,
-    MeetsMinimum,
+    /// Meets the minimum routed amount only.
+    Light,
+    /// Routed a noticeable share of the capacity, or several payments.
+    Moderate,
+    /// Routed a large share of the capacity over several payments.
+    Strong,
 }
 
 impl FeeState {
@@ -41,43 +78,560 @@ impl FeeState {
     }
 }
 
-pub fn run_fees(store: &Store) {
-    let normal_channels = store.normal_channels();
-    let forwards_24h = store.filter_forwards_by_hours(24);
-    let ever_settled_out_channels: HashSet<&str> = store.settled_out_channel_ids().collect();
+/// Fee changes decided for every normal channel. `fees --plan` writes it for
+/// review and `fees --apply` executes it later.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeePlan {
+    pub node_id: String,
+    /// RFC 3339 time the plan was computed.
+    pub generated_at: String,
+    pub channels: Vec<ChannelFeePlan>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelFeePlan {
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub alias: String,
+    pub action: FeeAction,
+    pub inputs: FeeInputs,
+    pub current: ChannelFees,
+    pub proposed: ChannelFees,
+    /// Latest `resolved_time` among the settled forwards this step counted,
+    /// saved as the channel's watermark once the plan is applied.
+    pub watermark: Option<f64>,
+    /// Inbound discount last set by lightdash, from the datastore.
+    #[serde(default)]
+    pub current_inbound_discount_ppm: Option<u64>,
+    /// Discount on forwards coming in through the channel, set only when the
+    /// node's `setchannel` supports inbound fees. `None` leaves it alone.
+    #[serde(default)]
+    pub inbound_discount_ppm: Option<u64>,
+    /// Why the update waits for a later run, see fee_schedule.rs.
+    #[serde(default)]
+    pub deferred: Option<DeferReason>,
+    /// Seconds after the start of the apply before the update runs, spreading
+    /// the `max_updates_per_hour` budget.
+    #[serde(default)]
+    pub delay_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum FeeAction {
+    /// Unchanged ppm; htlc limits may still change.
+    Equ,
+    Inc,
+    Dec,
+    /// The peer is in the disabled availability state, the channel is
+    /// disabled with htlcmax=1msat.
+    Dis,
+}
+
+/// What the controller looked at when deciding a channel's fees.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeeInputs {
+    pub state: FeeState,
+    pub activity: ForwardActivity,
+    /// Forwards resolved after this unix time are counted: the channel's
+    /// watermark, or the start of the first window without one.
+    pub forwards_since: f64,
+    /// Lifetime settled amount forwarded out of the channel.
+    pub settled_out_msat: u64,
+    /// The counts cover the forwards since `forwards_since`. Without a
+    /// settlement since then, failures only count over the first window.
+    pub settled_forwards: usize,
+    pub distinct_payments: usize,
+    pub failed_forwards: usize,
+    pub routed_since_watermark_msat: u64,
+    pub local_balance_msat: u64,
+    pub capacity_msat: u64,
+    pub availability: Option<f64>,
+    /// Peer availability state persisted by the previous applied run.
+    pub previous_availability_state: Option<AvailabilityState>,
+    pub availability_state: AvailabilityState,
+    /// Lifetime fees earned forwarding out of the channel.
+    #[serde(default)]
+    pub outbound_fees_sat: u64,
+    /// Lifetime fees earned on other channels by forwards that came in
+    /// through this one.
+    #[serde(default)]
+    pub indirect_fees_sat: u64,
+    /// The channel is mostly valuable for the incoming liquidity it supplies.
+    #[serde(default)]
+    pub inbound_role: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ChannelFees {
+    pub base_fee_msat: u64,
+    pub fee_ppm: u64,
+    pub htlc_min_msat: u64,
+    pub htlc_max_msat: u64,
+}
+
+/// One applied `setchannel`, appended as a JSON line to the datastore string
+/// `lightdash/fee_changes/<short_channel_id>`, which keeps the last
+/// `FEE_CHANGES_KEPT` changes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FeeChange {
+    /// RFC 3339 time `setchannel` succeeded.
+    pub changed_at: String,
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub action: FeeAction,
+    pub old_base_fee_msat: u64,
+    pub new_base_fee_msat: u64,
+    pub old_fee_ppm: u64,
+    pub new_fee_ppm: u64,
+    pub old_htlc_min_msat: u64,
+    pub new_htlc_min_msat: u64,
+    pub old_htlc_max_msat: u64,
+    pub new_htlc_max_msat: u64,
+    pub state: FeeState,
+    pub activity: ForwardActivity,
+    pub routed_since_watermark_msat: u64,
+    pub availability: Option<f64>,
+    pub lightdash_version: String,
+}
+
+impl FeeAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Equ => "EQU",
+            Self::Inc => "INC",
+            Self::Dec => "DEC",
+            Self::Dis => "DIS",
+        }
+    }
+}
+
+impl ChannelFeePlan {
+    /// Whether the outbound fees, base fee included, differ from the
+    /// proposed ones. An already disabled channel needs none.
+    pub fn needs_update(&self) -> bool {
+        self.current != self.proposed
+    }
+
+    /// Whether `setchannel` runs for the outbound fees in this plan.
+    pub fn scheduled(&self) -> bool {
+        self.needs_update() && self.deferred.is_none()
+    }
+
+    /// Whether the inbound discount differs from the one last set.
+    pub fn needs_inbound_update(&self) -> bool {
+        self.inbound_discount_ppm.is_some_and(|discount_ppm| {
+            discount_ppm != self.current_inbound_discount_ppm.unwrap_or(0)
+        })
+    }
+
+    /// Whether `setchannel` runs for the inbound discount in this plan.
+    pub fn inbound_scheduled(&self) -> bool {
+        self.needs_inbound_update() && self.deferred.is_none()
+    }
+
+    fn fee_change(&self, changed_at: DateTime<Utc>) -> FeeChange {
+        FeeChange {
+            changed_at: changed_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            short_channel_id: self.short_channel_id.clone(),
+            peer_id: self.peer_id.clone(),
+            action: self.action,
+            old_base_fee_msat: self.current.base_fee_msat,
+            new_base_fee_msat: self.proposed.base_fee_msat,
+            old_fee_ppm: self.current.fee_ppm,
+            new_fee_ppm: self.proposed.fee_ppm,
+            old_htlc_min_msat: self.current.htlc_min_msat,
+            new_htlc_min_msat: self.proposed.htlc_min_msat,
+            old_htlc_max_msat: self.current.htlc_max_msat,
+            new_htlc_max_msat: self.proposed.htlc_max_msat,
+            state: self.inputs.state,
+            activity: self.inputs.activity,
+            routed_since_watermark_msat: self.inputs.routed_since_watermark_msat,
+            availability: self.inputs.availability,
+            lightdash_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Parse the JSON lines of one channel's fee change log, skipping lines
+/// that do not parse.
+pub fn parse_fee_changes(short_channel_id: &str, log: &str) -> Vec<FeeChange> {
+    log.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(change) => Some(change),
+            Err(e) => {
+                log::warn!("Skipping invalid fee change of {short_channel_id}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn run_fees(store: &Store, policy: &FeePolicy) {
+    let plan = build_plan(store, policy);
+    apply_plan(&plan);
+}
 
-    let mut equ_count = 0;
-    let mut inc_count = 0;
-    let mut dec_count = 0;
-    let mut dis_count = 0;
+/// Write the fee plan to `path` without changing any channel.
+pub fn run_fees_plan(store: &Store, policy: &FeePolicy, path: &str) -> Result<(), String> {
+    let plan = build_plan(store, policy);
+    for channel in &plan.channels {
+        log_channel_plan(channel);
+    }
+    log_trend(&plan);
+    let json = serde_json::to_string_pretty(&plan)
+        .map_err(|e| format!("serializing fee plan failed: {e}"))?;
+    fs::write(path, json + "\n").map_err(|e| format!("writing fee plan `{path}` failed: {e}"))?;
+    let updates = plan.channels.iter().filter(|c| c.scheduled()).count();
+    let deferred = plan
+        .channels
+        .iter()
+        .filter(|c| c.deferred.is_some())
+        .count();
+    log::info!(
+        "Wrote fee plan for {} channels ({updates} updates, {deferred} deferred) to {path}",
+        plan.channels.len()
+    );
+    Ok(())
+}
 
-    for fund in normal_channels.iter() {
-        let short_channel_id = fund.short_channel_id();
-        let our = match store.get_channel(&short_channel_id, &store.info.id) {
-            Some(c) => c,
-            None => continue,
+/// Execute a previously written plan, refusing plans of another node or
+/// older than `max_age`.
+pub fn run_fees_apply(path: &str, max_age: Duration) -> Result<(), String> {
+    let mut plan = read_plan(Path::new(path))?;
+    let node_id = cmd::get_info()?.id;
+    validate_plan(&plan, &node_id, Utc::now(), max_age)?;
+    drop_changed_channels(&mut plan, &live_fees()?);
+    log::info!(
+        "Applying fee plan generated at {} for {} channels",
+        plan.generated_at,
+        plan.channels.len()
+    );
+    apply_plan(&plan);
+    Ok(())
+}
+
+fn read_plan(path: &Path) -> Result<FeePlan, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("reading fee plan `{}` failed: {e}", path.display()))?;
+    serde_json::from_str(&content)
+        .map_err(|e| format!("parsing fee plan `{}` failed: {e}", path.display()))
+}
+
+/// The fees each channel of the node applies now, `None` when the node does
+/// not report its local channel update.
+fn live_fees() -> Result<HashMap<String, Option<ChannelFees>>, String> {
+    let channels = cmd::list_peer_channels()?.channels;
+    Ok(channels
+        .into_iter()
+        .filter_map(|channel| {
+            let fees = channel
+                .updates
+                .and_then(|updates| updates.local)
+                .map(|update| ChannelFees {
+                    base_fee_msat: update.fee_base_msat,
+                    fee_ppm: update.fee_proportional_millionths,
+                    htlc_min_msat: update.htlc_minimum_msat,
+                    htlc_max_msat: update.htlc_maximum_msat,
+                });
+            Some((channel.short_channel_id?, fees))
+        })
+        .collect())
+}
+
+/// Drop the channels whose fees changed since the plan was made, by hand or
+/// by another run, or that are gone, so applying it does not undo the change.
+fn drop_changed_channels(plan: &mut FeePlan, live: &HashMap<String, Option<ChannelFees>>) {
+    plan.channels
+        .retain(|channel| match live.get(&channel.short_channel_id) {
+            None => {
+                log::warn!(
+                    "SKP {} with {} is no longer a channel of the node",
+                    channel.short_channel_id,
+                    channel.alias
+                );
+                false
+            }
+            Some(Some(fees)) if *fees != channel.current => {
+                log::warn!(
+                    "SKP {} with {} changed since the plan: base:{}->{} ppm:{}->{} min_htlc:{}->{} max_htlc:{}->{}",
+                    channel.short_channel_id,
+                    channel.alias,
+                    channel.current.base_fee_msat,
+                    fees.base_fee_msat,
+                    channel.current.fee_ppm,
+                    fees.fee_ppm,
+                    channel.current.htlc_min_msat,
+                    fees.htlc_min_msat,
+                    channel.current.htlc_max_msat,
+                    fees.htlc_max_msat
+                );
+                false
+            }
+            Some(_) => true,
+        });
+}
+
+fn validate_plan(
+    plan: &FeePlan,
+    node_id: &str,
+    now: DateTime<Utc>,
+    max_age: Duration,
+) -> Result<(), String> {
+    if plan.node_id != node_id {
+        return Err(format!(
+            "fee plan belongs to node {}, not {node_id}",
+            plan.node_id
+        ));
+    }
+    let generated_at = DateTime::parse_from_rfc3339(&plan.generated_at)
+        .map_err(|e| format!("invalid fee plan generated_at `{}`: {e}", plan.generated_at))?;
+    let age = now - generated_at.with_timezone(&Utc);
+    if age > max_age {
+        return Err(format!(
+            "fee plan generated at {} is {} hours old, more than the allowed {}",
+            plan.generated_at,
+            age.num_hours(),
+            max_age.num_hours()
+        ));
+    }
+    if age < Duration::zero() {
+        return Err(format!(
+            "fee plan generated at {} is in the future",
+            plan.generated_at
+        ));
+    }
+    Ok(())
+}
+
+pub fn build_plan(store: &Store, policy: &FeePolicy) -> FeePlan {
+    let generated_at = Utc::now();
+    let first_window_start = (generated_at - Duration::hours(FIRST_WINDOW_HOURS)).timestamp();
+    let settled_out_msat = store.settled_out_msat_by_channel();
+
+    let mut channels: Vec<ChannelFeePlan> = store
+        .normal_channels()
+        .iter()
+        .filter_map(|fund| {
+            let short_channel_id = fund.short_channel_id();
+            let our = store.get_channel(&short_channel_id, &store.info.id)?;
+            let alias = store.get_node_alias(&fund.peer_id);
+            let channel_policy = policy.for_channel(&fund.peer_id, &short_channel_id);
+            if channel_policy.excluded {
+                log::info!("SKP {short_channel_id} with {alias} is excluded by the fee policy");
+                return None;
+            }
+            let forwards_since = store
+                .get_fee_watermark(&short_channel_id)
+                .unwrap_or(first_window_start as f64);
+            let evidence = ForwardEvidence::new(
+                &short_channel_id,
+                &store.forwards.forwards,
+                forwards_since,
+                settled_out_msat
+                    .get(short_channel_id.as_str())
+                    .copied()
+                    .unwrap_or_default(),
+            )
+            .with_earned_fees(
+                store.get_channel_total_fees(&short_channel_id),
+                store.get_channel_indirect_fees(&short_channel_id),
+            )
+            .with_first_window_start(first_window_start as f64);
+            let mut plan = plan_channel(
+                fund,
+                our,
+                &alias,
+                evidence,
+                store.avail_map.get(&fund.peer_id).cloned(),
+                store
+                    .get_availability_record(&fund.peer_id)
+                    .map(|record| record.state),
+                &channel_policy,
+            );
+            plan.current_inbound_discount_ppm = store.get_inbound_discount(&short_channel_id);
+            Some(plan)
+        })
+        .collect();
+
+    fee_schedule::schedule(
+        &mut channels,
+        |short_channel_id| store.get_setchannel_timestamp(short_channel_id),
+        generated_at.timestamp(),
+        &policy.defaults,
+    );
+
+    FeePlan {
+        node_id: store.info.id.clone(),
+        generated_at: generated_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        channels,
+    }
+}
+
+fn apply_plan(plan: &FeePlan) {
+    let execute = std::env::var("EXECUTE_SETCHANNEL").is_ok();
+    let inbound_parameter = if plan.channels.iter().any(ChannelFeePlan::inbound_scheduled) {
+        inbound_fee_parameter()
+    } else {
+        None
+    };
+    let started = Instant::now();
+    let mut channels: Vec<&ChannelFeePlan> = plan.channels.iter().collect();
+    channels.sort_by_key(|channel| channel.delay_secs);
+    for channel in channels {
+        log_channel_plan(channel);
+        if channel.delay_secs > 0 && (channel.scheduled() || channel.inbound_scheduled()) {
+            log::info!(
+                "{} with {} waits {}s for the hourly update budget",
+                channel.short_channel_id,
+                channel.alias,
+                channel.delay_secs
+            );
+            if execute {
+                let at = started + std::time::Duration::from_secs(channel.delay_secs);
+                std::thread::sleep(at.saturating_duration_since(Instant::now()));
+            }
+        }
+        // A deferred update keeps the watermark, so its forwards count again
+        let applied = match channel.deferred {
+            Some(_) => false,
+            None => !channel.needs_update() || apply_channel_plan(channel, execute),
         };
-        let alias_or_id = store.get_node_alias(&fund.peer_id);
-        let avail = store.avail_map.get(&fund.peer_id).cloned();
-
-        let trend = calc_setchannel(
-            &short_channel_id,
-            &alias_or_id,
-            fund,
-            our,
-            &forwards_24h,
-            ever_settled_out_channels.contains(short_channel_id.as_str()),
-            avail,
-        );
-        match trend {
-            "EQU" => equ_count += 1,
-            "INC" => inc_count += 1,
-            "DEC" => dec_count += 1,
-            "DIS" => dis_count += 1,
-            _ => {}
+        if applied && execute {
+            save_watermark(channel);
+            save_availability_state(channel);
+        }
+        if channel.inbound_scheduled() {
+            apply_inbound_discount(channel, inbound_parameter.as_deref(), execute);
         }
     }
-    log::info!("setchannel trend: EQU:{equ_count} INC:{inc_count} DEC:{dec_count} DIS:{dis_count}");
+    log_trend(plan);
+}
+
+/// The node's `setchannel` parameter for inbound fees, if it has one.
+fn inbound_fee_parameter() -> Option<String> {
+    match cmd::setchannel_inbound_fee_parameter() {
+        Ok(Some(parameter)) => Some(parameter),
+        Ok(None) => {
+            log::info!("This node's setchannel has no inbound fee parameter, inbound discounts are only reported");
+            None
+        }
+        Err(e) => {
+            log::error!("Checking setchannel for inbound fee support failed: {e}");
+            None
+        }
+    }
+}
+
+/// Set the inbound discount of a channel as a negative inbound fee, and
+/// remember it in the datastore.
+fn apply_inbound_discount(plan: &ChannelFeePlan, parameter: Option<&str>, execute: bool) {
+    let ChannelFeePlan {
+        short_channel_id,
+        alias,
+        inputs,
+        ..
+    } = plan;
+    let discount_ppm = plan.inbound_discount_ppm.unwrap_or_default();
+    log::info!(
+        "INB {short_channel_id} with {alias} discount:{}->{discount_ppm} inbound_role:{} indirect_fees_sat:{} outbound_fees_sat:{}",
+        plan.current_inbound_discount_ppm.unwrap_or_default(),
+        inputs.inbound_role,
+        inputs.indirect_fees_sat,
+        inputs.outbound_fees_sat
+    );
+    let Some(parameter) = parameter else {
+        return;
+    };
+    let args = [
+        "setchannel".to_string(),
+        "-k".to_string(),
+        format!("id={short_channel_id}"),
+        format!("{parameter}={}", -(discount_ppm as i64)),
+    ];
+    if !execute {
+        log::info!("would execute `lightning-cli {}` {alias}", args.join(" "));
+        return;
+    }
+    log::info!("executing `lightning-cli {}` {alias}", args.join(" "));
+    match cmd::cmd_result("lightning-cli", &args) {
+        Ok(result) => log::debug!("cmd return: {result}"),
+        Err(e) => {
+            log::error!("Updating the inbound fee of {short_channel_id} failed: {e}");
+            return;
+        }
+    }
+    if let Err(e) = cmd::datastore_string(
+        &["lightdash", "inbound_discount", short_channel_id],
+        &discount_ppm.to_string(),
+        cmd::DatastoreMode::CreateOrReplace,
+    ) {
+        log::error!("Failed to save inbound discount for {short_channel_id}: {e}");
+    }
+    save_setchannel_timestamp(short_channel_id);
+}
+
+fn save_availability_state(plan: &ChannelFeePlan) {
+    let inputs = &plan.inputs;
+    if inputs.previous_availability_state.unwrap_or_default() == inputs.availability_state {
+        return;
+    }
+    let record = AvailabilityRecord {
+        state: inputs.availability_state,
+        since: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        availability: inputs.availability,
+    };
+    let value = serde_json::to_string(&record).expect("availability record serializes");
+    if let Err(e) = cmd::datastore_string(
+        &["lightdash", "availability", &plan.peer_id],
+        &value,
+        cmd::DatastoreMode::CreateOrReplace,
+    ) {
+        log::error!("Failed to save availability state of {}: {e}", plan.peer_id);
+    }
+}
+
+/// Advance the channel's watermark past the forwards its step counted, so
+/// the next run does not count them again. A disabled channel keeps its
+/// watermark: its forwards have not moved the fee yet.
+fn save_watermark(plan: &ChannelFeePlan) {
+    let Some(watermark) = plan.watermark else {
+        return;
+    };
+    if plan.action == FeeAction::Dis {
+        return;
+    }
+    if let Err(e) = cmd::datastore_string(
+        &["lightdash", "fee_watermark", &plan.short_channel_id],
+        &watermark.to_string(),
+        cmd::DatastoreMode::CreateOrReplace,
+    ) {
+        log::error!(
+            "Failed to save fee watermark for {}: {e}",
+            plan.short_channel_id
+        );
+    }
+}
+
+fn log_trend(plan: &FeePlan) {
+    let count = |action| {
+        plan.channels
+            .iter()
+            .filter(|channel| channel.action == action)
+            .count()
+    };
+    log::info!(
+        "setchannel trend: EQU:{} INC:{} DEC:{} DIS:{} deferred:{}",
+        count(FeeAction::Equ),
+        count(FeeAction::Inc),
+        count(FeeAction::Dec),
+        count(FeeAction::Dis),
+        plan.channels
+            .iter()
+            .filter(|channel| channel.deferred.is_some())
+            .count()
+    );
 }
 
 /// Returns the largest power of 2 that is less than or equal to n.
@@ -91,10 +645,14 @@ pub fn largest_power_of_two_leq(n: u64) -> u64 {
     }
 }
 
-fn fee_state(local_balance_sat: u64, ever_forwarded: bool) -> FeeState {
-    if local_balance_sat < DEPLETED_LOCAL_BALANCE_SAT {
+pub(crate) fn fee_state(
+    local_balance_sat: u64,
+    settled_out_msat: u64,
+    settings: &FeeSettings,
+) -> FeeState {
+    if local_balance_sat < settings.depleted_local_balance_sat {
         FeeState::Depleted
-    } else if ever_forwarded {
+    } else if settled_out_msat >= settings.bootstrap_exit_settled_sat.saturating_mul(1000) {
         FeeState::Normal
     } else {
         FeeState::Bootstrap
@@ -111,193 +669,501 @@ fn decrease_ppm_floor(current_ppm: u64, percent: u64) -> u64 {
     (numerator / 100).min(u64::MAX as u128) as u64
 }
 
-fn forward_activity(settled_forward_count: usize, routed_msat: u64) -> ForwardActivity {
-    if settled_forward_count == 0 {
-        ForwardActivity::None
-    } else if routed_msat >= MIN_ROUTED_24H_SAT * 1000 {
-        ForwardActivity::MeetsMinimum
+/// Grade the settled forwards of the window by routed amount relative to
+/// the channel capacity and by the number of distinct payments.
+pub(crate) fn forward_activity(
+    distinct_payments: usize,
+    routed_msat: u64,
+    capacity_msat: u64,
+    settings: &FeeSettings,
+) -> ForwardActivity {
+    if distinct_payments == 0 {
+        return ForwardActivity::None;
+    }
+    if routed_msat < settings.min_routed_sat.saturating_mul(1000) {
+        return ForwardActivity::BelowMinimum;
+    }
+    let routed_capacity_percent = if capacity_msat == 0 {
+        0.0
+    } else {
+        routed_msat as f64 * 100.0 / capacity_msat as f64
+    };
+    if routed_capacity_percent >= settings.strong_routed_capacity_percent
+        && distinct_payments >= settings.strong_distinct_payments
+    {
+        ForwardActivity::Strong
+    } else if routed_capacity_percent >= settings.moderate_routed_capacity_percent
+        || distinct_payments >= settings.moderate_distinct_payments
+    {
+        ForwardActivity::Moderate
     } else {
-        ForwardActivity::BelowMinimum
+        ForwardActivity::Light
     }
 }
 
-fn adjusted_ppm(current_ppm: u64, state: FeeState, activity: ForwardActivity) -> u64 {
+/// Count payments by hash, so the parts of a multi-part payment count once.
+/// Forwards without a hash count individually.
+fn distinct_payments(forwards: &[&Forward]) -> usize {
+    let mut hashes = HashSet::new();
+    let mut without_hash = 0;
+    for forward in forwards {
+        match &forward.payment_hash {
+            Some(hash) => {
+                hashes.insert(hash.as_str());
+            }
+            None => without_hash += 1,
+        }
+    }
+    hashes.len() + without_hash
+}
+
+pub(crate) fn adjusted_ppm(
+    current_ppm: u64,
+    state: FeeState,
+    activity: ForwardActivity,
+    settings: &FeeSettings,
+) -> u64 {
     let adjusted = match activity {
-        ForwardActivity::MeetsMinimum => increase_ppm_ceil(current_ppm, FORWARD_INCREASE_PERCENT),
+        ForwardActivity::Light => increase_ppm_ceil(current_ppm, settings.light_increase_percent),
+        ForwardActivity::Moderate => {
+            increase_ppm_ceil(current_ppm, settings.forward_increase_percent)
+        }
+        ForwardActivity::Strong => increase_ppm_ceil(current_ppm, settings.strong_increase_percent),
         ForwardActivity::BelowMinimum => current_ppm,
         ForwardActivity::None => match state {
-            FeeState::Bootstrap => decrease_ppm_floor(current_ppm, BOOTSTRAP_DECREASE_PERCENT),
-            FeeState::Normal => decrease_ppm_floor(current_ppm, NORMAL_DECREASE_PERCENT),
-            FeeState::Depleted => increase_ppm_ceil(current_ppm, DEPLETED_INCREASE_PERCENT),
+            FeeState::Bootstrap => {
+                decrease_ppm_floor(current_ppm, settings.bootstrap_decrease_percent)
+            }
+            FeeState::Normal => decrease_ppm_floor(current_ppm, settings.normal_decrease_percent),
+            FeeState::Depleted => {
+                increase_ppm_ceil(current_ppm, settings.depleted_increase_percent)
+            }
         },
     };
 
-    adjusted.clamp(PPM_MIN, PPM_MAX)
+    adjusted.clamp(settings.ppm_min, settings.ppm_max)
 }
 
-pub fn calc_setchannel(
-    short_channel_id: &str,
+/// The forwarding history one channel's step looks at.
+struct ForwardEvidence<'a> {
+    /// Forwards out of the channel resolved after `since`.
+    recent: Vec<&'a Forward>,
+    since: f64,
+    /// Start of the first window, bounding the failures counted when nothing
+    /// settled after `since`.
+    first_window_start: f64,
+    /// Lifetime settled amount forwarded out of the channel.
+    settled_out_msat: u64,
+    outbound_fees_sat: u64,
+    indirect_fees_sat: u64,
+}
+
+impl<'a> ForwardEvidence<'a> {
+    fn new(
+        short_channel_id: &str,
+        forwards: &'a [Forward],
+        since: f64,
+        settled_out_msat: u64,
+    ) -> Self {
+        ForwardEvidence {
+            recent: forwards_after(short_channel_id, since, forwards),
+            since,
+            first_window_start: since,
+            settled_out_msat,
+            outbound_fees_sat: 0,
+            indirect_fees_sat: 0,
+        }
+    }
+
+    /// Lifetime fees the channel earned directly and indirectly, see
+    /// `Store::get_channel_indirect_fees`.
+    fn with_earned_fees(mut self, outbound_fees_sat: u64, indirect_fees_sat: u64) -> Self {
+        self.outbound_fees_sat = outbound_fees_sat;
+        self.indirect_fees_sat = indirect_fees_sat;
+        self
+    }
+
+    /// Count failures only after `first_window_start` while the watermark
+    /// does not move, as it only advances with settlements.
+    fn with_first_window_start(mut self, first_window_start: f64) -> Self {
+        self.first_window_start = first_window_start;
+        self
+    }
+}
+
+/// Whether a channel earns mostly indirect fees, by supplying the incoming
+/// liquidity of forwards paid on other channels.
+fn inbound_role(outbound_fees_sat: u64, indirect_fees_sat: u64, settings: &FeeSettings) -> bool {
+    let total_fees_sat = outbound_fees_sat.saturating_add(indirect_fees_sat) as u128;
+    indirect_fees_sat >= settings.inbound_role_min_indirect_sat
+        && indirect_fees_sat as u128 * 100
+            >= total_fees_sat * settings.inbound_role_indirect_percent as u128
+}
+
+fn plan_channel(
+    fund: &Fund,
+    our: &Channel,
     alias: &str,
-    fund: &crate::cmd::Fund,
-    our: &crate::cmd::Channel,
-    forwards_24h: &[Forward],
-    ever_forwarded: bool,
+    evidence: ForwardEvidence,
     avail: Option<f64>,
-) -> &'static str {
-    let channel_fund_perc_ours = fund.perc_float(); // how full of our funds is the channel
-    let disp_perc = format!("{:.1}%", channel_fund_perc_ours * 100.0);
-    let current_channel_forwards = did_forward(short_channel_id, forwards_24h);
-    let forwards_all = current_channel_forwards.len();
-    let forwards_ok = current_channel_forwards
+    previous_availability_state: Option<AvailabilityState>,
+    policy: &ChannelPolicy,
+) -> ChannelFeePlan {
+    let settings = &policy.settings;
+    let short_channel_id = fund.short_channel_id();
+    let ForwardEvidence {
+        recent,
+        since: forwards_since,
+        first_window_start,
+        settled_out_msat,
+        outbound_fees_sat,
+        indirect_fees_sat,
+    } = evidence;
+    let settled: Vec<&Forward> = recent
         .iter()
+        .copied()
         .filter(|e| e.status == "settled")
+        .collect();
+    let watermark = settled
+        .iter()
+        .filter_map(|forward| forward.resolved_time)
+        .reduce(f64::max);
+    let forwards_ok = settled.len();
+    let failures_since = if forwards_ok == 0 {
+        forwards_since.max(first_window_start)
+    } else {
+        forwards_since
+    };
+    let failed_forwards = recent
+        .iter()
+        .filter(|forward| forward.status != "settled")
+        .filter(|forward| forward.resolved_time.unwrap_or(forward.received_time) > failures_since)
         .count();
-    let forwards_ko = forwards_all - forwards_ok;
-    let routed_24h_msat = current_channel_forwards
+    let payments = distinct_payments(&settled);
+    let routed_since_watermark_msat = settled
         .iter()
-        .filter(|forward| forward.status == "settled")
         .filter_map(|forward| forward.out_msat)
         .fold(0u64, u64::saturating_add);
-    let activity = forward_activity(forwards_ok, routed_24h_msat);
+    let activity = forward_activity(
+        payments,
+        routed_since_watermark_msat,
+        fund.amount_msat,
+        settings,
+    );
 
-    let current_ppm = our.fee_per_millionth;
-    let current_max_htlc_sat = our.htlc_maximum_msat;
-    let current_min_htlc_sat = our.htlc_minimum_msat;
     let our_amount_msat = fund.our_amount_msat;
     let local_balance_sat = our_amount_msat / 1000;
-    let state = fee_state(local_balance_sat, ever_forwarded);
-
-    if let Some(avail) = avail {
-        if avail < 0.8 {
-            // the channel is not available enough, "disable" it by setting htlc to 1msat
-
-            let cmd = "lightning-cli";
-            let args = format!("setchannel {short_channel_id} {FEE_BASE} {current_ppm} 1 1");
-            let splitted_args: Vec<&str> = args.split(' ').collect();
-            log::info!(
-                "DIS {short_channel_id} with {alias}. avail:{:.1}%",
-                avail * 100.0
-            );
-            if std::env::var("EXECUTE_SETCHANNEL").is_ok() {
-                let result = crate::cmd::cmd_result(cmd, &splitted_args);
-                log::debug!("cmd return: {result}");
-            }
-            return "DIS";
-        }
-    }
-
-    // Compute the largest power of 2 <= our_amount_msat for max HTLC
-    let new_max_htlc_msat = max(largest_power_of_two_leq(our_amount_msat), 1); // max_htlc canno be 0 when min_htlc is 1
-
-    let new_min_htlc_msat = min(
-        max(MIN_HTLC, current_min_htlc_sat), // some peer may enforce an higher than MIN_HTLC minimum value, thus we use the higher value
-        max(new_max_htlc_msat, 1), // min_htlc cannot be greater than max_htlc and lower than 1
-    );
+    let state = fee_state(local_balance_sat, settled_out_msat, settings);
+    let inputs = FeeInputs {
+        state,
+        activity,
+        forwards_since,
+        settled_out_msat,
+        settled_forwards: forwards_ok,
+        distinct_payments: payments,
+        failed_forwards,
+        routed_since_watermark_msat,
+        local_balance_msat: our_amount_msat,
+        capacity_msat: fund.amount_msat,
+        availability: avail,
+        previous_availability_state,
+        availability_state: availability::next_state(previous_availability_state, avail, settings),
+        outbound_fees_sat,
+        indirect_fees_sat,
+        inbound_role: inbound_role(outbound_fees_sat, indirect_fees_sat, settings),
+    };
+    let current = ChannelFees {
+        base_fee_msat: our.base_fee_millisatoshi,
+        fee_ppm: our.fee_per_millionth,
+        htlc_min_msat: our.htlc_minimum_msat,
+        htlc_max_msat: our.htlc_maximum_msat,
+    };
 
-    let new_ppm = adjusted_ppm(current_ppm, state, activity);
+    let (action, proposed) = if inputs.availability_state == AvailabilityState::Disabled {
+        // the channel is not available enough, "disable" it by setting htlc to 1msat
+        let proposed = ChannelFees {
+            base_fee_msat: settings.fee_base_msat,
+            fee_ppm: current.fee_ppm,
+            htlc_min_msat: 1,
+            htlc_max_msat: 1,
+        };
+        (FeeAction::Dis, proposed)
+    } else {
+        // A degraded peer may only use a share of the balance proportional to its availability
+        let usable_msat = if inputs.availability_state == AvailabilityState::Degraded {
+            (our_amount_msat as f64 * availability::degraded_htlc_max_factor(avail, settings))
+                as u64
+        } else {
+            our_amount_msat
+        };
+        // Compute the largest power of 2 <= usable_msat for max HTLC
+        let new_max_htlc_msat = max(largest_power_of_two_leq(usable_msat), 1); // max_htlc canno be 0 when min_htlc is 1
 
-    let changes = current_ppm != new_ppm
-        || current_max_htlc_sat != new_max_htlc_msat
-        || current_min_htlc_sat != new_min_htlc_msat;
+        let new_min_htlc_msat = min(
+            max(settings.min_htlc_msat, current.htlc_min_msat), // some peer may enforce an higher than MIN_HTLC minimum value, thus we use the higher value
+            max(new_max_htlc_msat, 1), // min_htlc cannot be greater than max_htlc and lower than 1
+        );
 
-    let data = match new_ppm.cmp(&current_ppm) {
-        Ordering::Equal => "EQU",
-        Ordering::Greater => "INC",
-        Ordering::Less => "DEC",
+        let new_ppm = policy
+            .fixed_ppm
+            .unwrap_or_else(|| adjusted_ppm(current.fee_ppm, state, activity, settings));
+        let action = match new_ppm.cmp(&current.fee_ppm) {
+            Ordering::Equal => FeeAction::Equ,
+            Ordering::Greater => FeeAction::Inc,
+            Ordering::Less => FeeAction::Dec,
+        };
+        let proposed = ChannelFees {
+            base_fee_msat: settings.fee_base_msat,
+            fee_ppm: new_ppm,
+            htlc_min_msat: new_min_htlc_msat,
+            htlc_max_msat: new_max_htlc_msat,
+        };
+        (action, proposed)
+    };
+    // A disabled channel keeps its discount, other channels lose it with the role
+    let inbound_discount_ppm = match policy.inbound_discount_ppm {
+        _ if action == FeeAction::Dis => None,
+        Some(discount_ppm) => Some(discount_ppm),
+        None if inputs.inbound_role => Some(settings.inbound_discount_ppm),
+        None => Some(0),
     };
 
-    if changes {
+    ChannelFeePlan {
+        short_channel_id,
+        peer_id: fund.peer_id.clone(),
+        alias: alias.to_string(),
+        action,
+        inputs,
+        current,
+        proposed,
+        watermark,
+        current_inbound_discount_ppm: None,
+        inbound_discount_ppm,
+        deferred: None,
+        delay_secs: 0,
+    }
+}
+
+fn log_channel_plan(plan: &ChannelFeePlan) {
+    let ChannelFeePlan {
+        short_channel_id,
+        alias,
+        inputs,
+        current,
+        proposed,
+        ..
+    } = plan;
+    let previous_state = inputs.previous_availability_state.unwrap_or_default();
+    if previous_state != inputs.availability_state {
+        log::info!(
+            "AVL {short_channel_id} with {alias} {previous_state}->{} avail:{}",
+            inputs.availability_state,
+            inputs
+                .availability
+                .map_or("unknown".to_string(), |avail| format!(
+                    "{:.1}%",
+                    avail * 100.0
+                ))
+        );
+    }
+    if let Some(reason) = plan.deferred {
+        log::info!(
+            "DEF {} {short_channel_id} with {alias} deferred ({}): change:{:.1}% ppm:{}->{} max_htlc:{}->{}",
+            plan.action.as_str(),
+            reason.as_str(),
+            fee_schedule::relative_change_percent(current, proposed),
+            current.fee_ppm,
+            proposed.fee_ppm,
+            current.htlc_max_msat,
+            proposed.htlc_max_msat
+        );
+    } else if plan.action == FeeAction::Dis {
+        log::info!(
+            "DIS {short_channel_id} with {alias}. avail:{:.1}%{}",
+            inputs.availability.unwrap_or_default() * 100.0,
+            if plan.needs_update() {
+                ""
+            } else {
+                " already disabled"
+            }
+        );
+    } else if plan.needs_update() {
         let mut change_parts = Vec::new();
-        if current_ppm != new_ppm {
-            change_parts.push(format!("ppm:{current_ppm}->{new_ppm}"));
+        if current.base_fee_msat != proposed.base_fee_msat {
+            change_parts.push(format!(
+                "base:{}->{}",
+                current.base_fee_msat, proposed.base_fee_msat
+            ));
+        }
+        if current.fee_ppm != proposed.fee_ppm {
+            change_parts.push(format!("ppm:{}->{}", current.fee_ppm, proposed.fee_ppm));
         }
-        if current_max_htlc_sat != new_max_htlc_msat {
+        if current.htlc_max_msat != proposed.htlc_max_msat {
             change_parts.push(format!(
-                "max_htlc:{current_max_htlc_sat}->{new_max_htlc_msat}"
+                "max_htlc:{}->{}",
+                current.htlc_max_msat, proposed.htlc_max_msat
             ));
         }
-        if current_min_htlc_sat != new_min_htlc_msat {
+        if current.htlc_min_msat != proposed.htlc_min_msat {
             change_parts.push(format!(
-                "min_htlc:{current_min_htlc_sat}->{new_min_htlc_msat}"
+                "min_htlc:{}->{}",
+                current.htlc_min_msat, proposed.htlc_min_msat
             ));
         }
         let change_str = change_parts.join(" ");
+        let disp_perc = if inputs.capacity_msat == 0 {
+            "0.0%".to_string()
+        } else {
+            format!(
+                "{:.1}%",
+                (inputs.local_balance_msat as f64 / inputs.capacity_msat as f64).clamp(0.0, 1.0)
+                    * 100.0
+            )
+        };
         log::info!(
-            "{data} state:{} ok:{forwards_ok} ko:{forwards_ko} routed_24h_msat:{routed_24h_msat} {short_channel_id} with {alias}. my_fund:{our_amount_msat} ({disp_perc})  {change_str}",
-            state.as_str()
-        );
-
-        let cmd = "lightning-cli";
-        let args = format!(
-            "setchannel {short_channel_id} {FEE_BASE} {new_ppm} {new_min_htlc_msat} {new_max_htlc_msat}"
+            "{} state:{} ok:{} ko:{} payments:{} routed_msat:{} {short_channel_id} with {alias}. my_fund:{} ({disp_perc})  {change_str}",
+            plan.action.as_str(),
+            inputs.state.as_str(),
+            inputs.settled_forwards,
+            inputs.failed_forwards,
+            inputs.distinct_payments,
+            inputs.routed_since_watermark_msat,
+            inputs.local_balance_msat,
         );
+    } else {
+        log::info!(
+            "EQU state:{} routed_msat:{} no changes in {short_channel_id} with {alias}, skipping",
+            inputs.state.as_str(),
+            inputs.routed_since_watermark_msat
+        )
+    }
+}
 
-        if std::env::var("EXECUTE_SETCHANNEL").is_ok() {
-            log::info!("executing `{cmd} {args}` {alias}");
+/// Run `setchannel` for the plan, returning whether it succeeded.
+fn apply_channel_plan(plan: &ChannelFeePlan, execute: bool) -> bool {
+    let ChannelFeePlan {
+        short_channel_id,
+        alias,
+        proposed,
+        ..
+    } = plan;
+    let cmd = "lightning-cli";
+    let args = format!(
+        "setchannel {short_channel_id} {} {} {} {}",
+        proposed.base_fee_msat, proposed.fee_ppm, proposed.htlc_min_msat, proposed.htlc_max_msat
+    );
 
-            let splitted_args: Vec<&str> = args.split(' ').collect();
-            let result = crate::cmd::cmd_result(cmd, &splitted_args);
-            log::debug!("cmd return: {result}");
+    if !execute {
+        log::info!("would execute `{cmd} {args}` {alias}");
+        return false;
+    }
+    log::info!("executing `{cmd} {args}` {alias}");
 
-            // Save timestamp to datastore
-            let timestamp = Utc::now().timestamp().to_string();
-            if let Err(e) = crate::cmd::datastore_string(
-                &["lightdash", "last_setchannel", short_channel_id],
-                &timestamp,
-                crate::cmd::DatastoreMode::CreateOrReplace,
-            ) {
-                log::error!(
-                    "Failed to save setchannel timestamp for {}: {}",
-                    short_channel_id,
-                    e
-                );
-            }
-        } else {
-            log::info!("would execute `{cmd} {args}` {alias}");
+    let splitted_args: Vec<&str> = args.split(' ').collect();
+    match cmd::cmd_result(cmd, &splitted_args) {
+        Ok(result) => log::debug!("cmd return: {result}"),
+        Err(e) => {
+            log::error!("Updating {short_channel_id} failed: {e}");
+            return false;
         }
-    } else {
-        log::info!(
-            "EQU state:{} routed_24h_msat:{routed_24h_msat} no changes in {short_channel_id} with {alias}, skipping",
-            state.as_str()
-        )
-    };
-    data
+    }
+    record_fee_change(&plan.fee_change(Utc::now()));
+    if plan.action == FeeAction::Dis {
+        return true;
+    }
+    save_setchannel_timestamp(short_channel_id);
+    true
 }
 
-pub fn did_forward<'a>(
+/// Remember the time of the channel's last `setchannel`, read back by the
+/// update schedule.
+fn save_setchannel_timestamp(short_channel_id: &str) {
+    let timestamp = Utc::now().timestamp().to_string();
+    if let Err(e) = cmd::datastore_string(
+        &["lightdash", "last_setchannel", short_channel_id],
+        &timestamp,
+        cmd::DatastoreMode::CreateOrReplace,
+    ) {
+        log::error!(
+            "Failed to save setchannel timestamp for {}: {}",
+            short_channel_id,
+            e
+        );
+    }
+}
+
+fn record_fee_change(change: &FeeChange) {
+    let line = serde_json::to_string(change).expect("fee change serializes") + "\n";
+    if let Err(e) = cmd::datastore_append_line(
+        &["lightdash", "fee_changes", &change.short_channel_id],
+        &line,
+        FEE_CHANGES_KEPT,
+    ) {
+        log::error!(
+            "Failed to record fee change for {}: {e}",
+            change.short_channel_id
+        );
+    }
+}
+
+/// Forwards out of the channel resolved after `since`; unresolved ones by
+/// their received time.
+pub fn forwards_after<'a>(
     short_channel_id: &str,
-    forwards: &'a [crate::cmd::Forward],
-) -> Vec<&'a crate::cmd::Forward> {
+    since: f64,
+    forwards: &'a [Forward],
+) -> Vec<&'a Forward> {
     forwards
         .iter()
         .filter(|f| f.out_channel.as_deref() == Some(short_channel_id))
+        .filter(|f| f.resolved_time.unwrap_or(f.received_time) > since)
         .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fee_policy::FeeOverride;
+
+    const CAPACITY_MSAT: u64 = 2_000_000_000;
 
     #[test]
     fn fee_state_gives_depleted_balance_precedence() {
         assert_eq!(
-            fee_state(DEPLETED_LOCAL_BALANCE_SAT - 1, false),
+            fee_state(DEPLETED_LOCAL_BALANCE_SAT - 1, 0, &FeeSettings::default()),
             FeeState::Depleted
         );
         assert_eq!(
-            fee_state(DEPLETED_LOCAL_BALANCE_SAT - 1, true),
+            fee_state(
+                DEPLETED_LOCAL_BALANCE_SAT - 1,
+                BOOTSTRAP_EXIT_SETTLED_SAT * 1000,
+                &FeeSettings::default()
+            ),
             FeeState::Depleted
         );
     }
 
     #[test]
-    fn fee_state_distinguishes_bootstrap_and_normal_channels() {
+    fn fee_state_requires_settled_amount_to_leave_bootstrap() {
+        let settings = FeeSettings::default();
         assert_eq!(
-            fee_state(DEPLETED_LOCAL_BALANCE_SAT, false),
+            fee_state(DEPLETED_LOCAL_BALANCE_SAT, 0, &settings),
             FeeState::Bootstrap
         );
         assert_eq!(
-            fee_state(DEPLETED_LOCAL_BALANCE_SAT, true),
+            fee_state(
+                DEPLETED_LOCAL_BALANCE_SAT,
+                BOOTSTRAP_EXIT_SETTLED_SAT * 1000 - 1,
+                &settings
+            ),
+            FeeState::Bootstrap
+        );
+        assert_eq!(
+            fee_state(
+                DEPLETED_LOCAL_BALANCE_SAT,
+                BOOTSTRAP_EXIT_SETTLED_SAT * 1000,
+                &settings
+            ),
             FeeState::Normal
         );
     }
@@ -318,10 +1184,24 @@ mod tests {
     #[test]
     fn recent_forward_increases_every_channel_state() {
         for state in [FeeState::Bootstrap, FeeState::Normal, FeeState::Depleted] {
-            assert_eq!(adjusted_ppm(100, state, ForwardActivity::MeetsMinimum), 105);
+            for (activity, ppm) in [
+                (ForwardActivity::Light, 102),
+                (ForwardActivity::Moderate, 105),
+                (ForwardActivity::Strong, 110),
+            ] {
+                assert_eq!(
+                    adjusted_ppm(100, state, activity, &FeeSettings::default()),
+                    ppm
+                );
+            }
         }
         assert_eq!(
-            adjusted_ppm(PPM_MIN, FeeState::Depleted, ForwardActivity::MeetsMinimum),
+            adjusted_ppm(
+                PPM_MIN,
+                FeeState::Depleted,
+                ForwardActivity::Light,
+                &FeeSettings::default()
+            ),
             2
         );
     }
@@ -329,35 +1209,143 @@ mod tests {
     #[test]
     fn low_volume_forwarding_keeps_ppm_unchanged() {
         for state in [FeeState::Bootstrap, FeeState::Normal, FeeState::Depleted] {
-            assert_eq!(adjusted_ppm(100, state, ForwardActivity::BelowMinimum), 100);
+            assert_eq!(
+                adjusted_ppm(
+                    100,
+                    state,
+                    ForwardActivity::BelowMinimum,
+                    &FeeSettings::default()
+                ),
+                100
+            );
         }
     }
 
     #[test]
     fn forwarding_activity_requires_5000_routed_sats() {
-        assert_eq!(forward_activity(0, 0), ForwardActivity::None);
+        let settings = FeeSettings::default();
+        assert_eq!(
+            forward_activity(0, 0, CAPACITY_MSAT, &settings),
+            ForwardActivity::None
+        );
         assert_eq!(
-            forward_activity(1, MIN_ROUTED_24H_SAT * 1000 - 1),
+            forward_activity(5, MIN_ROUTED_SAT * 1000 - 1, 1, &settings),
             ForwardActivity::BelowMinimum
         );
+    }
+
+    #[test]
+    fn single_small_payment_is_light_activity() {
+        assert_eq!(
+            forward_activity(
+                1,
+                MIN_ROUTED_SAT * 1000,
+                CAPACITY_MSAT,
+                &FeeSettings::default()
+            ),
+            ForwardActivity::Light
+        );
+    }
+
+    #[test]
+    fn routed_share_of_capacity_or_payment_count_is_moderate_activity() {
+        let settings = FeeSettings::default();
+        // 1% of a 2M sat channel in a single payment
+        assert_eq!(
+            forward_activity(1, CAPACITY_MSAT / 100, CAPACITY_MSAT, &settings),
+            ForwardActivity::Moderate
+        );
+        // two small payments
+        assert_eq!(
+            forward_activity(
+                MODERATE_DISTINCT_PAYMENTS,
+                MIN_ROUTED_SAT * 1000,
+                CAPACITY_MSAT,
+                &settings
+            ),
+            ForwardActivity::Moderate
+        );
+        // a large share of the capacity in a single payment is not strong
         assert_eq!(
-            forward_activity(1, MIN_ROUTED_24H_SAT * 1000),
-            ForwardActivity::MeetsMinimum
+            forward_activity(1, CAPACITY_MSAT / 2, CAPACITY_MSAT, &settings),
+            ForwardActivity::Moderate
         );
     }
 
+    #[test]
+    fn large_share_over_several_payments_is_strong_activity() {
+        let settings = FeeSettings::default();
+        assert_eq!(
+            forward_activity(
+                STRONG_DISTINCT_PAYMENTS,
+                CAPACITY_MSAT / 20,
+                CAPACITY_MSAT,
+                &settings
+            ),
+            ForwardActivity::Strong
+        );
+        assert_eq!(
+            forward_activity(
+                STRONG_DISTINCT_PAYMENTS,
+                CAPACITY_MSAT / 20 - 1,
+                CAPACITY_MSAT,
+                &settings
+            ),
+            ForwardActivity::Moderate
+        );
+    }
+
+    #[test]
+    fn parts_of_one_payment_count_once() {
+        let parts: Vec<_> = (0..4)
+            .map(|_| {
+                let mut forward = settled_forward(CAPACITY_MSAT / 50);
+                forward.payment_hash = Some("hash".to_string());
+                forward
+            })
+            .collect();
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &parts, 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.inputs.settled_forwards, 4);
+        assert_eq!(plan.inputs.distinct_payments, 1);
+        assert_eq!(plan.inputs.activity, ForwardActivity::Moderate);
+        assert_eq!(plan.proposed.fee_ppm, 105);
+    }
+
     #[test]
     fn idle_policy_depends_on_channel_state() {
         assert_eq!(
-            adjusted_ppm(100, FeeState::Bootstrap, ForwardActivity::None),
+            adjusted_ppm(
+                100,
+                FeeState::Bootstrap,
+                ForwardActivity::None,
+                &FeeSettings::default()
+            ),
             85
         );
         assert_eq!(
-            adjusted_ppm(100, FeeState::Normal, ForwardActivity::None),
+            adjusted_ppm(
+                100,
+                FeeState::Normal,
+                ForwardActivity::None,
+                &FeeSettings::default()
+            ),
             98
         );
         assert_eq!(
-            adjusted_ppm(100, FeeState::Depleted, ForwardActivity::None),
+            adjusted_ppm(
+                100,
+                FeeState::Depleted,
+                ForwardActivity::None,
+                &FeeSettings::default()
+            ),
             101
         );
     }
@@ -365,15 +1353,456 @@ mod tests {
     #[test]
     fn adjusted_ppm_respects_bounds() {
         assert_eq!(
-            adjusted_ppm(PPM_MIN, FeeState::Bootstrap, ForwardActivity::None),
+            adjusted_ppm(
+                PPM_MIN,
+                FeeState::Bootstrap,
+                ForwardActivity::None,
+                &FeeSettings::default()
+            ),
             PPM_MIN
         );
         assert_eq!(
-            adjusted_ppm(PPM_MAX, FeeState::Depleted, ForwardActivity::None),
+            adjusted_ppm(
+                PPM_MAX,
+                FeeState::Depleted,
+                ForwardActivity::None,
+                &FeeSettings::default()
+            ),
             PPM_MAX
         );
     }
 
+    #[test]
+    fn plan_disables_unavailable_peers() {
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.5),
+            None,
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Dis);
+        assert!(plan.needs_update());
+        assert_eq!(plan.proposed.fee_ppm, 100);
+        assert_eq!(
+            (plan.proposed.htlc_min_msat, plan.proposed.htlc_max_msat),
+            (1, 1)
+        );
+
+        // the next run finds the channel disabled and leaves it alone
+        let disabled = Channel {
+            htlc_minimum_msat: 1,
+            htlc_maximum_msat: 1,
+            ..channel(100)
+        };
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &disabled,
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.5),
+            Some(AvailabilityState::Disabled),
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Dis);
+        assert!(!plan.needs_update());
+    }
+
+    #[test]
+    fn degraded_peers_get_a_proportional_htlc_max() {
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.85),
+            Some(AvailabilityState::Healthy),
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.inputs.availability_state, AvailabilityState::Degraded);
+        assert_eq!(plan.action, FeeAction::Dec);
+        // half of the 1M sat balance, rounded down to a power of two
+        assert_eq!(plan.proposed.htlc_max_msat, 268_435_456);
+
+        // a disabled peer back at 81% stays disabled
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.81),
+            Some(AvailabilityState::Disabled),
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Dis);
+    }
+
+    #[test]
+    fn plan_records_inputs_and_proposed_fees() {
+        let forwards = [settled_forward(MIN_ROUTED_SAT * 1000)];
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &forwards, 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.99),
+            None,
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Inc);
+        assert_eq!(plan.inputs.state, FeeState::Normal);
+        assert_eq!(plan.inputs.activity, ForwardActivity::Light);
+        assert_eq!(plan.inputs.settled_forwards, 1);
+        assert_eq!(plan.proposed.fee_ppm, 102);
+        assert_eq!(plan.proposed.htlc_max_msat, 536_870_912);
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["action"], "INC");
+        assert_eq!(json["inputs"]["state"], "normal");
+        assert_eq!(json["inputs"]["activity"], "light");
+        let parsed: ChannelFeePlan = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.proposed, plan.proposed);
+    }
+
+    #[test]
+    fn failures_without_settlements_count_over_the_first_window() {
+        let failed = |resolved_time: f64| Forward {
+            status: "failed".to_string(),
+            resolved_time: Some(resolved_time),
+            ..settled_forward(MIN_ROUTED_SAT * 1000)
+        };
+        let forwards = [failed(100.0), failed(2_000.0)];
+        let evidence = || ForwardEvidence::new("1x1x1", &forwards, 0.0, 0);
+        let failed_forwards = |evidence| {
+            plan_channel(
+                &fund(1_000_000_000),
+                &channel(100),
+                "alias",
+                evidence,
+                None,
+                None,
+                &FeePolicy::default().for_channel("peer", "1x1x1"),
+            )
+            .inputs
+            .failed_forwards
+        };
+        assert_eq!(failed_forwards(evidence()), 2);
+        assert_eq!(
+            failed_forwards(evidence().with_first_window_start(1_000.0)),
+            1
+        );
+
+        // with a settlement every failure since the watermark counts
+        let mut forwards = forwards.to_vec();
+        forwards.push(settled_forward(MIN_ROUTED_SAT * 1000));
+        let evidence = ForwardEvidence::new("1x1x1", &forwards, 0.0, 0);
+        assert_eq!(
+            failed_forwards(evidence.with_first_window_start(1_000.0)),
+            2
+        );
+    }
+
+    #[test]
+    fn policy_overrides_shape_the_proposed_ppm() {
+        let mut policy = FeePolicy::default();
+        policy.channels.insert(
+            "1x1x1".to_string(),
+            FeeOverride {
+                fixed_ppm: Some(700),
+                ..FeeOverride::default()
+            },
+        );
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Inc);
+        assert_eq!(plan.proposed.fee_ppm, 700);
+
+        policy.channels.insert(
+            "1x1x1".to_string(),
+            FeeOverride {
+                ppm_floor: Some(99),
+                ..FeeOverride::default()
+            },
+        );
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.proposed.fee_ppm, 99);
+    }
+
+    #[test]
+    fn base_fee_override_alone_triggers_an_update() {
+        let mut policy = FeePolicy::default();
+        policy.channels.insert(
+            "1x1x1".to_string(),
+            FeeOverride {
+                base_fee_msat: Some(0),
+                fixed_ppm: Some(100),
+                ..FeeOverride::default()
+            },
+        );
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.action, FeeAction::Equ);
+        assert_eq!(plan.proposed.base_fee_msat, 0);
+        assert!(plan.needs_update());
+    }
+
+    #[test]
+    fn inbound_role_requires_mostly_indirect_fees() {
+        let settings = FeeSettings::default();
+        assert!(inbound_role(200, 1_000, &settings));
+        assert!(!inbound_role(400, 1_000, &settings));
+        assert!(!inbound_role(
+            0,
+            INBOUND_ROLE_MIN_INDIRECT_SAT - 1,
+            &settings
+        ));
+    }
+
+    #[test]
+    fn inbound_role_channels_get_the_inbound_discount() {
+        let mut policy = FeePolicy::default();
+        policy.defaults.inbound_discount_ppm = 40;
+        let evidence = || {
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000)
+                .with_earned_fees(100, 5_000)
+        };
+        let mut plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            evidence(),
+            None,
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert!(plan.inputs.inbound_role);
+        assert_eq!(plan.inbound_discount_ppm, Some(40));
+        assert!(plan.needs_inbound_update());
+        plan.current_inbound_discount_ppm = Some(40);
+        assert!(!plan.needs_inbound_update());
+
+        // without the role a discount set earlier is removed
+        let mut plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.inbound_discount_ppm, Some(0));
+        assert!(!plan.needs_inbound_update());
+        plan.current_inbound_discount_ppm = Some(40);
+        assert!(plan.needs_inbound_update());
+
+        // disabled channels keep their discount
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            evidence(),
+            Some(0.5),
+            None,
+            &policy.for_channel("peer", "1x1x1"),
+        );
+        assert_eq!(plan.inbound_discount_ppm, None);
+    }
+
+    #[test]
+    fn forwards_count_once_after_the_watermark() {
+        let forwards: Vec<_> = [10.0, 20.0, 30.0]
+            .into_iter()
+            .map(|resolved_time| {
+                let mut forward = settled_forward(MIN_ROUTED_SAT * 1000);
+                forward.resolved_time = Some(resolved_time);
+                forward
+            })
+            .collect();
+        let policy = FeePolicy::default().for_channel("peer", "1x1x1");
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &forwards, 20.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy,
+        );
+        assert_eq!(plan.inputs.settled_forwards, 1);
+        assert_eq!(plan.inputs.forwards_since, 20.0);
+        assert_eq!(plan.watermark, Some(30.0));
+
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &forwards, 30.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            None,
+            None,
+            &policy,
+        );
+        assert_eq!(plan.inputs.activity, ForwardActivity::None);
+        assert_eq!(plan.watermark, None);
+    }
+
+    #[test]
+    fn fee_changes_round_trip_through_the_datastore_log() {
+        let forwards = [settled_forward(MIN_ROUTED_SAT * 1000)];
+        let plan = plan_channel(
+            &fund(1_000_000_000),
+            &channel(100),
+            "alias",
+            ForwardEvidence::new("1x1x1", &forwards, 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+            Some(0.99),
+            None,
+            &FeePolicy::default().for_channel("peer", "1x1x1"),
+        );
+        let changed_at = DateTime::parse_from_rfc3339("2026-01-01T00:01:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let change = plan.fee_change(changed_at);
+        assert_eq!((change.old_fee_ppm, change.new_fee_ppm), (100, 102));
+        assert_eq!(change.changed_at, "2026-01-01T00:01:00Z");
+        assert_eq!(change.routed_since_watermark_msat, MIN_ROUTED_SAT * 1000);
+
+        let line = serde_json::to_string(&change).unwrap();
+        let log = format!("{line}\nnot json\n{line}\n");
+        assert_eq!(parse_fee_changes("1x1x1", &log), [change.clone(), change]);
+    }
+
+    #[test]
+    fn plans_of_other_nodes_or_stale_plans_are_rejected() {
+        let plan = FeePlan {
+            node_id: "node".to_string(),
+            generated_at: "2026-01-01T00:00:00Z".to_string(),
+            channels: Vec::new(),
+        };
+        let generated_at = DateTime::parse_from_rfc3339(&plan.generated_at)
+            .unwrap()
+            .with_timezone(&Utc);
+        let max_age = Duration::hours(24);
+        let now = generated_at + Duration::hours(1);
+        assert!(validate_plan(&plan, "node", now, max_age).is_ok());
+        assert!(validate_plan(&plan, "other", now, max_age).is_err());
+        let later = generated_at + Duration::hours(25);
+        assert!(validate_plan(&plan, "node", later, max_age).is_err());
+    }
+
+    #[test]
+    fn channels_changed_since_the_plan_are_not_applied() {
+        let plan_for = |scid: &str| {
+            let mut plan = plan_channel(
+                &fund(1_000_000_000),
+                &channel(100),
+                "alias",
+                ForwardEvidence::new("1x1x1", &[], 0.0, BOOTSTRAP_EXIT_SETTLED_SAT * 1000),
+                None,
+                None,
+                &FeePolicy::default().for_channel("peer", "1x1x1"),
+            );
+            plan.short_channel_id = scid.to_string();
+            plan
+        };
+        let mut plan = FeePlan {
+            node_id: "node".to_string(),
+            generated_at: "2026-01-01T00:00:00Z".to_string(),
+            channels: ["1x1x1", "2x2x2", "3x3x3", "4x4x4"].map(plan_for).into(),
+        };
+        let current = plan.channels[0].current;
+        let live = HashMap::from([
+            ("1x1x1".to_string(), Some(current)),
+            (
+                "2x2x2".to_string(),
+                Some(ChannelFees {
+                    fee_ppm: 150,
+                    ..current
+                }),
+            ),
+            // a node without local updates in listpeerchannels
+            ("3x3x3".to_string(), None),
+        ]);
+        drop_changed_channels(&mut plan, &live);
+        let kept: Vec<_> = plan
+            .channels
+            .iter()
+            .map(|channel| channel.short_channel_id.as_str())
+            .collect();
+        assert_eq!(kept, ["1x1x1", "3x3x3"]);
+    }
+
+    fn fund(our_amount_msat: u64) -> Fund {
+        Fund {
+            peer_id: "peer".to_string(),
+            connected: true,
+            state: "CHANNELD_NORMAL".to_string(),
+            channel_id: "channel-id".to_string(),
+            short_channel_id: Some("1x1x1".to_string()),
+            our_amount_msat,
+            amount_msat: CAPACITY_MSAT,
+            funding_txid: "funding-txid".to_string(),
+            funding_output: 0,
+        }
+    }
+
+    fn channel(fee_per_millionth: u64) -> Channel {
+        Channel {
+            source: "local".to_string(),
+            destination: "peer".to_string(),
+            short_channel_id: "1x1x1".to_string(),
+            amount_msat: 2_000_000_000,
+            last_update: 0,
+            base_fee_millisatoshi: FEE_BASE,
+            fee_per_millionth,
+            delay: 34,
+            htlc_minimum_msat: MIN_HTLC,
+            htlc_maximum_msat: 536_870_912,
+            active: Some(true),
+        }
+    }
+
+    fn settled_forward(out_msat: u64) -> Forward {
+        Forward {
+            created_index: None,
+            updated_index: None,
+            in_channel: "2x2x2".to_string(),
+            out_channel: Some("1x1x1".to_string()),
+            fee_msat: Some(1),
+            in_msat: out_msat + 1,
+            out_msat: Some(out_msat),
+            status: "settled".to_string(),
+            received_time: 0.0,
+            resolved_time: Some(1.0),
+            failreason: None,
+            failcode: None,
+            payment_hash: None,
+        }
+    }
+
     #[test]
     fn test_largest_power_of_two_leq() {
         // Test edge cases
@@ -0,0 +1,94 @@
+// Advisory `flock` locks between lightdash processes sharing a directory.
+//
+// The kernel releases the lock when its file is closed, also when the holder
+// crashes, so a lock is never left behind. The lock file itself is kept: it
+// only carries the pid of the last holder for diagnostics.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// An exclusive lock, held until dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Wait for the exclusive lock on `path`, creating the file.
+    pub fn exclusive(path: &Path) -> Result<Self, String> {
+        let file = open(path)?;
+        flock(&file, libc::LOCK_EX)
+            .map_err(|e| format!("locking `{}` failed: {e}", path.display()))?;
+        Self::held(file, path)
+    }
+
+    /// Take the exclusive lock on `path` if no one holds it, creating the
+    /// file. `None` when another process holds it.
+    pub fn try_exclusive(path: &Path) -> Result<Option<Self>, String> {
+        let file = open(path)?;
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Self::held(file, path).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(format!("locking `{}` failed: {e}", path.display())),
+        }
+    }
+
+    fn held(mut file: File, path: &Path) -> Result<Self, String> {
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| format!("writing lock `{}` failed: {e}", path.display()))?;
+        Ok(FileLock { _file: file })
+    }
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("opening lock `{}` failed: {e}", path.display()))
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    // SAFETY: the descriptor is owned by `file` and stays open for the call.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn a_second_holder_waits_for_the_first() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("lightdash-lock-{nanos}"));
+        let lock = FileLock::exclusive(&path).unwrap();
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (path, acquired) = (path.clone(), acquired.clone());
+            std::thread::spawn(move || {
+                let _lock = FileLock::exclusive(&path).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(lock);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+        std::fs::remove_file(path).unwrap();
+    }
+}
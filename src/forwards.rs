@@ -0,0 +1,649 @@
+//! Incremental `listforwards` fetching and the local forward archive.
+//!
+//! Forwards are paged by `created_index`, and forwards that changed since the
+//! previous run are paged by `updated_index`. Every fetched forward is appended
+//! to a gzip JSONL archive, so history survives Core Lightning pruning forwards
+//! with `autoclean` and a run only transfers what is new or updated. Runs
+//! syncing the same archive at once, like the `fees` and `sling` cron jobs,
+//! take turns on an exclusive lock of its directory.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::{self, Forward, ForwardIndex, ListForwards};
+use crate::error::LightdashError;
+use crate::file_lock::FileLock;
+use crate::replay;
+
+pub(crate) const DEFAULT_FORWARDS_DIRECTORY: &str = "/var/lib/lightdash/forwards";
+const ARCHIVE_FILE: &str = "forwards.jsonl.gz";
+const STATE_FILE: &str = "forwards-state.json";
+const LOCK_FILE: &str = "forwards.lock";
+const ARCHIVE_SCHEMA_VERSION: u32 = 1;
+const PAGE_LIMIT: u64 = 10_000;
+
+static FORWARDS_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn configure_directory(directory: String) -> Result<(), String> {
+    if directory.is_empty() {
+        return Err("forwards directory cannot be empty".to_string());
+    }
+    FORWARDS_DIRECTORY
+        .set(PathBuf::from(directory))
+        .map_err(|_| "forwards directory was already configured".to_string())
+}
+
+/// Progress of the archive, written after every successful append.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ArchiveState {
+    schema_version: u32,
+    node_id: String,
+    /// Highest `created_index` archived so far.
+    created_index: u64,
+    /// Highest `updated_index` archived so far.
+    updated_index: u64,
+    /// Length of the archive file after the last complete append. Anything
+    /// beyond it is a partial write from an interrupted run, since appends
+    /// only happen under the archive lock.
+    archive_bytes: u64,
+}
+
+#[derive(Debug)]
+struct ForwardArchive {
+    directory: PathBuf,
+    state: ArchiveState,
+    /// Archived forwards by `created_index`; later lines replace earlier ones.
+    forwards: BTreeMap<u64, Forward>,
+}
+
+impl ForwardArchive {
+    fn archive_path(&self) -> PathBuf {
+        self.directory.join(ARCHIVE_FILE)
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.directory.join(STATE_FILE)
+    }
+}
+
+/// Fetch all forwards of `node_id`: the archived ones plus those new or updated
+/// on the node, which are appended to the archive.
+///
+/// Falls back to a full `listforwards` merged over the archive when the node
+/// does not support pagination.
+pub fn list_forwards(node_id: &str) -> Result<ListForwards, LightdashError> {
+    if cmd::using_test_data() || replay::active() {
+        return cmd::list_forwards();
+    }
+
+    let directory = configured_directory();
+    // Held until the append is recorded, so no other sync sees it half written
+    let _lock = match lock_archive(&directory) {
+        Ok(lock) => lock,
+        Err(e) => {
+            log::warn!("Forward archive is unavailable, using live forwards only: {e}");
+            return cmd::list_forwards();
+        }
+    };
+    let mut archive = match open_archive(&directory, node_id) {
+        Ok(archive) => archive,
+        Err(e) => {
+            log::warn!("Forward archive is unavailable, using live forwards only: {e}");
+            return cmd::list_forwards();
+        }
+    };
+    let archived = archive.forwards.len();
+
+    let changed = match sync_forwards(&mut archive, PAGE_LIMIT, cmd::list_forwards_page, || {
+        cmd::current_forwards_index(ForwardIndex::Updated)
+    }) {
+        Ok(changed) => changed,
+        Err(e) => {
+            log::warn!("Paginated listforwards failed, merging all live forwards: {e}");
+            let live = cmd::list_forwards()?;
+            return Ok(ListForwards {
+                forwards: merge_live(archive.forwards, live.forwards),
+            });
+        }
+    };
+    log::info!(
+        "Fetched {} new or updated forwards, {} were archived",
+        changed.len(),
+        archived
+    );
+    if !changed.is_empty() {
+        if let Err(e) = append_to_archive(&mut archive, &changed) {
+            log::warn!("Appending to the forward archive failed: {e}");
+        }
+    }
+
+    Ok(ListForwards {
+        forwards: archive.forwards.into_values().collect(),
+    })
+}
+
+/// Fetch forwards created or updated after the archive state and merge them
+/// into `archive`, returning the ones that are new or changed.
+///
+/// A first sync reads every forward in its current state by `created_index`,
+/// so its `updated_index` pass only starts after the index read from
+/// `current_updated_index` before paging.
+fn sync_forwards(
+    archive: &mut ForwardArchive,
+    limit: u64,
+    mut fetch_page: impl FnMut(ForwardIndex, u64, u64) -> Result<Vec<Forward>, LightdashError>,
+    current_updated_index: impl FnOnce() -> Result<u64, LightdashError>,
+) -> Result<Vec<Forward>, LightdashError> {
+    let mut changed = Vec::new();
+    if archive.state.created_index == 0 && archive.state.updated_index == 0 {
+        archive.state.updated_index = current_updated_index()?;
+    }
+
+    for index in [ForwardIndex::Created, ForwardIndex::Updated] {
+        let mut start = match index {
+            ForwardIndex::Created => archive.state.created_index,
+            ForwardIndex::Updated => archive.state.updated_index,
+        } + 1;
+        loop {
+            let page = fetch_page(index, start, limit)?;
+            let page_len = page.len() as u64;
+            for forward in page {
+                let created_index = required_index(&forward, ForwardIndex::Created)?;
+                let index_value = required_index(&forward, index)?;
+                start = start.max(index_value + 1);
+                if archive.forwards.get(&created_index) != Some(&forward) {
+                    archive.forwards.insert(created_index, forward.clone());
+                    changed.push(forward);
+                }
+            }
+            if page_len < limit {
+                break;
+            }
+        }
+        match index {
+            ForwardIndex::Created => archive.state.created_index = start - 1,
+            ForwardIndex::Updated => archive.state.updated_index = start - 1,
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Union of archived and live forwards, the live version winning.
+fn merge_live(mut archived: BTreeMap<u64, Forward>, live: Vec<Forward>) -> Vec<Forward> {
+    let mut unindexed = Vec::new();
+    for forward in live {
+        match forward.created_index {
+            Some(created_index) => {
+                archived.insert(created_index, forward);
+            }
+            None => unindexed.push(forward),
+        }
+    }
+    archived.into_values().chain(unindexed).collect()
+}
+
+fn required_index(forward: &Forward, index: ForwardIndex) -> Result<u64, LightdashError> {
+    let value = match index {
+        ForwardIndex::Created => forward.created_index,
+        ForwardIndex::Updated => forward.updated_index,
+    };
+    value.ok_or_else(|| {
+        LightdashError::transport(
+            "listforwards",
+            format!(
+                "forward without {}_index; Core Lightning 23.11 or newer is required",
+                index.as_str()
+            ),
+        )
+    })
+}
+
+/// Wait for the exclusive lock of the archive in `directory`.
+fn lock_archive(directory: &Path) -> Result<FileLock, String> {
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("creating `{}` failed: {e}", directory.display()))?;
+    FileLock::exclusive(&directory.join(LOCK_FILE))
+}
+
+/// Read the archive in `directory`, discarding the tail of an interrupted
+/// append. The caller holds the archive lock.
+fn open_archive(directory: &Path, node_id: &str) -> Result<ForwardArchive, String> {
+    let mut archive = ForwardArchive {
+        directory: directory.to_path_buf(),
+        state: ArchiveState {
+            schema_version: ARCHIVE_SCHEMA_VERSION,
+            node_id: node_id.to_string(),
+            created_index: 0,
+            updated_index: 0,
+            archive_bytes: 0,
+        },
+        forwards: BTreeMap::new(),
+    };
+    let state_path = archive.state_path();
+    let archive_path = archive.archive_path();
+    match fs::read_to_string(&state_path) {
+        Ok(content) => {
+            archive.state = serde_json::from_str(&content)
+                .map_err(|e| format!("parsing `{}` failed: {e}", state_path.display()))?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if archive_path.exists() {
+                return Err(format!(
+                    "`{}` exists without `{}`",
+                    archive_path.display(),
+                    state_path.display()
+                ));
+            }
+            return Ok(archive);
+        }
+        Err(e) => return Err(format!("reading `{}` failed: {e}", state_path.display())),
+    }
+    if archive.state.schema_version != ARCHIVE_SCHEMA_VERSION {
+        return Err(format!(
+            "archive schema {} is not {ARCHIVE_SCHEMA_VERSION}",
+            archive.state.schema_version
+        ));
+    }
+    if archive.state.node_id != node_id {
+        return Err(format!(
+            "archive in `{}` belongs to node {}",
+            directory.display(),
+            archive.state.node_id
+        ));
+    }
+
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&archive_path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && archive.state.archive_bytes == 0 => {
+            return Ok(archive);
+        }
+        Err(e) => return Err(format!("opening `{}` failed: {e}", archive_path.display())),
+    };
+    let length = file
+        .metadata()
+        .map_err(|e| format!("reading `{}` metadata failed: {e}", archive_path.display()))?
+        .len();
+    if length < archive.state.archive_bytes {
+        return Err(format!(
+            "`{}` is shorter than the {} archived bytes",
+            archive_path.display(),
+            archive.state.archive_bytes
+        ));
+    }
+    if length > archive.state.archive_bytes {
+        log::warn!(
+            "Discarding {} bytes of an interrupted append to `{}`",
+            length - archive.state.archive_bytes,
+            archive_path.display()
+        );
+        file.set_len(archive.state.archive_bytes)
+            .map_err(|e| format!("truncating `{}` failed: {e}", archive_path.display()))?;
+    }
+
+    archive.forwards = read_archive_lines(file, &archive_path)?;
+    Ok(archive)
+}
+
+/// Archived forwards of `node_id` in the configured directory, for offline
+/// analysis. Unlike a sync this never modifies the archive: a partial tail
+/// from an interrupted append is ignored rather than truncated.
+pub(crate) fn read_archived_forwards(node_id: &str) -> Result<Vec<Forward>, String> {
+    let directory = configured_directory();
+    let state_path = directory.join(STATE_FILE);
+    let archive_path = directory.join(ARCHIVE_FILE);
+    let content = fs::read_to_string(&state_path)
+        .map_err(|e| format!("reading `{}` failed: {e}", state_path.display()))?;
+    let state: ArchiveState = serde_json::from_str(&content)
+        .map_err(|e| format!("parsing `{}` failed: {e}", state_path.display()))?;
+    if state.schema_version != ARCHIVE_SCHEMA_VERSION || state.node_id != node_id {
+        return Err(format!(
+            "archive in `{}` has schema {} for node {}, expected schema {ARCHIVE_SCHEMA_VERSION} for {node_id}",
+            directory.display(),
+            state.schema_version,
+            state.node_id
+        ));
+    }
+    let file = File::open(&archive_path)
+        .map_err(|e| format!("opening `{}` failed: {e}", archive_path.display()))?;
+    let forwards = read_archive_lines(file.take(state.archive_bytes), &archive_path)?;
+    Ok(forwards.into_values().collect())
+}
+
+fn configured_directory() -> PathBuf {
+    FORWARDS_DIRECTORY
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_FORWARDS_DIRECTORY))
+}
+
+fn read_archive_lines(
+    file: impl Read,
+    archive_path: &Path,
+) -> Result<BTreeMap<u64, Forward>, String> {
+    let mut forwards = BTreeMap::new();
+    let reader = BufReader::new(MultiGzDecoder::new(BufReader::new(file)));
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("reading `{}` failed: {e}", archive_path.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let forward: Forward = serde_json::from_str(&line).map_err(|e| {
+            format!(
+                "parsing line {} of `{}` failed: {e}",
+                number + 1,
+                archive_path.display()
+            )
+        })?;
+        let created_index = forward.created_index.ok_or_else(|| {
+            format!(
+                "line {} of `{}` has no created_index",
+                number + 1,
+                archive_path.display()
+            )
+        })?;
+        forwards.insert(created_index, forward);
+    }
+    Ok(forwards)
+}
+
+/// Append `forwards` as one gzip member and record the new archive length.
+fn append_to_archive(archive: &mut ForwardArchive, forwards: &[Forward]) -> Result<(), String> {
+    fs::create_dir_all(&archive.directory)
+        .map_err(|e| format!("creating `{}` failed: {e}", archive.directory.display()))?;
+    let archive_path = archive.archive_path();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&archive_path)
+        .map_err(|e| format!("opening `{}` failed: {e}", archive_path.display()))?;
+    let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+    for forward in forwards {
+        serde_json::to_writer(&mut writer, forward)
+            .map_err(|e| format!("serializing forward failed: {e}"))?;
+        writer
+            .write_all(b"\n")
+            .map_err(|e| format!("writing `{}` failed: {e}", archive_path.display()))?;
+    }
+    let file = writer
+        .finish()
+        .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+        .map_err(|e| format!("writing `{}` failed: {e}", archive_path.display()))?;
+    file.sync_all()
+        .map_err(|e| format!("syncing `{}` failed: {e}", archive_path.display()))?;
+    archive.state.archive_bytes = file
+        .metadata()
+        .map_err(|e| format!("reading `{}` metadata failed: {e}", archive_path.display()))?
+        .len();
+
+    write_state(archive)
+}
+
+fn write_state(archive: &ForwardArchive) -> Result<(), String> {
+    let state_path = archive.state_path();
+    let temporary = archive
+        .directory
+        .join(format!(".{STATE_FILE}.tmp-{}", std::process::id()));
+    let mut file = File::create(&temporary)
+        .map_err(|e| format!("creating `{}` failed: {e}", temporary.display()))?;
+    serde_json::to_writer_pretty(&mut file, &archive.state)
+        .map_err(|e| format!("serializing `{}` failed: {e}", state_path.display()))?;
+    file.write_all(b"\n")
+        .and_then(|()| file.sync_all())
+        .map_err(|e| format!("writing `{}` failed: {e}", temporary.display()))?;
+    fs::rename(&temporary, &state_path).map_err(|e| {
+        format!(
+            "replacing `{}` with `{}` failed: {e}",
+            state_path.display(),
+            temporary.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{
+        append_to_archive, merge_live, open_archive, sync_forwards, ForwardArchive, ARCHIVE_FILE,
+    };
+    use crate::cmd::{Forward, ForwardIndex};
+    use crate::error::LightdashError;
+
+    const NODE_ID: &str = "node";
+
+    fn forward(created_index: u64, updated_index: Option<u64>, status: &str) -> Forward {
+        Forward {
+            created_index: Some(created_index),
+            updated_index,
+            in_channel: "1x1x1".to_string(),
+            out_channel: Some("2x2x2".to_string()),
+            fee_msat: Some(1_000),
+            in_msat: 1_001_000,
+            out_msat: Some(1_000_000),
+            status: status.to_string(),
+            received_time: created_index as f64,
+            resolved_time: updated_index.map(|index| index as f64),
+            failreason: None,
+            failcode: None,
+            payment_hash: None,
+        }
+    }
+
+    fn empty_archive(directory: &Path) -> ForwardArchive {
+        open_archive(directory, NODE_ID).unwrap()
+    }
+
+    /// Serve pages of `forwards` like `listforwards -k index= start= limit=`.
+    fn pager(
+        forwards: &[Forward],
+        requests: &mut Vec<(ForwardIndex, u64)>,
+        index: ForwardIndex,
+        start: u64,
+        limit: u64,
+    ) -> Vec<Forward> {
+        requests.push((index, start));
+        let key = |forward: &Forward| match index {
+            ForwardIndex::Created => forward.created_index,
+            ForwardIndex::Updated => forward.updated_index,
+        };
+        let mut page: Vec<_> = forwards
+            .iter()
+            .filter(|forward| key(forward).is_some_and(|value| value >= start))
+            .cloned()
+            .collect();
+        page.sort_by_key(key);
+        page.truncate(limit as usize);
+        page
+    }
+
+    /// The `updated_index` of the node, as `wait forwards updated 0` returns it.
+    fn current_updated_index(forwards: &[Forward]) -> Result<u64, LightdashError> {
+        Ok(forwards
+            .iter()
+            .filter_map(|forward| forward.updated_index)
+            .max()
+            .unwrap_or_default())
+    }
+
+    #[test]
+    fn first_sync_pages_through_all_forwards_once() {
+        let node: Vec<_> = (1..=5)
+            .map(|index| forward(index, Some(index + 10), "settled"))
+            .collect();
+        let mut archive = empty_archive(&temporary_test_directory());
+        let mut requests = Vec::new();
+
+        let changed = sync_forwards(
+            &mut archive,
+            2,
+            |index, start, limit| Ok(pager(&node, &mut requests, index, start, limit)),
+            || current_updated_index(&node),
+        )
+        .unwrap();
+
+        assert_eq!(changed, node);
+        assert_eq!(
+            (archive.state.created_index, archive.state.updated_index),
+            (5, 15)
+        );
+        assert_eq!(
+            requests,
+            [
+                (ForwardIndex::Created, 1),
+                (ForwardIndex::Created, 3),
+                (ForwardIndex::Created, 5),
+                (ForwardIndex::Updated, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn later_syncs_append_only_new_and_updated_forwards_and_keep_pruned_ones() {
+        let directory = temporary_test_directory();
+        let mut node = vec![forward(1, Some(3), "settled"), forward(2, None, "offered")];
+        let mut archive = empty_archive(&directory);
+        let changed = sync_forwards(
+            &mut archive,
+            10,
+            |index, start, limit| Ok(pager(&node, &mut Vec::new(), index, start, limit)),
+            || current_updated_index(&node),
+        )
+        .unwrap();
+        append_to_archive(&mut archive, &changed).unwrap();
+
+        // autoclean prunes the settled forward, the offered one settles and a new one arrives
+        node.remove(0);
+        node[0] = forward(2, Some(4), "settled");
+        node.push(forward(3, None, "offered"));
+        let mut archive = open_archive(&directory, NODE_ID).unwrap();
+        let mut requests = Vec::new();
+        let changed = sync_forwards(
+            &mut archive,
+            10,
+            |index, start, limit| Ok(pager(&node, &mut requests, index, start, limit)),
+            || panic!("only a first sync reads the current index"),
+        )
+        .unwrap();
+        append_to_archive(&mut archive, &changed).unwrap();
+
+        assert_eq!(
+            requests,
+            [(ForwardIndex::Created, 3), (ForwardIndex::Updated, 4)]
+        );
+        assert_eq!(
+            changed,
+            [forward(3, None, "offered"), forward(2, Some(4), "settled")]
+        );
+        let reopened = open_archive(&directory, NODE_ID).unwrap();
+        assert_eq!(
+            reopened.forwards.into_values().collect::<Vec<_>>(),
+            [
+                forward(1, Some(3), "settled"),
+                forward(2, Some(4), "settled"),
+                forward(3, None, "offered"),
+            ]
+        );
+        assert_eq!(reopened.state, archive.state);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn interrupted_appends_are_discarded() {
+        let directory = temporary_test_directory();
+        let mut archive = empty_archive(&directory);
+        append_to_archive(&mut archive, &[forward(1, Some(1), "settled")]).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(directory.join(ARCHIVE_FILE))
+            .unwrap()
+            .write_all(b"\x1f\x8b\x08partial")
+            .unwrap();
+
+        let reopened = open_archive(&directory, NODE_ID).unwrap();
+        assert_eq!(reopened.forwards.len(), 1);
+        assert_eq!(
+            fs::metadata(directory.join(ARCHIVE_FILE)).unwrap().len(),
+            archive.state.archive_bytes
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn archives_of_other_nodes_are_rejected() {
+        let directory = temporary_test_directory();
+        let mut archive = empty_archive(&directory);
+        append_to_archive(&mut archive, &[forward(1, Some(1), "settled")]).unwrap();
+
+        assert!(open_archive(&directory, "other-node").is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn forwards_without_indexes_are_rejected() {
+        let mut legacy = forward(1, None, "settled");
+        legacy.created_index = None;
+        let mut archive = empty_archive(&temporary_test_directory());
+
+        assert!(sync_forwards(
+            &mut archive,
+            10,
+            |_, _, _| Ok(vec![legacy.clone()]),
+            || Ok(0)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn live_forwards_replace_archived_ones() {
+        let archived = [
+            (1, forward(1, Some(2), "settled")),
+            (3, forward(3, None, "offered")),
+        ]
+        .into_iter()
+        .collect();
+        let mut unindexed = forward(9, None, "failed");
+        unindexed.created_index = None;
+
+        let merged = merge_live(
+            archived,
+            vec![forward(3, Some(5), "settled"), unindexed.clone()],
+        );
+        assert_eq!(
+            merged,
+            [
+                forward(1, Some(2), "settled"),
+                forward(3, Some(5), "settled"),
+                unindexed,
+            ]
+        );
+    }
+
+    fn temporary_test_directory() -> PathBuf {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!(
+            "lightdash-forwards-test-{}-{nonce}",
+            std::process::id()
+        ))
+    }
+}
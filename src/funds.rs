@@ -82,7 +82,13 @@ pub fn run_funds(dir: &str, output_dir: &str) {
         };
 
         log::info!("Processing funds file: {}", filename);
-        let list_funds = cmd::read_xz_funds(&filename);
+        let list_funds = match cmd::read_xz_funds(&filename) {
+            Ok(list_funds) => list_funds,
+            Err(e) => {
+                log::warn!("Skipping unreadable funds file {filename}: {e}");
+                continue;
+            }
+        };
 
         for fund in list_funds.channels {
             let channel_key = channel_key(&fund);
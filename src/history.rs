@@ -1,18 +1,22 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Component, Path, PathBuf};
-use std::process::Command;
+use std::thread;
 use std::time::UNIX_EPOCH;
 
 use chrono::{DateTime, SecondsFormat, Utc};
-use flate2::read::GzDecoder;
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use lzma_rust2::{XzOptions, XzWriter};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::cmd::{self, Channel, Fund, ListChannels, ListFunds};
+use crate::error::LightdashError;
+use crate::file_lock::FileLock;
 use crate::snapshot_metadata::{DatasetMetadata, FieldMetadata};
 
 const HISTORY_SCHEMA_VERSION: u32 = 2;
@@ -23,6 +27,7 @@ const LIQUIDITY_SCHEMA_FILE: &str = "channel-liquidity-history.schema.json";
 const CHANNEL_FUNDS_FILE: &str = "channel-funds-history.jsonl.gz";
 const CHANNEL_FUNDS_SCHEMA_FILE: &str = "channel-funds-history.schema.json";
 pub(crate) const DEFAULT_PROCESSED_DIRECTORY: &str = "/var/lib/lightdash/history/processed";
+const CAPTURE_LOCK_FILE: &str = ".capture.lock";
 pub(crate) const SNAPSHOT_HISTORY_MANIFEST: &str = "history-manifest.json";
 
 pub(crate) struct ImportedHistory {
@@ -43,6 +48,38 @@ struct HistoryManifest {
     node_id: String,
     source: HistorySource,
     datasets: BTreeMap<String, DatasetMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rebuild_state: Option<RebuildState>,
+}
+
+/// Where the last rebuild stopped, so `history rebuild --incremental` only has
+/// to decode newer archives and can still detect their change points.
+#[derive(Default, Deserialize, Serialize)]
+struct RebuildState {
+    channel_archive_timestamp: Option<u64>,
+    funds_archive_timestamp: Option<u64>,
+    policy: Vec<PolicyState>,
+    liquidity: BTreeMap<String, LiquidityValues>,
+    channel_funds: Option<ChannelFundsValues>,
+    /// Length of every dataset file after the rebuild, by file name. An
+    /// incremental rebuild appends a gzip member there, discarding the tail
+    /// of an interrupted one.
+    #[serde(default)]
+    dataset_bytes: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PolicyState {
+    short_channel_id: String,
+    source_node_id: String,
+    #[serde(flatten)]
+    values: PolicyValues,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct ChannelFundsValues {
+    channel_funds_msat: u64,
+    normal_channel_count: usize,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -52,7 +89,7 @@ struct HistorySource {
     funds_archive_count: usize,
 }
 
-#[derive(Clone, PartialEq, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 struct PolicyValues {
     active: Option<bool>,
     capacity_msat: u64,
@@ -75,7 +112,7 @@ struct PolicyHistoryRecord<'a> {
     values: PolicyValues,
 }
 
-#[derive(Clone, PartialEq, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 struct LiquidityValues {
     peer_id: String,
     connected: bool,
@@ -101,12 +138,17 @@ struct ChannelFundsHistoryRecord {
     normal_channel_count: usize,
 }
 
-pub fn run_rebuild(raw_directory: &str, output_directory: &str) -> Result<(), String> {
-    let node_id = cmd::get_info().id;
+pub fn run_rebuild(
+    raw_directory: &str,
+    output_directory: &str,
+    incremental: bool,
+) -> Result<(), String> {
+    let node_id = cmd::get_info()?.id;
     rebuild_history(
         Path::new(raw_directory),
         Path::new(output_directory),
         &node_id,
+        incremental,
     )
 }
 
@@ -119,6 +161,68 @@ pub fn run_export(directory: &str) -> Result<(), String> {
         .map_err(|e| format!("flushing history export failed: {e}"))
 }
 
+pub fn run_capture(
+    raw_directory: &str,
+    peer_channels: bool,
+    keep: Option<usize>,
+) -> Result<(), String> {
+    let raw_directory = Path::new(raw_directory);
+    let _lock = lock_capture(raw_directory)?;
+    let mut kinds = vec![("channels", "listchannels"), ("funds", "listfunds")];
+    if peer_channels {
+        kinds.push(("peerchannels", "listpeerchannels"));
+    }
+    // Fetch everything before writing so a failed command leaves no partial capture.
+    let mut listings = Vec::new();
+    for (kind, method) in kinds {
+        listings.push((kind, cmd::raw_listing(method)?));
+    }
+    let timestamp = Utc::now().timestamp() as u64;
+    capture_archives(raw_directory, timestamp, &listings, keep)
+}
+
+/// Records of one processed dataset, with the node id of the manifest.
+pub(crate) fn read_processed_dataset<T: DeserializeOwned>(
+    directory: &Path,
+    dataset: &str,
+) -> Result<(String, Vec<T>), String> {
+    let manifest_path = directory.join("manifest.json");
+    let manifest: HistoryManifest = serde_json::from_slice(
+        &fs::read(&manifest_path)
+            .map_err(|e| format!("reading `{}` failed: {e}", manifest_path.display()))?,
+    )
+    .map_err(|e| format!("parsing `{}` failed: {e}", manifest_path.display()))?;
+    if manifest.schema_version != HISTORY_SCHEMA_VERSION {
+        return Err(format!(
+            "unsupported history schema version {}; expected {HISTORY_SCHEMA_VERSION}",
+            manifest.schema_version
+        ));
+    }
+    let metadata = manifest
+        .datasets
+        .get(dataset)
+        .ok_or_else(|| format!("processed history is missing {dataset}"))?;
+    let path = directory.join(&metadata.path);
+    let reader = BufReader::new(MultiGzDecoder::new(BufReader::new(
+        File::open(&path).map_err(|e| format!("opening `{}` failed: {e}", path.display()))?,
+    )));
+    let records = reader
+        .lines()
+        .enumerate()
+        .map(|(number, line)| {
+            let line = line.map_err(|e| format!("reading `{}` failed: {e}", path.display()))?;
+            serde_json::from_str(&line).map_err(|e| {
+                format!(
+                    "parsing line {} of `{}` failed: {e}",
+                    number + 1,
+                    path.display()
+                )
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((manifest.node_id, records))
+}
+
 pub(crate) fn import_for_snapshot(
     snapshot_directory: &Path,
     configured_directory: Option<&str>,
@@ -157,18 +261,21 @@ fn import_directory_for_snapshot(
     let manifest = validate_history_manifest(&manifest_bytes, expected_node_id)?;
 
     for dataset in manifest.datasets.values() {
-        for relative_path in [&dataset.path, &dataset.schema_path] {
-            validate_export_path(relative_path)?;
-            let source = processed_directory.join(relative_path);
-            let destination = snapshot_directory.join(relative_path);
-            fs::copy(&source, &destination).map_err(|e| {
-                format!(
-                    "copying processed history `{}` to `{}` failed: {e}",
-                    source.display(),
-                    destination.display()
-                )
-            })?;
-        }
+        validate_export_path(&dataset.path)?;
+        validate_export_path(&dataset.schema_path)?;
+        let source = processed_directory.join(&dataset.path);
+        let file = File::open(&source)
+            .map_err(|e| format!("opening `{}` failed: {e}", source.display()))?;
+        write_snapshot_dataset(file, &snapshot_directory.join(&dataset.path))?;
+        let source = processed_directory.join(&dataset.schema_path);
+        let destination = snapshot_directory.join(&dataset.schema_path);
+        fs::copy(&source, &destination).map_err(|e| {
+            format!(
+                "copying processed history `{}` to `{}` failed: {e}",
+                source.display(),
+                destination.display()
+            )
+        })?;
     }
     fs::write(
         snapshot_directory.join(SNAPSHOT_HISTORY_MANIFEST),
@@ -201,9 +308,14 @@ fn import_tar_for_snapshot(
             let content = entries.get(relative_path).ok_or_else(|| {
                 format!("history export is missing manifest file `{relative_path}`")
             })?;
-            fs::write(snapshot_directory.join(relative_path), content).map_err(|e| {
-                format!("writing snapshot history file `{relative_path}` failed: {e}")
-            })?;
+            let destination = snapshot_directory.join(relative_path);
+            if relative_path == &dataset.path {
+                write_snapshot_dataset(content.as_slice(), &destination)?;
+            } else {
+                fs::write(destination, content).map_err(|e| {
+                    format!("writing snapshot history file `{relative_path}` failed: {e}")
+                })?;
+            }
         }
     }
     fs::write(
@@ -220,6 +332,21 @@ fn import_tar_for_snapshot(
     })
 }
 
+/// Write a processed dataset into a snapshot as a single gzip member. The
+/// dataset has one member per incremental rebuild, and the browser's
+/// `DecompressionStream` does not read past the first one.
+fn write_snapshot_dataset(content: impl Read, destination: &Path) -> Result<(), String> {
+    let file = File::create(destination)
+        .map_err(|e| format!("creating `{}` failed: {e}", destination.display()))?;
+    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+    io::copy(
+        &mut MultiGzDecoder::new(BufReader::new(content)),
+        &mut encoder,
+    )
+    .and_then(|_| encoder.finish()?.flush())
+    .map_err(|e| format!("writing `{}` failed: {e}", destination.display()))
+}
+
 fn read_channel_funds_history(
     directory: &Path,
     manifest: &HistoryManifest,
@@ -229,9 +356,9 @@ fn read_channel_funds_history(
         .get("channel_funds_history")
         .ok_or_else(|| "processed history is missing channel_funds_history".to_string())?;
     let path = directory.join(&dataset.path);
-    let reader = BufReader::new(GzDecoder::new(
+    let reader = BufReader::new(MultiGzDecoder::new(BufReader::new(
         File::open(&path).map_err(|e| format!("opening `{}` failed: {e}", path.display()))?,
-    ));
+    )));
     reader
         .lines()
         .map(|line| {
@@ -319,6 +446,7 @@ fn rebuild_history(
     raw_directory: &Path,
     output_directory: &Path,
     node_id: &str,
+    incremental: bool,
 ) -> Result<(), String> {
     let channel_archives = archive_files(&raw_directory.join("channels"))?;
     let funds_archives = archive_files(&raw_directory.join("funds"))?;
@@ -330,25 +458,93 @@ fn rebuild_history(
         )
     })?;
 
+    let previous = if incremental {
+        previous_rebuild(raw_directory, output_directory, node_id)
+    } else {
+        None
+    };
+    let (mut state, base_counts, base_source) = match previous {
+        Some(mut manifest) => {
+            let counts = [
+                "channel_policy_history",
+                "channel_liquidity_history",
+                "channel_funds_history",
+            ]
+            .map(|name| manifest.datasets[name].record_count);
+            let state = manifest
+                .rebuild_state
+                .take()
+                .expect("checked by previous_rebuild");
+            (state, Some(counts), Some(manifest.source))
+        }
+        None => (RebuildState::default(), None, None),
+    };
+    let channel_archives = newer_archives(&channel_archives, state.channel_archive_timestamp);
+    let funds_archives = newer_archives(&funds_archives, state.funds_archive_timestamp);
+
+    if base_counts.is_some() && channel_archives.is_empty() && funds_archives.is_empty() {
+        log::info!(
+            "Processed history in {} is already up to date",
+            output_directory.display()
+        );
+        return Ok(());
+    }
     log::info!(
-        "Rebuilding history from {} channel archives and {} funds archives",
+        "{} history from {} channel archives and {} funds archives",
+        if base_counts.is_some() {
+            "Extending"
+        } else {
+            "Rebuilding"
+        },
         channel_archives.len(),
         funds_archives.len()
     );
 
-    let policy_temp = temporary_path(output_directory, POLICY_FILE);
-    let liquidity_temp = temporary_path(output_directory, LIQUIDITY_FILE);
-    let channel_funds_temp = temporary_path(output_directory, CHANNEL_FUNDS_FILE);
-    let policy_count = write_policy_history(&policy_temp, &channel_archives, node_id)?;
-    let (liquidity_count, channel_funds_count) =
-        write_liquidity_history(&liquidity_temp, &channel_funds_temp, &funds_archives)?;
-
-    replace_file(&policy_temp, &output_directory.join(POLICY_FILE))?;
-    replace_file(&liquidity_temp, &output_directory.join(LIQUIDITY_FILE))?;
-    replace_file(
-        &channel_funds_temp,
-        &output_directory.join(CHANNEL_FUNDS_FILE),
+    // A full rebuild writes temporary files renamed over the old ones; an
+    // incremental one appends a gzip member to each dataset in place.
+    let target = |file_name: &str| match base_counts {
+        Some(_) => DatasetTarget {
+            path: output_directory.join(file_name),
+            append_at: Some(state.dataset_bytes[file_name]),
+        },
+        None => DatasetTarget {
+            path: temporary_path(output_directory, file_name),
+            append_at: None,
+        },
+    };
+    let policy_target = target(POLICY_FILE);
+    let liquidity_target = target(LIQUIDITY_FILE);
+    let channel_funds_target = target(CHANNEL_FUNDS_FILE);
+    let policy_count = write_policy_history(&policy_target, channel_archives, node_id, &mut state)?;
+    let (liquidity_count, channel_funds_count) = write_liquidity_history(
+        &liquidity_target,
+        &channel_funds_target,
+        funds_archives,
+        &mut state,
     )?;
+    let [policy_count, liquidity_count, channel_funds_count] = match base_counts {
+        Some([policy, liquidity, channel_funds]) => [
+            policy + policy_count,
+            liquidity + liquidity_count,
+            channel_funds + channel_funds_count,
+        ],
+        None => [policy_count, liquidity_count, channel_funds_count],
+    };
+
+    for (target, file_name) in [
+        (&policy_target, POLICY_FILE),
+        (&liquidity_target, LIQUIDITY_FILE),
+        (&channel_funds_target, CHANNEL_FUNDS_FILE),
+    ] {
+        let path = output_directory.join(file_name);
+        if target.append_at.is_none() {
+            replace_file(&target.path, &path)?;
+        }
+        let length = fs::metadata(&path)
+            .map_err(|e| format!("reading `{}` metadata failed: {e}", path.display()))?
+            .len();
+        state.dataset_bytes.insert(file_name.to_string(), length);
+    }
 
     let datasets = history_dataset_metadata(policy_count, liquidity_count, channel_funds_count);
     write_json_atomic(
@@ -370,16 +566,20 @@ fn rebuild_history(
             .expect("channel funds metadata exists"),
     )?;
 
+    let (base_channel_archives, base_funds_archives) = base_source.map_or((0, 0), |source| {
+        (source.channel_archive_count, source.funds_archive_count)
+    });
     let manifest = HistoryManifest {
         schema_version: HISTORY_SCHEMA_VERSION,
         generated_at: format_datetime(Utc::now()),
         node_id: node_id.to_string(),
         source: HistorySource {
             raw_directory: raw_directory.display().to_string(),
-            channel_archive_count: channel_archives.len(),
-            funds_archive_count: funds_archives.len(),
+            channel_archive_count: base_channel_archives + channel_archives.len(),
+            funds_archive_count: base_funds_archives + funds_archives.len(),
         },
         datasets,
+        rebuild_state: Some(state),
     };
     write_json_atomic(&output_directory.join("manifest.json"), &manifest)?;
 
@@ -390,6 +590,146 @@ fn rebuild_history(
     Ok(())
 }
 
+/// The manifest of a previous rebuild that can be extended, or `None` with
+/// the reason logged when a full rebuild is needed.
+fn previous_rebuild(
+    raw_directory: &Path,
+    output_directory: &Path,
+    node_id: &str,
+) -> Option<HistoryManifest> {
+    let path = output_directory.join("manifest.json");
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::info!(
+                "No previous history manifest at {} ({e}); running a full rebuild",
+                path.display()
+            );
+            return None;
+        }
+    };
+    let manifest = match validate_history_manifest(&bytes, node_id) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            log::info!("Previous history cannot be extended: {e}; running a full rebuild");
+            return None;
+        }
+    };
+    let reason = if manifest.rebuild_state.is_none() {
+        Some("the manifest has no rebuild state".to_string())
+    } else if manifest.source.raw_directory != raw_directory.display().to_string() {
+        Some(format!(
+            "it was built from {}",
+            manifest.source.raw_directory
+        ))
+    } else {
+        let dataset_bytes = &manifest
+            .rebuild_state
+            .as_ref()
+            .expect("checked above")
+            .dataset_bytes;
+        [POLICY_FILE, LIQUIDITY_FILE, CHANNEL_FUNDS_FILE]
+            .into_iter()
+            .find(|file| {
+                let recorded = dataset_bytes.get(*file);
+                let length = fs::metadata(output_directory.join(file)).map(|m| m.len());
+                !manifest
+                    .datasets
+                    .values()
+                    .any(|dataset| dataset.path == *file)
+                    || !matches!((recorded, length), (Some(recorded), Ok(length)) if length >= *recorded)
+            })
+            .map(|file| format!("{file} is missing or shorter than recorded"))
+    };
+    if let Some(reason) = reason {
+        log::info!("Previous history cannot be extended because {reason}; running a full rebuild");
+        return None;
+    }
+    Some(manifest)
+}
+
+/// Archives newer than the last one processed. Archives added behind that
+/// point are not picked up; they need a full rebuild.
+fn newer_archives(archives: &[(u64, PathBuf)], processed: Option<u64>) -> &[(u64, PathBuf)] {
+    let start = processed.map_or(0, |processed| {
+        archives.partition_point(|(timestamp, _)| *timestamp <= processed)
+    });
+    &archives[start..]
+}
+
+/// Lock preventing overlapping captures, for example a slow SSH command
+/// still running when cron starts the next one. A crashed capture releases
+/// it with its process.
+fn lock_capture(raw_directory: &Path) -> Result<FileLock, String> {
+    fs::create_dir_all(raw_directory)
+        .map_err(|e| format!("creating `{}` failed: {e}", raw_directory.display()))?;
+    let path = raw_directory.join(CAPTURE_LOCK_FILE);
+    FileLock::try_exclusive(&path)?.ok_or_else(|| {
+        format!(
+            "another capture holds `{}`, its pid is in the file",
+            path.display()
+        )
+    })
+}
+
+fn capture_archives(
+    raw_directory: &Path,
+    timestamp: u64,
+    listings: &[(&str, Value)],
+    keep: Option<usize>,
+) -> Result<(), String> {
+    for (kind, listing) in listings {
+        let directory = raw_directory.join(kind);
+        fs::create_dir_all(&directory)
+            .map_err(|e| format!("creating `{}` failed: {e}", directory.display()))?;
+        let path = directory.join(format!("{timestamp}.json.xz"));
+        if path.exists() {
+            return Err(format!(
+                "history archive `{}` already exists",
+                path.display()
+            ));
+        }
+        write_xz_json_atomic(&path, listing)?;
+        log::info!("Captured {kind} history archive {}", path.display());
+        if let Some(keep) = keep {
+            prune_archives(&directory, keep)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_xz_json_atomic(path: &Path, value: &Value) -> Result<(), String> {
+    let temporary = temporary_path(
+        path.parent().expect("raw archives have a parent"),
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .expect("raw archive names are UTF-8"),
+    );
+    let file = File::create(&temporary)
+        .map_err(|e| format!("creating `{}` failed: {e}", temporary.display()))?;
+    let mut writer = XzWriter::new(BufWriter::new(file), XzOptions::with_preset(6))
+        .map_err(|e| format!("starting xz stream for `{}` failed: {e}", path.display()))?;
+    serde_json::to_writer(&mut writer, value)
+        .map_err(|e| format!("writing `{}` failed: {e}", temporary.display()))?;
+    let file = writer
+        .finish()
+        .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+        .map_err(|e| format!("finishing `{}` failed: {e}", temporary.display()))?;
+    file.sync_all()
+        .map_err(|e| format!("syncing `{}` failed: {e}", temporary.display()))?;
+    replace_file(&temporary, path)
+}
+
+fn prune_archives(directory: &Path, keep: usize) -> Result<(), String> {
+    let archives = archive_files(directory)?;
+    let excess = archives.len().saturating_sub(keep);
+    for (_, path) in &archives[..excess] {
+        fs::remove_file(path).map_err(|e| format!("removing `{}` failed: {e}", path.display()))?;
+        log::info!("Removed expired history archive {}", path.display());
+    }
+    Ok(())
+}
+
 fn archive_files(directory: &Path) -> Result<Vec<(u64, PathBuf)>, String> {
     let entries = fs::read_dir(directory).map_err(|e| {
         format!(
@@ -425,21 +765,68 @@ fn archive_files(directory: &Path) -> Result<Vec<(u64, PathBuf)>, String> {
     Ok(archives)
 }
 
+/// Where a rebuild writes one dataset.
+struct DatasetTarget {
+    path: PathBuf,
+    /// Length of the existing dataset to append to, `None` to create it.
+    append_at: Option<u64>,
+}
+
+impl DatasetTarget {
+    /// Open the dataset for one new gzip member. Bytes beyond `append_at` are
+    /// the tail of an interrupted rebuild and are discarded.
+    fn open(&self) -> Result<GzEncoder<BufWriter<File>>, String> {
+        let path = &self.path;
+        let file = match self.append_at {
+            None => File::create(path)
+                .map_err(|e| format!("creating `{}` failed: {e}", path.display()))?,
+            Some(length) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("opening `{}` failed: {e}", path.display()))?;
+                let current = file
+                    .metadata()
+                    .map_err(|e| format!("reading `{}` metadata failed: {e}", path.display()))?
+                    .len();
+                if current > length {
+                    log::warn!(
+                        "Discarding {} bytes of an interrupted rebuild from {}",
+                        current - length,
+                        path.display()
+                    );
+                    file.set_len(length)
+                        .map_err(|e| format!("truncating `{}` failed: {e}", path.display()))?;
+                }
+                file
+            }
+        };
+        Ok(GzEncoder::new(BufWriter::new(file), Compression::default()))
+    }
+}
+
 fn write_policy_history(
-    path: &Path,
+    target: &DatasetTarget,
     archives: &[(u64, PathBuf)],
     node_id: &str,
+    state: &mut RebuildState,
 ) -> Result<usize, String> {
-    let file =
-        File::create(path).map_err(|e| format!("creating `{}` failed: {e}", path.display()))?;
-    let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
-    let mut previous: HashMap<(String, String), PolicyValues> = HashMap::new();
+    let path = &target.path;
+    let mut writer = target.open()?;
+    let mut previous: HashMap<(String, String), PolicyValues> = state
+        .policy
+        .drain(..)
+        .map(|policy| {
+            (
+                (policy.short_channel_id, policy.source_node_id),
+                policy.values,
+            )
+        })
+        .collect();
     let mut record_count = 0;
 
-    for (index, (timestamp, archive)) in archives.iter().enumerate() {
-        log_progress("channel", index, archives.len(), archive);
-        let channels: ListChannels = read_xz_json(archive)?;
-        let observed_at = format_timestamp(*timestamp)?;
+    for_each_archive("channel", archives, |timestamp, channels: ListChannels| {
+        let observed_at = format_timestamp(timestamp)?;
         for channel in channels
             .channels
             .iter()
@@ -467,6 +854,22 @@ fn write_policy_history(
             previous.insert(key, values);
             record_count += 1;
         }
+        Ok(())
+    })?;
+
+    state.policy = previous
+        .into_iter()
+        .map(|((short_channel_id, source_node_id), values)| PolicyState {
+            short_channel_id,
+            source_node_id,
+            values,
+        })
+        .collect();
+    state.policy.sort_by(|a, b| {
+        (&a.short_channel_id, &a.source_node_id).cmp(&(&b.short_channel_id, &b.source_node_id))
+    });
+    if let Some((timestamp, _)) = archives.last() {
+        state.channel_archive_timestamp = Some(*timestamp);
     }
 
     let mut writer = writer
@@ -479,26 +882,23 @@ fn write_policy_history(
 }
 
 fn write_liquidity_history(
-    path: &Path,
-    channel_funds_path: &Path,
+    target: &DatasetTarget,
+    channel_funds_target: &DatasetTarget,
     archives: &[(u64, PathBuf)],
+    state: &mut RebuildState,
 ) -> Result<(usize, usize), String> {
-    let file =
-        File::create(path).map_err(|e| format!("creating `{}` failed: {e}", path.display()))?;
-    let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
-    let channel_funds_file = File::create(channel_funds_path)
-        .map_err(|e| format!("creating `{}` failed: {e}", channel_funds_path.display()))?;
-    let mut channel_funds_writer =
-        GzEncoder::new(BufWriter::new(channel_funds_file), Compression::default());
-    let mut previous: HashMap<String, LiquidityValues> = HashMap::new();
-    let mut previous_channel_funds = None;
+    let path = &target.path;
+    let channel_funds_path = &channel_funds_target.path;
+    let mut writer = target.open()?;
+    let mut channel_funds_writer = channel_funds_target.open()?;
+    let mut previous: HashMap<String, LiquidityValues> =
+        std::mem::take(&mut state.liquidity).into_iter().collect();
+    let mut previous_channel_funds = state.channel_funds;
     let mut record_count = 0;
     let mut channel_funds_count = 0;
 
-    for (index, (timestamp, archive)) in archives.iter().enumerate() {
-        log_progress("funds", index, archives.len(), archive);
-        let funds: ListFunds = read_xz_json(archive)?;
-        let observed_at = format_timestamp(*timestamp)?;
+    for_each_archive("funds", archives, |timestamp, funds: ListFunds| {
+        let observed_at = format_timestamp(timestamp)?;
         let normal_channels = funds
             .channels
             .iter()
@@ -508,7 +908,10 @@ fn write_liquidity_history(
             .map(|fund| fund.our_amount_msat)
             .sum();
         let normal_channel_count = normal_channels.count();
-        let current_channel_funds = (channel_funds_msat, normal_channel_count);
+        let current_channel_funds = ChannelFundsValues {
+            channel_funds_msat,
+            normal_channel_count,
+        };
         if previous_channel_funds != Some(current_channel_funds) {
             write_json_line(
                 &mut channel_funds_writer,
@@ -535,6 +938,13 @@ fn write_liquidity_history(
             previous.insert(fund.channel_id.clone(), values);
             record_count += 1;
         }
+        Ok(())
+    })?;
+
+    state.liquidity = previous.into_iter().collect();
+    state.channel_funds = previous_channel_funds;
+    if let Some((timestamp, _)) = archives.last() {
+        state.funds_archive_timestamp = Some(*timestamp);
     }
 
     let mut writer = writer
@@ -580,25 +990,38 @@ fn liquidity_values(fund: &Fund) -> LiquidityValues {
     }
 }
 
-fn read_xz_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
-    let output = Command::new("xzcat")
-        .arg(path)
-        .output()
-        .map_err(|e| format!("executing xzcat for `{}` failed: {e}", path.display()))?;
-    if !output.status.success() {
-        return Err(format!(
-            "xzcat failed for `{}` with status {}: {}",
-            path.display(),
-            output.status,
-            String::from_utf8_lossy(&output.stderr).trim()
-        ));
+/// Decode `archives` on a few worker threads and hand them to `apply` in
+/// timestamp order, so change detection sees the same sequence as a serial
+/// scan while keeping at most one batch of decoded archives in memory.
+fn for_each_archive<T: DeserializeOwned + Send>(
+    kind: &str,
+    archives: &[(u64, PathBuf)],
+    mut apply: impl FnMut(u64, T) -> Result<(), String>,
+) -> Result<(), String> {
+    let batch_size = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut index = 0;
+    for batch in archives.chunks(batch_size) {
+        let decoded: Vec<Result<T, LightdashError>> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|(_, archive)| scope.spawn(move || cmd::read_xz_json(archive)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+        for ((timestamp, archive), value) in batch.iter().zip(decoded) {
+            log_progress(kind, index, archives.len(), archive);
+            apply(*timestamp, value?)?;
+            index += 1;
+        }
     }
-    serde_json::from_slice(&output.stdout).map_err(|e| {
-        format!(
-            "parsing historical archive `{}` failed: {e}",
-            path.display()
-        )
-    })
+    Ok(())
 }
 
 fn write_json_line(writer: &mut impl Write, value: &impl Serialize) -> Result<(), String> {
@@ -912,14 +1335,15 @@ mod tests {
     use std::fs::{self, File};
     use std::io::{Read, Write};
     use std::path::{Path, PathBuf};
-    use std::process::{Command, Stdio};
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use flate2::read::GzDecoder;
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+    use lzma_rust2::{XzOptions, XzWriter};
     use serde_json::Value;
 
     use super::{
-        import_directory_for_snapshot, import_tar_for_snapshot, rebuild_history, write_export,
+        capture_archives, import_directory_for_snapshot, import_tar_for_snapshot, lock_capture,
+        rebuild_history, write_export,
     };
 
     #[test]
@@ -951,7 +1375,7 @@ mod tests {
             r#"{"channels":[],"outputs":[]}"#,
         );
 
-        rebuild_history(&raw, &output, "local").unwrap();
+        rebuild_history(&raw, &output, "local", false).unwrap();
 
         let manifest: Value =
             serde_json::from_slice(&fs::read(output.join("manifest.json")).unwrap()).unwrap();
@@ -1028,26 +1452,173 @@ mod tests {
         fs::remove_dir_all(root).unwrap();
     }
 
-    fn write_xz(path: &Path, content: &str) {
-        let file = File::create(path).unwrap();
-        let mut child = Command::new("xz")
-            .arg("-c")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::from(file))
-            .spawn()
+    #[test]
+    fn incremental_rebuild_appends_to_previous_history() {
+        let root = temporary_test_directory();
+        let raw = root.join("raw");
+        let incremental = root.join("incremental");
+        let full = root.join("full");
+        fs::create_dir_all(raw.join("channels")).unwrap();
+        fs::create_dir_all(raw.join("funds")).unwrap();
+        let write_archives = |timestamp: u64, fee_ppm: u64, balance_msat: u64| {
+            write_xz(
+                &raw.join(format!("channels/{timestamp}.json.xz")),
+                &format!(
+                    r#"{{"channels":[{{"source":"local","destination":"peer","short_channel_id":"1x1x1","amount_msat":2000000,"last_update":{timestamp},"base_fee_millisatoshi":0,"fee_per_millionth":{fee_ppm},"delay":34,"htlc_minimum_msat":0,"htlc_maximum_msat":1900000,"active":true}}]}}"#
+                ),
+            );
+            write_xz(
+                &raw.join(format!("funds/{timestamp}.json.xz")),
+                &format!(
+                    r#"{{"channels":[{{"peer_id":"peer","connected":true,"state":"CHANNELD_NORMAL","channel_id":"full-id","short_channel_id":"1x1x1","our_amount_msat":{balance_msat},"amount_msat":2000000,"funding_txid":"txid","funding_output":0}}],"outputs":[]}}"#
+                ),
+            );
+        };
+
+        write_archives(1700000000, 100, 500_000);
+        write_archives(1700003600, 100, 750_000);
+        // Without a previous manifest this is a full rebuild.
+        rebuild_history(&raw, &incremental, "local", true).unwrap();
+        write_archives(1700007200, 100, 750_000);
+        write_archives(1700010800, 150, 900_000);
+        // The partial gzip member of an interrupted rebuild is discarded.
+        let mut policy = fs::OpenOptions::new()
+            .append(true)
+            .open(incremental.join("channel-policy-history.jsonl.gz"))
             .unwrap();
-        child
-            .stdin
-            .take()
+        policy.write_all(b"\x1f\x8b interrupted").unwrap();
+        drop(policy);
+        rebuild_history(&raw, &incremental, "local", true).unwrap();
+        rebuild_history(&raw, &full, "local", false).unwrap();
+
+        for file in [
+            "channel-policy-history.jsonl.gz",
+            "channel-liquidity-history.jsonl.gz",
+            "channel-funds-history.jsonl.gz",
+        ] {
+            assert_eq!(
+                read_gzip_lines(&incremental.join(file)),
+                read_gzip_lines(&full.join(file)),
+                "{file}"
+            );
+        }
+        let manifest = |directory: &Path| -> Value {
+            serde_json::from_slice(&fs::read(directory.join("manifest.json")).unwrap()).unwrap()
+        };
+        let incremental_manifest = manifest(&incremental);
+        assert_eq!(
+            incremental_manifest["datasets"],
+            manifest(&full)["datasets"]
+        );
+        assert_eq!(
+            incremental_manifest["datasets"]["channel_policy_history"]["record_count"],
+            2
+        );
+        assert_eq!(
+            incremental_manifest["rebuild_state"]["funds_archive_timestamp"],
+            1700010800
+        );
+        assert_eq!(incremental_manifest["source"]["channel_archive_count"], 4);
+
+        // Snapshots hold every record in the first gzip member, which is all
+        // the browser decodes.
+        let local_snapshot = root.join("local-snapshot");
+        let remote_snapshot = root.join("remote-snapshot");
+        fs::create_dir_all(&local_snapshot).unwrap();
+        fs::create_dir_all(&remote_snapshot).unwrap();
+        import_directory_for_snapshot(&incremental, &local_snapshot, "local").unwrap();
+        let mut export = Vec::new();
+        write_export(&incremental, &mut export).unwrap();
+        import_tar_for_snapshot(&export, &remote_snapshot, "local").unwrap();
+        for snapshot in [&local_snapshot, &remote_snapshot] {
+            assert_eq!(
+                read_first_gzip_member_lines(&snapshot.join("channel-liquidity-history.jsonl.gz")),
+                read_gzip_lines(&full.join("channel-liquidity-history.jsonl.gz"))
+            );
+        }
+
+        // A schema change forces a full rebuild instead of appending to stale data.
+        let mut stale = incremental_manifest;
+        stale["schema_version"] = 1.into();
+        fs::write(
+            incremental.join("manifest.json"),
+            serde_json::to_vec(&stale).unwrap(),
+        )
+        .unwrap();
+        rebuild_history(&raw, &incremental, "local", true).unwrap();
+        assert_eq!(manifest(&incremental)["schema_version"], 2);
+        assert_eq!(
+            read_gzip_lines(&incremental.join("channel-liquidity-history.jsonl.gz")).len(),
+            3
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn captures_readable_archives_with_retention() {
+        let root = temporary_test_directory();
+        let channels: Value = serde_json::from_str(
+            r#"{"channels":[{"source":"local","destination":"peer","short_channel_id":"1x1x1","amount_msat":2000000,"last_update":1699999990,"base_fee_millisatoshi":1000,"fee_per_millionth":100,"delay":34,"htlc_minimum_msat":0,"htlc_maximum_msat":1900000,"active":true}]}"#,
+        )
+        .unwrap();
+        let funds: Value = serde_json::from_str(r#"{"channels":[],"outputs":[]}"#).unwrap();
+        let listings = [("channels", channels), ("funds", funds)];
+
+        for timestamp in [1700000000, 1700003600, 1700007200] {
+            capture_archives(&root, timestamp, &listings, Some(2)).unwrap();
+        }
+        assert!(capture_archives(&root, 1700007200, &listings, Some(2)).is_err());
+
+        let mut names: Vec<_> = fs::read_dir(root.join("channels"))
             .unwrap()
-            .write_all(content.as_bytes())
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["1700003600.json.xz", "1700007200.json.xz"]);
+        let channels = crate::cmd::read_xz_channels(
+            root.join("channels/1700007200.json.xz").to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(channels.channels[0].fee_per_millionth, 100);
+        assert!(
+            crate::cmd::read_xz_funds(root.join("funds/1700007200.json.xz").to_str().unwrap())
+                .unwrap()
+                .channels
+                .is_empty()
+        );
+
+        let lock = lock_capture(&root).unwrap();
+        assert!(lock_capture(&root).is_err());
+        drop(lock);
+        // the file left behind, as after a crash, does not block the next one
+        assert!(root.join(".capture.lock").exists());
+        drop(lock_capture(&root).unwrap());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn write_xz(path: &Path, content: &str) {
+        let mut writer =
+            XzWriter::new(File::create(path).unwrap(), XzOptions::with_preset(6)).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn read_first_gzip_member_lines(path: &Path) -> Vec<Value> {
+        let mut content = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut content)
             .unwrap();
-        assert!(child.wait().unwrap().success());
+        content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
     }
 
     fn read_gzip_lines(path: &Path) -> Vec<Value> {
         let mut content = String::new();
-        GzDecoder::new(File::open(path).unwrap())
+        MultiGzDecoder::new(File::open(path).unwrap())
             .read_to_string(&mut content)
             .unwrap();
         content
@@ -10,12 +10,13 @@
 use std::cmp::max;
 
 use crate::cmd::{cmd_result, list_peer_channels, using_test_data};
+use crate::error::LightdashError;
 use crate::fees::largest_power_of_two_leq;
 
-pub fn run_htlc() {
+pub fn run_htlc() -> Result<(), LightdashError> {
     log::info!("Running HTLC max adjustment");
 
-    let channels = list_peer_channels();
+    let channels = list_peer_channels()?;
     log::info!("Found {} channels", channels.channels.len());
 
     let channels_to_adjust: Vec<_> = channels
@@ -43,25 +44,26 @@ pub fn run_htlc() {
             new_htlc_max
         );
 
-        set_channel_htlc_max(scid, new_htlc_max);
+        if let Err(e) = set_channel_htlc_max(scid, new_htlc_max) {
+            log::error!("Adjusting HTLC max for {scid} failed: {e}");
+        }
     }
 
     log::info!("HTLC max adjustment completed");
+    Ok(())
 }
 
-fn set_channel_htlc_max(short_channel_id: &str, htlc_max: u64) {
+fn set_channel_htlc_max(short_channel_id: &str, htlc_max: u64) -> Result<(), LightdashError> {
     if using_test_data() {
         log::debug!("Debug mode: would set htlcmax={htlc_max} for {short_channel_id}");
-        return;
+        return Ok(());
     }
 
     let id_arg = format!("id={short_channel_id}");
     let htlc_max_str = format!("htlcmax={htlc_max}");
     let args = ["setchannel", "-k", &id_arg, &htlc_max_str];
     println!("Executing: `lightning-cli {}`", args.join(" "));
-    let result = cmd_result("lightning-cli", &args);
-    log::debug!(
-        "setchannel result: {}",
-        serde_json::to_string(&result).unwrap()
-    );
+    let result = cmd_result("lightning-cli", &args)?;
+    log::debug!("setchannel result: {result}");
+    Ok(())
 }
@@ -50,7 +50,7 @@ impl LnPlusClient {
 
         // Step 2: Sign the message with our node
         log::info!("Signing message with node...");
-        let sign_response = cmd::signmessage(&response.message);
+        let sign_response = cmd::signmessage(&response.message)?;
         log::info!("Message signed successfully");
 
         Ok(Self {
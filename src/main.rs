@@ -1,21 +1,37 @@
 use clap::{Parser, Subcommand};
 use env_logger::Env;
 use std::io::Write;
+use std::time::Duration;
 
+use crate::fee_policy::FeePolicy;
 use crate::store::Store;
 
+mod availability;
+mod backtest;
 mod channels;
 mod cmd;
 mod common;
 mod dashboard;
 mod dashboard2;
+mod error;
+mod fee_policy;
+mod fee_schedule;
 mod fees;
+mod file_lock;
+mod forwards;
 mod funds;
 mod history;
 mod htlc;
 mod lnplus;
+mod rebalance;
+mod replay;
 mod routes;
+mod rpc;
 mod sling;
+mod sling_budget;
+mod sling_jobs;
+mod sling_review;
+mod sling_sources;
 mod snapshot;
 mod snapshot_metadata;
 mod store;
@@ -27,6 +43,27 @@ struct Cli {
     /// Execute lightning-cli on a remote host through SSH
     #[arg(long, global = true, value_name = "USER@HOST")]
     ssh: Option<String>,
+    /// Call this lightning-rpc socket directly instead of spawning lightning-cli;
+    /// forwarded through SSH when --ssh is used
+    #[arg(long, global = true, value_name = "PATH")]
+    rpc_file: Option<String>,
+    /// Seconds without data from the JSON-RPC socket before a call fails
+    #[arg(long, global = true, default_value = "300")]
+    rpc_timeout: u64,
+    /// Directory caching already fetched forwards between runs
+    #[arg(long, global = true, default_value = forwards::DEFAULT_FORWARDS_DIRECTORY)]
+    forwards_directory: String,
+    /// Serve lightning-cli calls from a directory written by `lightdash record`
+    #[arg(long, global = true, value_name = "DIR")]
+    replay: Option<String>,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Command run by `lightdash record`
+#[derive(Parser)]
+#[command(name = "lightdash record DIRECTORY")]
+struct RecordedCli {
     #[command(subcommand)]
     command: Commands,
 }
@@ -74,7 +111,7 @@ enum Commands {
         #[arg(long)]
         without_routes: bool,
     },
-    /// Process raw listchannels and listfunds archives into normalized history datasets
+    /// Capture raw listchannels and listfunds archives and process them into history datasets
     History {
         #[command(subcommand)]
         command: HistoryCommands,
@@ -87,12 +124,75 @@ enum Commands {
         command: Option<RoutesCommands>,
     },
     /// Execute sling jobs for rebalancing
-    Sling,
+    #[command(args_conflicts_with_subcommands = true)]
+    Sling {
+        #[command(subcommand)]
+        command: Option<SlingCommands>,
+        /// Days of settled forwards counted as a target's routed demand
+        #[arg(long, default_value_t = sling::DEMAND_ROUTED_DAYS)]
+        demand_routed_days: i64,
+        /// Sats a target must have routed out over --demand-routed-days
+        #[arg(long, default_value_t = sling::DEMAND_MIN_ROUTED_SAT)]
+        demand_min_routed_sat: u64,
+        /// Days of settled forwards counted for --demand-min-forwards
+        #[arg(long, default_value_t = sling::DEMAND_FORWARDS_DAYS)]
+        demand_forwards_days: i64,
+        /// Settled forwards a target must have routed out over --demand-forwards-days
+        #[arg(long, default_value_t = sling::DEMAND_MIN_FORWARDS)]
+        demand_min_forwards: usize,
+        /// Local forwarding failures a target must have had over the last 7 days
+        #[arg(long, default_value_t = sling::DEMAND_MIN_LOCAL_FAILURES)]
+        demand_min_local_failures: usize,
+        /// Days of rebalance fees counted against the spend budget
+        #[arg(long, default_value_t = sling_budget::BUDGET_DAYS)]
+        budget_days: i64,
+        /// Sats all rebalances may spend over --budget-days
+        #[arg(long)]
+        budget_sat: Option<u64>,
+        /// Percentage of the forwarding fees earned over --budget-days all rebalances may spend
+        #[arg(long)]
+        budget_fee_percent: Option<f64>,
+        /// Route every job's candidates with getroutes and skip jobs without a route within maxppm
+        #[arg(long)]
+        simulate: bool,
+    },
     /// Execute fee adjustments
+    #[command(args_conflicts_with_subcommands = true)]
     Fees {
+        #[command(subcommand)]
+        command: Option<FeesCommands>,
         /// Override the availdb path; remote when --ssh is used
-        #[arg(long)]
+        #[arg(long, conflicts_with = "apply")]
         availdb: Option<String>,
+        /// Write the proposed changes to this JSON file without executing them
+        #[arg(long, conflicts_with = "apply")]
+        plan: Option<String>,
+        /// TOML (or .json) fee policy with defaults and per-peer/per-channel overrides
+        #[arg(long, conflicts_with = "apply")]
+        policy: Option<String>,
+        /// Execute a fee plan previously written with --plan
+        #[arg(long)]
+        apply: Option<String>,
+        /// Refuse to apply plans older than this many hours
+        #[arg(long, default_value_t = 24, requires = "apply")]
+        max_plan_age_hours: i64,
+    },
+    /// Pull liquidity into a channel with a circular payment, without Sling
+    Rebalance {
+        /// Short channel id of the channel receiving the liquidity
+        target_scid: String,
+        /// Sats to move
+        #[arg(long)]
+        amount: u64,
+        /// Maximum fee in ppm of the amount
+        #[arg(long)]
+        max_ppm: u64,
+        /// Source channel to drain, repeatable; by default the Sling candidates
+        #[arg(long = "source")]
+        sources: Vec<String>,
+        /// Maximum number of routes tried
+        #[arg(long, default_value_t = rebalance::MAX_ATTEMPTS)]
+        max_attempts: usize,
     },
     /// Display channels information
     Channels {
@@ -120,11 +220,22 @@ enum Commands {
         #[arg(long, default_value = ".")]
         output_dir: String,
     },
+    /// Run a command and record every lightning-cli call it makes for --replay
+    Record {
+        /// Empty directory for the recorded calls
+        directory: String,
+        /// Replace node ids and 32-byte hex ids (txids, channel ids, hashes) with placeholders
+        #[arg(long)]
+        redact: bool,
+        /// Command to record with its arguments, e.g. `fees --availdb PATH`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
 }
 
 #[derive(Subcommand)]
 enum HistoryCommands {
-    /// Rebuild all processed history datasets from the raw archives
+    /// Rebuild the processed history datasets from the raw archives
     Rebuild {
         /// Directory containing channels/ and funds/ raw archive directories
         #[arg(long, default_value = "/var/lib/lightdash/history/raw")]
@@ -132,6 +243,21 @@ enum HistoryCommands {
         /// Directory for normalized processed history datasets
         #[arg(long, default_value = "/var/lib/lightdash/history/processed")]
         output_directory: String,
+        /// Only process archives newer than the previous rebuild
+        #[arg(long)]
+        incremental: bool,
+    },
+    /// Capture listchannels and listfunds into timestamped raw archives
+    Capture {
+        /// Directory receiving channels/ and funds/ raw archive directories
+        #[arg(long, default_value = "/var/lib/lightdash/history/raw")]
+        raw_directory: String,
+        /// Also capture listpeerchannels into peerchannels/
+        #[arg(long)]
+        peer_channels: bool,
+        /// Keep only the newest N archives of each kind
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        keep: Option<u64>,
     },
     /// Stream the processed history manifest and datasets as a tar archive
     Export {
@@ -141,6 +267,41 @@ enum HistoryCommands {
     },
 }
 
+#[derive(Subcommand)]
+enum FeesCommands {
+    /// Replay the fee controller over processed history and archived forwards
+    Backtest {
+        /// Directory containing processed history datasets
+        #[arg(long, default_value = "/var/lib/lightdash/history/processed")]
+        history_directory: String,
+        /// Number of days to simulate, ending at the last policy observation
+        #[arg(long, default_value_t = 90)]
+        days: u32,
+        /// Assumed price elasticity of forwarded volume
+        #[arg(long, default_value_t = 0.5)]
+        elasticity: f64,
+        /// TOML (or .json) fee policy to simulate instead of the defaults
+        #[arg(long)]
+        policy: Option<String>,
+        /// Write per-day trajectories to this JSON file
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SlingCommands {
+    /// Report whether the rebalances of every target paid back
+    Review {
+        /// Number of days of rebalances to review
+        #[arg(long, default_value_t = sling_review::REVIEW_DAYS)]
+        days: i64,
+        /// Write the review to this JSON file
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
 #[derive(Subcommand)]
 enum RoutesCommands {
     /// Refresh the durable route-analysis cache
@@ -167,15 +328,55 @@ fn main() {
     if let Err(e) = cmd::configure_ssh(cli.ssh) {
         error_panic!("configuring SSH command mode failed: {e}");
     }
+    if let Err(e) = forwards::configure_directory(cli.forwards_directory) {
+        error_panic!("configuring the forwards directory failed: {e}");
+    }
+    if let Some(directory) = &cli.replay {
+        if let Err(e) = replay::configure_replay(directory) {
+            error_panic!("loading recordings from `{directory}` failed: {e}");
+        }
+    }
+    let _rpc_tunnel = cmd::configure_rpc(cli.rpc_file, Duration::from_secs(cli.rpc_timeout))
+        .unwrap_or_else(|e| {
+            error_panic!("configuring the JSON-RPC socket failed: {e}");
+        });
 
-    match cli.command {
+    let command = match cli.command {
+        Commands::Record {
+            directory,
+            redact,
+            command,
+        } => {
+            if cli.replay.is_some() {
+                error_panic!("record cannot be combined with --replay");
+            }
+            let recorded = RecordedCli::try_parse_from(
+                std::iter::once("lightdash".to_string()).chain(command),
+            )
+            .unwrap_or_else(|e| e.exit());
+            if matches!(recorded.command, Commands::Record { .. }) {
+                error_panic!("record cannot record itself");
+            }
+            if let Err(e) = replay::configure_recording(&directory, redact) {
+                error_panic!("preparing recording directory `{directory}` failed: {e}");
+            }
+            log::info!("Recording lightning-cli calls into {directory}");
+            recorded.command
+        }
+        command => command,
+    };
+    run_command(command);
+}
+
+fn run_command(command: Commands) {
+    match command {
         Commands::Dashboard {
             directory,
             min_channels,
             availdb,
             funds_charts_url,
         } => {
-            let store = Store::new(availdb);
+            let store = new_store(availdb);
             log::debug!("Dashboard directory: {}", directory);
             dashboard::run_dashboard(&store, directory, min_channels, funds_charts_url);
         }
@@ -195,7 +396,7 @@ fn main() {
             routes_directory,
             without_routes,
         } => {
-            let store = Store::new(availdb);
+            let store = new_store(availdb);
             if let Err(e) = snapshot::run_snapshot(
                 &store,
                 &directory,
@@ -211,11 +412,23 @@ fn main() {
             HistoryCommands::Rebuild {
                 raw_directory,
                 output_directory,
+                incremental,
             } => {
-                if let Err(e) = history::run_rebuild(&raw_directory, &output_directory) {
+                if let Err(e) = history::run_rebuild(&raw_directory, &output_directory, incremental)
+                {
                     error_panic!("rebuilding historical datasets failed: {e}");
                 }
             }
+            HistoryCommands::Capture {
+                raw_directory,
+                peer_channels,
+                keep,
+            } => {
+                let keep = keep.map(|keep| keep as usize);
+                if let Err(e) = history::run_capture(&raw_directory, peer_channels, keep) {
+                    error_panic!("capturing raw history archives failed: {e}");
+                }
+            }
             HistoryCommands::Export { directory } => {
                 if let Err(e) = history::run_export(&directory) {
                     error_panic!("exporting historical datasets failed: {e}");
@@ -240,19 +453,117 @@ fn main() {
                 let directory = directory.unwrap_or_else(|| {
                     error_panic!("routes requires an output directory or a subcommand");
                 });
-                let store = Store::new(None);
+                let store = new_store(None);
                 routes::run_routes(&store, &directory);
             }
         },
-        Commands::Sling => {
-            let store = Store::new(None);
+        Commands::Sling {
+            command: Some(SlingCommands::Review { days, output }),
+            ..
+        } => {
+            let store = new_store(None);
+            if let Err(e) = sling_review::run_review(&store, days, output.as_deref()) {
+                error_panic!("reviewing sling rebalances failed: {e}");
+            }
+        }
+        Commands::Sling {
+            command: None,
+            demand_routed_days,
+            demand_min_routed_sat,
+            demand_forwards_days,
+            demand_min_forwards,
+            demand_min_local_failures,
+            budget_days,
+            budget_sat,
+            budget_fee_percent,
+            simulate,
+        } => {
+            let store = new_store(None);
+            let demand_settings = sling::DemandSettings {
+                routed_days: demand_routed_days,
+                min_routed_sat: demand_min_routed_sat,
+                forwards_days: demand_forwards_days,
+                min_forwards: demand_min_forwards,
+                min_local_failures: demand_min_local_failures,
+            };
+
+            let budget_settings = sling_budget::BudgetSettings {
+                days: budget_days,
+                max_sat: budget_sat,
+                max_fee_percent: budget_fee_percent,
+            };
 
-            sling::run_sling(&store);
+            if let Err(e) = sling::run_sling(&store, &demand_settings, &budget_settings, simulate) {
+                error_panic!("executing sling jobs failed: {e}");
+            }
         }
-        Commands::Fees { availdb } => {
-            let store = Store::new(availdb);
+        Commands::Fees {
+            command:
+                Some(FeesCommands::Backtest {
+                    history_directory,
+                    days,
+                    elasticity,
+                    policy,
+                    output,
+                }),
+            ..
+        } => {
+            let policy = load_fee_policy(policy);
+            if let Err(e) = backtest::run_backtest(
+                &history_directory,
+                days,
+                elasticity,
+                &policy,
+                output.as_deref(),
+            ) {
+                error_panic!("backtesting fees failed: {e}");
+            }
+        }
+        Commands::Fees {
+            command: None,
+            availdb,
+            plan,
+            policy,
+            apply,
+            max_plan_age_hours,
+        } => {
+            if let Some(path) = apply {
+                let max_age = chrono::Duration::hours(max_plan_age_hours);
+                if let Err(e) = fees::run_fees_apply(&path, max_age) {
+                    error_panic!("applying fee plan failed: {e}");
+                }
+                return;
+            }
+            let policy = load_fee_policy(policy);
+            let store = new_store(availdb);
 
-            fees::run_fees(&store);
+            match plan {
+                Some(path) => {
+                    if let Err(e) = fees::run_fees_plan(&store, &policy, &path) {
+                        error_panic!("writing fee plan failed: {e}");
+                    }
+                }
+                None => fees::run_fees(&store, &policy),
+            }
+        }
+        Commands::Rebalance {
+            target_scid,
+            amount,
+            max_ppm,
+            sources,
+            max_attempts,
+        } => {
+            let store = new_store(None);
+            let request = rebalance::RebalanceRequest {
+                target_scid,
+                amount_sat: amount,
+                max_ppm,
+                sources,
+                max_attempts,
+            };
+            if let Err(e) = rebalance::run_rebalance(&store, &request) {
+                error_panic!("rebalancing failed: {e}");
+            }
         }
         Commands::Channels { path, output_dir } => {
             channels::run_channels(path.as_str(), output_dir.as_str());
@@ -261,14 +572,23 @@ fn main() {
             funds::run_funds(path.as_str(), output_dir.as_str());
         }
         Commands::Htlc => {
-            htlc::run_htlc();
+            if let Err(e) = htlc::run_htlc() {
+                error_panic!("adjusting HTLC max failed: {e}");
+            }
         }
         Commands::LnPlus { output_dir } => {
             lnplus::run_lnplus(&output_dir);
         }
+        Commands::Record { .. } => unreachable!("record is resolved before dispatch"),
     }
 }
 
+fn new_store(availdb: Option<String>) -> Store {
+    Store::new(availdb).unwrap_or_else(|e| {
+        error_panic!("fetching data from the Lightning node failed: {e}");
+    })
+}
+
 fn init_logging() {
     let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
     if let Ok(s) = std::env::var("RUST_LOG_STYLE") {
@@ -301,3 +621,13 @@ macro_rules! error_panic {
     };
 }
 pub(crate) use error_panic;
+
+fn load_fee_policy(path: Option<String>) -> FeePolicy {
+    match path {
+        Some(path) => match FeePolicy::load(&path) {
+            Ok(policy) => policy,
+            Err(e) => error_panic!("loading fee policy failed: {e}"),
+        },
+        None => FeePolicy::default(),
+    }
+}
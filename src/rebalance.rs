@@ -0,0 +1,809 @@
+// Native circular rebalancing, without the Sling plugin.
+//
+// `lightdash rebalance` pays an invoice of our own node along a circle: out
+// through a source channel, over a path found by `getroutes` from the source
+// peer to the target peer, and back in through the target channel. The
+// source peer's fee on the first path channel and the target peer's fee on the
+// target channel are outside the `getroutes` query and computed from gossip.
+//
+// Routes are searched over `GETROUTES_LAYERS` plus a layer of this run that
+// disables our node, so paths cannot shortcut through our other channels, and
+// collects the channels that failed. A failed payment excludes its erring
+// channel and retries, up to `--max-attempts` routes in total. Every attempt
+// is logged and, when executing, appended to the datastore.
+//
+// A payment whose outcome is unknown, because `waitsendpay` timed out or the
+// node could not be reached, may still settle: the run keeps waiting on it
+// until the invoice expires, and otherwise stops without retrying or deleting
+// the invoice, so the same payment hash is never sent twice.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cmd::{self, Fund, GetRoutesOutcome, RouteNode};
+use crate::error::LightdashError;
+use crate::sling::{CANDIDATE_DEPLETE_UP_TO_PERCENT, MIN_CANDIDATE_BALANCE};
+use crate::sling_sources;
+use crate::store::Store;
+
+pub const MAX_ATTEMPTS: usize = 5;
+/// Blocks left to the final HTLC, Core Lightning's default `cltv-final`.
+const FINAL_CLTV: u64 = 18;
+const INVOICE_EXPIRY_SECS: u64 = 3600;
+const WAITSENDPAY_TIMEOUT_SECS: u64 = 120;
+/// `sendpay` error: a part with this payment hash is still in flight.
+const PAY_IN_PROGRESS: i64 = 200;
+/// `waitsendpay` error: the payment did not resolve within the timeout.
+const WAIT_TIMEOUT: i64 = 2000;
+const CMD: &str = "lightning-cli";
+/// Attempts kept in a target's datastore log, the oldest dropped first.
+const ATTEMPTS_KEPT: usize = 500;
+
+/// Options of `lightdash rebalance`.
+#[derive(Clone, Debug)]
+pub struct RebalanceRequest {
+    pub target_scid: String,
+    pub amount_sat: u64,
+    pub max_ppm: u64,
+    /// Empty to pick sources like Sling does.
+    pub sources: Vec<String>,
+    pub max_attempts: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct LocalChannel {
+    scid: String,
+    peer_id: String,
+}
+
+/// Forwarding policy of one channel direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Policy {
+    base_fee_msat: u64,
+    fee_ppm: u64,
+    cltv_delta: u64,
+}
+
+impl Policy {
+    fn of(channel: &cmd::Channel) -> Self {
+        Policy {
+            base_fee_msat: channel.base_fee_millisatoshi,
+            fee_ppm: channel.fee_per_millionth,
+            cltv_delta: channel.delay,
+        }
+    }
+
+    /// Fee for forwarding `amount_msat`, rounded down like Core Lightning.
+    fn fee_msat(&self, amount_msat: u64) -> u64 {
+        self.base_fee_msat + (amount_msat as u128 * self.fee_ppm as u128 / 1_000_000) as u64
+    }
+}
+
+/// One hop of a `sendpay` route.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Hop {
+    id: String,
+    channel: String,
+    direction: u8,
+    amount_msat: u64,
+    delay: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    NoRoute,
+    TooExpensive,
+    /// Route found without `EXECUTE_REBALANCE`.
+    Planned,
+    Failed,
+    /// The payment may still settle.
+    Pending,
+    Succeeded,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Attempt {
+    pub attempted_at: String,
+    pub target_scid: String,
+    pub source_scid: String,
+    pub amount_msat: u64,
+    pub fee_msat: Option<u64>,
+    pub fee_ppm: Option<u64>,
+    /// Channels of the route as `scid/direction`.
+    pub route: Vec<String>,
+    pub outcome: AttemptOutcome,
+    pub erring_channel: Option<String>,
+    pub message: Option<String>,
+}
+
+/// What a failed payment says about the next attempt.
+#[derive(Clone, Debug, PartialEq)]
+enum Failure {
+    /// A channel in the middle failed: exclude it and retry the source.
+    Exclude(String),
+    /// The source channel or its peer failed: try the next source.
+    NextSource,
+    /// The target channel or our node failed: other sources will not help.
+    Stop,
+}
+
+/// How paying one route ended.
+#[derive(Debug)]
+enum Payment {
+    Settled,
+    /// Nothing of the payment is in flight any more.
+    Failed(LightdashError),
+    /// The payment may still settle.
+    Pending(LightdashError),
+}
+
+#[derive(Deserialize)]
+struct InvoiceResponse {
+    payment_hash: String,
+    payment_secret: String,
+}
+
+/// Direction of the channel from `from` to `to`: 0 from the lesser node id.
+fn direction(from: &str, to: &str) -> u8 {
+    u8::from(from > to)
+}
+
+/// Route out through `source`, along `path` from the source peer to the
+/// target peer, and back in through `target`, delivering `amount_msat` to
+/// `our_id`. `first_policy` is the source peer's policy on the first path
+/// channel. None when the path lacks amounts or delays.
+fn circular_route(
+    our_id: &str,
+    source: &LocalChannel,
+    first_policy: Policy,
+    path: &[RouteNode],
+    target: &LocalChannel,
+    amount_msat: u64,
+) -> Option<Vec<Hop>> {
+    let mut middle = Vec::with_capacity(path.len());
+    for node in path {
+        let (channel, direction) = node.short_channel_id_dir.as_deref()?.split_once('/')?;
+        middle.push(Hop {
+            id: node.outgoing_node_id()?.to_string(),
+            channel: channel.to_string(),
+            direction: direction.parse().ok()?,
+            amount_msat: node.channel_amount_msat()?,
+            delay: node.channel_cltv()?,
+        });
+    }
+    let first = middle.first()?;
+    let mut route = vec![Hop {
+        id: source.peer_id.clone(),
+        channel: source.scid.clone(),
+        direction: direction(our_id, &source.peer_id),
+        amount_msat: first.amount_msat + first_policy.fee_msat(first.amount_msat),
+        delay: first.delay + first_policy.cltv_delta,
+    }];
+    route.extend(middle);
+    route.push(Hop {
+        id: our_id.to_string(),
+        channel: target.scid.clone(),
+        direction: direction(&target.peer_id, our_id),
+        amount_msat,
+        delay: FINAL_CLTV,
+    });
+    Some(route)
+}
+
+/// Classify the `data` of a failed `sendpay` or `waitsendpay` along `route`.
+fn classify_failure(data: Option<&Value>, route: &[Hop]) -> Failure {
+    let Some(data) = data else {
+        return Failure::NextSource;
+    };
+    let erring_index = data.get("erring_index").and_then(Value::as_u64);
+    let erring_channel = data.get("erring_channel").and_then(Value::as_str);
+    let erring_direction = data.get("erring_direction").and_then(Value::as_u64);
+    let last = route.len() as u64 - 1;
+    match (erring_index, erring_channel, erring_direction) {
+        (Some(index), ..) if index >= last => Failure::Stop,
+        (_, Some(channel), _) if route.last().is_some_and(|hop| hop.channel == channel) => {
+            Failure::Stop
+        }
+        (Some(0), ..) => Failure::NextSource,
+        (_, Some(channel), _) if route[0].channel == channel => Failure::NextSource,
+        (_, Some(channel), Some(direction)) => Failure::Exclude(format!("{channel}/{direction}")),
+        _ => Failure::NextSource,
+    }
+}
+
+/// Search a circular route out through `source` and in through `target`
+/// whose fees, apart from the source peer's, stay within `max_fee_msat`.
+fn find_route(
+    store: &Store,
+    source: &LocalChannel,
+    target: &LocalChannel,
+    target_policy: Policy,
+    amount_msat: u64,
+    max_fee_msat: u64,
+    layer: &ExclusionLayer,
+) -> Result<Option<Vec<Hop>>, LightdashError> {
+    let target_fee_msat = target_policy.fee_msat(amount_msat);
+    let outcome = cmd::get_routes_with_layers(
+        &source.peer_id,
+        &target.peer_id,
+        amount_msat + target_fee_msat,
+        max_fee_msat.saturating_sub(target_fee_msat),
+        &[&layer.name],
+        FINAL_CLTV + target_policy.cltv_delta,
+    )?;
+    let path = match outcome {
+        GetRoutesOutcome::Found(routes) => routes.routes.into_iter().next().map(|r| r.path),
+        GetRoutesOutcome::TimedOut | GetRoutesOutcome::NotFound => None,
+    };
+    Ok(path.as_deref().and_then(|path| {
+        let first = path.first()?.short_channel_id_dir.as_deref()?;
+        let (first_scid, _) = first.split_once('/')?;
+        let first_policy = Policy::of(store.get_channel(first_scid, &source.peer_id)?);
+        circular_route(
+            &store.info.id,
+            source,
+            first_policy,
+            path,
+            target,
+            amount_msat,
+        )
+    }))
+}
+
+fn local_channel(channels: &[Fund], scid: &str) -> Option<LocalChannel> {
+    channels
+        .iter()
+        .find(|ch| ch.short_channel_id.as_deref() == Some(scid))
+        .map(|ch| LocalChannel {
+            scid: scid.to_string(),
+            peer_id: ch.peer_id.clone(),
+        })
+}
+
+/// Circular route fees of planned rebalances, without paying anything.
+pub struct RouteSimulator<'a> {
+    store: &'a Store,
+    channels: Vec<Fund>,
+    layer: ExclusionLayer,
+}
+
+impl<'a> RouteSimulator<'a> {
+    pub fn new(store: &'a Store) -> Result<Self, LightdashError> {
+        Ok(RouteSimulator {
+            store,
+            channels: store.normal_channels(),
+            layer: ExclusionLayer::create(&store.info.id)?,
+        })
+    }
+
+    /// Fee PPM of the route `getroutes` finds for `amount_sat` out of
+    /// `source_scid` and into `target_scid`, searching up to `max_ppm`. None
+    /// when there is no route.
+    pub fn route_ppm(
+        &self,
+        source_scid: &str,
+        target_scid: &str,
+        amount_sat: u64,
+        max_ppm: u64,
+    ) -> Result<Option<u64>, LightdashError> {
+        let (Some(source), Some(target)) = (
+            local_channel(&self.channels, source_scid),
+            local_channel(&self.channels, target_scid),
+        ) else {
+            return Ok(None);
+        };
+        let Some(target_policy) = self
+            .store
+            .get_channel(&target.scid, &target.peer_id)
+            .map(Policy::of)
+        else {
+            return Ok(None);
+        };
+        let amount_msat = amount_sat * 1000;
+        let route = find_route(
+            self.store,
+            &source,
+            &target,
+            target_policy,
+            amount_msat,
+            amount_msat * max_ppm / 1_000_000,
+            &self.layer,
+        )?;
+        Ok(route.map(|route| (route[0].amount_msat - amount_msat) * 1_000_000 / amount_msat))
+    }
+}
+
+/// The askrene layer of this run.
+struct ExclusionLayer {
+    name: String,
+    /// False with test data, where `getroutes` is a fixture.
+    active: bool,
+}
+
+impl ExclusionLayer {
+    fn create(our_id: &str) -> Result<Self, LightdashError> {
+        let layer = ExclusionLayer {
+            name: format!("lightdash-rebalance-{}", std::process::id()),
+            active: !cmd::using_test_data(),
+        };
+        if layer.active {
+            cmd::cmd_result(CMD, &["askrene-create-layer", &layer.name])?;
+            cmd::cmd_result(CMD, &["askrene-disable-node", &layer.name, our_id])?;
+        }
+        Ok(layer)
+    }
+
+    fn exclude(&self, short_channel_id_dir: &str) -> Result<(), LightdashError> {
+        log::info!("excluding {short_channel_id_dir} from further routes");
+        if self.active {
+            cmd::cmd_result(
+                CMD,
+                &[
+                    "askrene-update-channel",
+                    "-k",
+                    &format!("layer={}", self.name),
+                    &format!("short_channel_id_dir={short_channel_id_dir}"),
+                    "enabled=false",
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExclusionLayer {
+    fn drop(&mut self) {
+        if self.active {
+            if let Err(e) = cmd::cmd_result(CMD, &["askrene-remove-layer", &self.name]) {
+                log::warn!("Removing route layer {} failed: {e}", self.name);
+            }
+        }
+    }
+}
+
+fn record_attempt(attempt: &Attempt) {
+    let line = serde_json::to_string(attempt).expect("rebalance attempt serializes") + "\n";
+    if let Err(e) = cmd::datastore_append_line(
+        &["lightdash", "rebalance_attempts", &attempt.target_scid],
+        &line,
+        ATTEMPTS_KEPT,
+    ) {
+        log::error!(
+            "Failed to record rebalance attempt for {}: {e}",
+            attempt.target_scid
+        );
+    }
+}
+
+fn log_attempt(number: usize, attempt: &Attempt) {
+    log::info!(
+        "attempt:{number} source:{} fee:{} ppm:{} hops:{} result:{:?}{}",
+        attempt.source_scid,
+        attempt
+            .fee_msat
+            .map(|msat| format!("{msat}msat"))
+            .unwrap_or_else(|| "n/a".to_string()),
+        attempt
+            .fee_ppm
+            .map(|ppm| ppm.to_string())
+            .unwrap_or_else(|| "n/a".to_string()),
+        attempt.route.len(),
+        attempt.outcome,
+        attempt
+            .message
+            .as_deref()
+            .map(|message| format!(" message:{message}"))
+            .unwrap_or_default(),
+    );
+    log::debug!("route: {}", attempt.route.join(" "));
+}
+
+/// Rebalance `request.amount_sat` into the target channel. Without
+/// `EXECUTE_REBALANCE`, stops at the first affordable route.
+pub fn run_rebalance(store: &Store, request: &RebalanceRequest) -> Result<(), String> {
+    let execute = std::env::var("EXECUTE_REBALANCE").is_ok();
+    let our_id = store.info.id.as_str();
+    let channels = store.normal_channels();
+    let local = |scid: &str| {
+        local_channel(&channels, scid).ok_or_else(|| format!("{scid} is not a normal channel"))
+    };
+
+    let target = local(&request.target_scid)?;
+    let target_policy = store
+        .get_channel(&target.scid, &target.peer_id)
+        .map(Policy::of)
+        .ok_or_else(|| format!("no gossip for the peer side of {}", target.scid))?;
+    let mut sources: VecDeque<LocalChannel> = if request.sources.is_empty() {
+        let scores = sling_sources::score_sources(
+            store,
+            &channels,
+            MIN_CANDIDATE_BALANCE,
+            CANDIDATE_DEPLETE_UP_TO_PERCENT,
+        );
+        let explore = |scid: &str| sling_sources::is_exploration_day(scid, store.snapshot_time());
+        sling_sources::select_candidates(&scores, request.max_ppm, explore)
+            .iter()
+            .map(|scid| local(scid))
+            .collect::<Result<_, _>>()?
+    } else {
+        request
+            .sources
+            .iter()
+            .map(|scid| local(scid))
+            .collect::<Result<_, _>>()?
+    };
+    sources.retain(|source| source.scid != target.scid);
+    if sources.is_empty() {
+        return Err(format!("no source channel for {}", target.scid));
+    }
+
+    let amount_msat = request.amount_sat * 1000;
+    let max_fee_msat = amount_msat * request.max_ppm / 1_000_000;
+    let target_fee_msat = target_policy.fee_msat(amount_msat);
+    if target_fee_msat > max_fee_msat {
+        return Err(format!(
+            "the target peer alone charges {target_fee_msat}msat, more than {}ppm",
+            request.max_ppm
+        ));
+    }
+    log::info!(
+        "Rebalance target:{} amount:{}sat max_ppm:{} max_fee:{max_fee_msat}msat sources:{} execute:{execute}",
+        target.scid,
+        request.amount_sat,
+        request.max_ppm,
+        sources.len(),
+    );
+
+    let layer = ExclusionLayer::create(our_id).map_err(|e| e.to_string())?;
+    let label = format!(
+        "lightdash-rebalance-{}-{}",
+        target.scid,
+        Utc::now().timestamp()
+    );
+    let mut invoice: Option<InvoiceResponse> = None;
+    let mut attempts = 0;
+    let mut succeeded = false;
+    let mut pending = false;
+    while attempts < request.max_attempts {
+        let Some(source) = sources.front().cloned() else {
+            break;
+        };
+        attempts += 1;
+        let mut attempt = Attempt {
+            attempted_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            target_scid: target.scid.clone(),
+            source_scid: source.scid.clone(),
+            amount_msat,
+            fee_msat: None,
+            fee_ppm: None,
+            route: Vec::new(),
+            outcome: AttemptOutcome::NoRoute,
+            erring_channel: None,
+            message: None,
+        };
+
+        let route = find_route(
+            store,
+            &source,
+            &target,
+            target_policy,
+            amount_msat,
+            max_fee_msat,
+            &layer,
+        )
+        .map_err(|e| e.to_string())?;
+        let Some(route) = route else {
+            log_attempt(attempts, &attempt);
+            if execute {
+                record_attempt(&attempt);
+            }
+            sources.pop_front();
+            continue;
+        };
+
+        let fee_msat = route[0].amount_msat - amount_msat;
+        attempt.fee_msat = Some(fee_msat);
+        attempt.fee_ppm = Some(fee_msat * 1_000_000 / amount_msat);
+        attempt.route = route
+            .iter()
+            .map(|hop| format!("{}/{}", hop.channel, hop.direction))
+            .collect();
+        if fee_msat > max_fee_msat {
+            // Only the source peer's fee was outside the search
+            attempt.outcome = AttemptOutcome::TooExpensive;
+            log_attempt(attempts, &attempt);
+            if execute {
+                record_attempt(&attempt);
+            }
+            layer
+                .exclude(&attempt.route[1])
+                .map_err(|e| e.to_string())?;
+            continue;
+        }
+        if !execute {
+            attempt.outcome = AttemptOutcome::Planned;
+            log_attempt(attempts, &attempt);
+            log::info!("EXECUTE_REBALANCE is not set, not paying the route");
+            break;
+        }
+
+        let invoice = match &invoice {
+            Some(invoice) => invoice,
+            None => invoice.insert(create_invoice(&label, amount_msat)?),
+        };
+        match send_route(&route, invoice) {
+            Payment::Settled => {
+                attempt.outcome = AttemptOutcome::Succeeded;
+                log_attempt(attempts, &attempt);
+                record_attempt(&attempt);
+                succeeded = true;
+                break;
+            }
+            Payment::Pending(e) => {
+                // Another route could pay the invoice twice
+                attempt.outcome = AttemptOutcome::Pending;
+                attempt.message = Some(e.to_string());
+                log_attempt(attempts, &attempt);
+                record_attempt(&attempt);
+                pending = true;
+                break;
+            }
+            Payment::Failed(e) => {
+                attempt.outcome = AttemptOutcome::Failed;
+                attempt.message = Some(e.to_string());
+                let data = match &e {
+                    LightdashError::Rpc { data, .. } => data.as_ref(),
+                    _ => None,
+                };
+                attempt.erring_channel = data
+                    .and_then(|data| data.get("erring_channel"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                log_attempt(attempts, &attempt);
+                record_attempt(&attempt);
+                match classify_failure(data, &route) {
+                    Failure::Exclude(channel) => {
+                        layer.exclude(&channel).map_err(|e| e.to_string())?
+                    }
+                    Failure::NextSource => {
+                        sources.pop_front();
+                    }
+                    Failure::Stop => break,
+                }
+            }
+        }
+    }
+
+    if invoice.is_some() && !succeeded && !pending {
+        if let Err(e) = cmd::cmd_result(
+            CMD,
+            &[
+                "delinvoice",
+                "-k",
+                &format!("label={label}"),
+                "status=unpaid",
+            ],
+        ) {
+            log::warn!("Deleting invoice {label} failed: {e}");
+        }
+    }
+    log::info!(
+        "Rebalance summary: target:{} attempts:{attempts} succeeded:{succeeded} pending:{pending}",
+        target.scid
+    );
+    if pending {
+        return Err(format!(
+            "the payment rebalancing {} is still pending, check `listsendpays` for invoice {label}",
+            target.scid
+        ));
+    }
+    if execute && !succeeded {
+        return Err(format!("rebalancing {} failed", target.scid));
+    }
+    Ok(())
+}
+
+fn create_invoice(label: &str, amount_msat: u64) -> Result<InvoiceResponse, String> {
+    let v = cmd::cmd_result(
+        CMD,
+        &[
+            "invoice",
+            "-k",
+            &format!("amount_msat={amount_msat}"),
+            &format!("label={label}"),
+            "description=lightdash rebalance",
+            &format!("expiry={INVOICE_EXPIRY_SECS}"),
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    serde_json::from_value(v).map_err(|e| format!("parsing invoice response failed: {e}"))
+}
+
+/// Pay the invoice along `route` and wait for the result.
+fn send_route(route: &[Hop], invoice: &InvoiceResponse) -> Payment {
+    let route = serde_json::to_string(route).expect("route serializes");
+    let payment_hash = format!("payment_hash={}", invoice.payment_hash);
+    let sent = cmd::cmd_result(
+        CMD,
+        &[
+            "sendpay",
+            "-k",
+            &format!("route={route}"),
+            &payment_hash,
+            &format!("payment_secret={}", invoice.payment_secret),
+        ],
+    );
+    match sent {
+        Ok(_) => {}
+        // An earlier part is still in flight, its result is ours too
+        Err(e) if e.rpc_code() == Some(PAY_IN_PROGRESS) => {
+            log::warn!("A payment of this invoice is already in flight: {e}");
+        }
+        Err(e @ LightdashError::Rpc { .. }) => return Payment::Failed(e),
+        Err(e) => return Payment::Pending(e),
+    }
+
+    let started = Instant::now();
+    loop {
+        let waited = cmd::cmd_result(
+            CMD,
+            &[
+                "waitsendpay",
+                "-k",
+                &payment_hash,
+                &format!("timeout={WAITSENDPAY_TIMEOUT_SECS}"),
+            ],
+        );
+        match waited {
+            Ok(_) => return Payment::Settled,
+            Err(e)
+                if e.rpc_code() == Some(WAIT_TIMEOUT)
+                    && started.elapsed() < Duration::from_secs(INVOICE_EXPIRY_SECS) =>
+            {
+                log::info!(
+                    "payment still in flight after {}s, waiting",
+                    started.elapsed().as_secs()
+                );
+            }
+            Err(e) => return classify_wait_error(e),
+        }
+    }
+}
+
+/// A `waitsendpay` error other than a timeout reports how the payment
+/// ended; anything else leaves its outcome unknown.
+fn classify_wait_error(error: LightdashError) -> Payment {
+    match error.rpc_code() {
+        Some(code) if code != WAIT_TIMEOUT => Payment::Failed(error),
+        _ => Payment::Pending(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: &str = "02e56c765b7c65f816ec44485d327fe1dec78985c31f5dce6d8293f61e4c06722e";
+    const SOURCE_PEER: &str = "03b2fdf3674e934a13a72f495d471dbe15962edd4d37b9ef3bdf2cd6c09ef9f719";
+    const TARGET_PEER: &str = "037659a0ac8eb3b8d0a720114efc861d3a940382dcfa1403746b4f8f6b2e8810ba";
+
+    fn channel(scid: &str, peer_id: &str) -> LocalChannel {
+        LocalChannel {
+            scid: scid.to_string(),
+            peer_id: peer_id.to_string(),
+        }
+    }
+
+    fn route() -> Vec<Hop> {
+        let path: Vec<RouteNode> = serde_json::from_value(serde_json::json!([
+            {
+                "short_channel_id_dir": "100x1x0/1",
+                "node_id_in": SOURCE_PEER,
+                "node_id_out": "0299",
+                "amount_in_msat": 1_000_200,
+                "amount_out_msat": 1_000_100,
+                "cltv_in": 120,
+                "cltv_out": 80
+            },
+            {
+                "short_channel_id_dir": "200x2x0/0",
+                "next_node_id": TARGET_PEER,
+                "amount_msat": 1_000_100,
+                "delay": 80
+            }
+        ]))
+        .unwrap();
+        let first_policy = Policy {
+            base_fee_msat: 1_000,
+            fee_ppm: 100,
+            cltv_delta: 34,
+        };
+        circular_route(
+            US,
+            &channel("10x1x0", SOURCE_PEER),
+            first_policy,
+            &path,
+            &channel("20x1x0", TARGET_PEER),
+            1_000_000,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn circular_route_adds_the_source_and_target_hops() {
+        let route = route();
+        let hops: Vec<_> = route
+            .iter()
+            .map(|hop| {
+                (
+                    hop.channel.as_str(),
+                    hop.direction,
+                    hop.amount_msat,
+                    hop.delay,
+                )
+            })
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                // the source peer charges 1,000msat + 100ppm to forward 1,000,200msat
+                ("10x1x0", 0, 1_001_300, 154),
+                ("100x1x0", 1, 1_000_200, 120),
+                ("200x2x0", 0, 1_000_100, 80),
+                ("20x1x0", 1, 1_000_000, FINAL_CLTV),
+            ]
+        );
+        assert_eq!(route[0].id, SOURCE_PEER);
+        assert_eq!(route[2].id, TARGET_PEER);
+        assert_eq!(route[3].id, US);
+    }
+
+    #[test]
+    fn failures_exclude_middle_channels_only() {
+        let route = route();
+        let failure = |data: Value| classify_failure(Some(&data), &route);
+        assert_eq!(
+            failure(serde_json::json!({
+                "erring_index": 2, "erring_channel": "200x2x0", "erring_direction": 0
+            })),
+            Failure::Exclude("200x2x0/0".to_string())
+        );
+        assert_eq!(
+            failure(serde_json::json!({"erring_index": 0, "erring_channel": "10x1x0"})),
+            Failure::NextSource
+        );
+        assert_eq!(
+            failure(serde_json::json!({"erring_index": 3})),
+            Failure::Stop
+        );
+        assert_eq!(
+            failure(serde_json::json!({"erring_channel": "20x1x0", "erring_direction": 1})),
+            Failure::Stop
+        );
+        assert_eq!(classify_failure(None, &route), Failure::NextSource);
+    }
+
+    #[test]
+    fn unresolved_payments_stay_pending() {
+        let rpc = |code| LightdashError::Rpc {
+            command: "waitsendpay".to_string(),
+            code,
+            message: "error".to_string(),
+            data: None,
+        };
+        assert!(matches!(classify_wait_error(rpc(204)), Payment::Failed(_)));
+        assert!(matches!(
+            classify_wait_error(rpc(WAIT_TIMEOUT)),
+            Payment::Pending(_)
+        ));
+        assert!(matches!(
+            classify_wait_error(LightdashError::transport("waitsendpay", "socket closed")),
+            Payment::Pending(_)
+        ));
+    }
+}
@@ -0,0 +1,490 @@
+//! Recording of `lightning-cli` calls into a fixture directory and replaying them.
+//!
+//! `lightdash record DIR COMMAND...` writes one `NNNN-method.json` file per call
+//! with its arguments and response. `lightdash --replay DIR COMMAND...` serves
+//! those responses instead of talking to a node, in release builds too. The
+//! Summars availdb read by the command is kept next to them as `availdb.json`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::LightdashError;
+
+static RECORDER: OnceLock<Mutex<Recorder>> = OnceLock::new();
+static REPLAYER: OnceLock<Mutex<Replayer>> = OnceLock::new();
+
+const AVAILDB_FILE: &str = "availdb.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct RecordedCall {
+    args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RecordedError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct RecordedError {
+    code: i64,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+struct Recorder {
+    directory: PathBuf,
+    next_call: usize,
+    redactor: Option<Redactor>,
+}
+
+struct Replayer {
+    directory: PathBuf,
+    calls: HashMap<Vec<String>, VecDeque<RecordedCall>>,
+}
+
+/// Record every `lightning-cli` call into `directory`, which must be empty or missing.
+pub fn configure_recording(directory: &str, redact: bool) -> Result<(), String> {
+    let directory = PathBuf::from(directory);
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("creating `{}` failed: {e}", directory.display()))?;
+    let is_empty = fs::read_dir(&directory)
+        .map_err(|e| format!("reading `{}` failed: {e}", directory.display()))?
+        .next()
+        .is_none();
+    if !is_empty {
+        return Err(format!(
+            "recording directory `{}` is not empty",
+            directory.display()
+        ));
+    }
+    let recorder = Recorder {
+        directory,
+        next_call: 1,
+        redactor: redact.then(Redactor::default),
+    };
+    RECORDER
+        .set(Mutex::new(recorder))
+        .map_err(|_| "recording was already configured".to_string())
+}
+
+/// Serve `lightning-cli` calls from the recordings in `directory`.
+pub fn configure_replay(directory: &str) -> Result<(), String> {
+    let replayer = load_recordings(Path::new(directory))?;
+    log::info!(
+        "Replaying {} recorded calls from {directory}",
+        replayer.calls.values().map(VecDeque::len).sum::<usize>()
+    );
+    REPLAYER
+        .set(Mutex::new(replayer))
+        .map_err(|_| "replay was already configured".to_string())
+}
+
+/// Whether calls are being recorded or replayed, which bypasses bundled test
+/// data and the local forward archive.
+pub fn active() -> bool {
+    RECORDER.get().is_some() || REPLAYER.get().is_some()
+}
+
+/// The recorded response for `args` when replaying.
+///
+/// Calls with identical arguments are served in recording order, the last one
+/// repeating. A call whose arguments were not recorded, such as a datastore
+/// write keyed by the current time, gets a recording of the same method.
+pub(crate) fn replayed_response(args: &[&str]) -> Option<Result<Value, LightdashError>> {
+    let replayer = REPLAYER.get()?;
+    let mut replayer = replayer.lock().expect("replayer lock is not poisoned");
+    let command = args.join(" ");
+    let key: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let key = if replayer.calls.contains_key(&key) {
+        key
+    } else {
+        let wanted = method(args);
+        let Some(fallback) = replayer
+            .calls
+            .keys()
+            .filter(|recorded| {
+                method(&recorded.iter().map(String::as_str).collect::<Vec<_>>()) == wanted
+            })
+            .min()
+            .cloned()
+        else {
+            return Some(Err(LightdashError::transport(
+                command,
+                format!("no recording in `{}`", replayer.directory.display()),
+            )));
+        };
+        log::warn!(
+            "No recording of `{command}`, replaying `{}`",
+            fallback.join(" ")
+        );
+        fallback
+    };
+
+    let queue = replayer
+        .calls
+        .get_mut(&key)
+        .expect("replay key was looked up");
+    let call = if queue.len() > 1 {
+        queue.pop_front().expect("queue is not empty")
+    } else {
+        queue.front().expect("queue is not empty").clone()
+    };
+    Some(match (call.response, call.error) {
+        (_, Some(error)) => Err(LightdashError::Rpc {
+            command,
+            code: error.code,
+            message: error.message,
+            data: error.data,
+        }),
+        (Some(response), None) => Ok(response),
+        (None, None) => Err(LightdashError::transport(
+            command,
+            "recording has neither response nor error",
+        )),
+    })
+}
+
+/// The recorded availdb when replaying.
+pub(crate) fn replayed_availdb() -> Option<Result<Value, LightdashError>> {
+    let replayer = REPLAYER.get()?;
+    let replayer = replayer.lock().expect("replayer lock is not poisoned");
+    let path = replayer.directory.join(AVAILDB_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Some(Err(LightdashError::io(
+                format!("reading availdb `{}`", path.display()),
+                e,
+            )))
+        }
+    };
+    Some(
+        serde_json::from_slice(&content)
+            .map_err(|e| LightdashError::json(format!("availdb `{}`", path.display()), e)),
+    )
+}
+
+/// Write the availdb read by the command when recording. A missing availdb
+/// is not recorded, and is missing on replay too.
+pub(crate) fn record_availdb(result: &Result<Value, LightdashError>) {
+    let (Some(recorder), Ok(availdb)) = (RECORDER.get(), result) else {
+        return;
+    };
+    let mut recorder = recorder.lock().expect("recorder lock is not poisoned");
+    let mut availdb = availdb.clone();
+    if let Some(redactor) = recorder.redactor.as_mut() {
+        redactor.redact_value(&mut availdb);
+    }
+    let path = recorder.directory.join(AVAILDB_FILE);
+    let written = serde_json::to_vec(&availdb)
+        .map_err(|e| e.to_string())
+        .and_then(|content| fs::write(&path, content).map_err(|e| e.to_string()));
+    match written {
+        Ok(()) => log::debug!("Recorded availdb into {}", path.display()),
+        Err(e) => log::error!("Recording into `{}` failed: {e}", path.display()),
+    }
+}
+
+/// Write the outcome of a `lightning-cli` call when recording.
+///
+/// Transport failures are not recorded since they say nothing about the node.
+pub(crate) fn record_response(args: &[&str], result: &Result<Value, LightdashError>) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let mut recorder = recorder.lock().expect("recorder lock is not poisoned");
+    let mut call = RecordedCall {
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        response: None,
+        error: None,
+    };
+    match result {
+        Ok(response) => call.response = Some(response.clone()),
+        Err(LightdashError::Rpc {
+            code,
+            message,
+            data,
+            ..
+        }) => {
+            call.error = Some(RecordedError {
+                code: *code,
+                message: message.clone(),
+                data: data.clone(),
+            })
+        }
+        Err(_) => return,
+    }
+    if let Some(redactor) = recorder.redactor.as_mut() {
+        redactor.redact_call(&mut call);
+    }
+
+    let file_name = format!(
+        "{:04}-{}.json",
+        recorder.next_call,
+        sanitize_file_name(method(args))
+    );
+    recorder.next_call += 1;
+    let path = recorder.directory.join(file_name);
+    let written = serde_json::to_vec_pretty(&call)
+        .map_err(|e| e.to_string())
+        .and_then(|content| fs::write(&path, content).map_err(|e| e.to_string()));
+    match written {
+        Ok(()) => log::debug!("Recorded `{}` into {}", args.join(" "), path.display()),
+        Err(e) => log::error!("Recording into `{}` failed: {e}", path.display()),
+    }
+}
+
+fn load_recordings(directory: &Path) -> Result<Replayer, String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|e| format!("reading `{}` failed: {e}", directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("reading `{}` failed: {e}", directory.display()))?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "json")
+            && path.file_name().is_some_and(|name| name != AVAILDB_FILE)
+    });
+    paths.sort();
+
+    let mut calls: HashMap<Vec<String>, VecDeque<RecordedCall>> = HashMap::new();
+    for path in paths {
+        let content =
+            fs::read(&path).map_err(|e| format!("reading `{}` failed: {e}", path.display()))?;
+        let call: RecordedCall = serde_json::from_slice(&content)
+            .map_err(|e| format!("parsing `{}` failed: {e}", path.display()))?;
+        calls.entry(call.args.clone()).or_default().push_back(call);
+    }
+    Ok(Replayer {
+        directory: directory.to_path_buf(),
+        calls,
+    })
+}
+
+/// The RPC method of `lightning-cli` arguments, skipping the `-k` flag.
+fn method<'a>(args: &[&'a str]) -> &'a str {
+    args.iter()
+        .copied()
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default()
+}
+
+fn sanitize_file_name(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Replaces node ids and other 32-byte hex identifiers (txids, channel ids,
+/// payment hashes) with placeholders that stay consistent within a recording,
+/// so joins between responses keep working.
+#[derive(Default)]
+struct Redactor {
+    node_ids: HashMap<String, String>,
+    hashes: HashMap<String, String>,
+}
+
+impl Redactor {
+    fn redact_call(&mut self, call: &mut RecordedCall) {
+        for arg in call.args.iter_mut() {
+            *arg = self.redact_str(arg);
+        }
+        if let Some(response) = call.response.as_mut() {
+            self.redact_value(response);
+        }
+        if let Some(error) = call.error.as_mut() {
+            error.message = self.redact_str(&error.message);
+            if let Some(data) = error.data.as_mut() {
+                self.redact_value(data);
+            }
+        }
+    }
+
+    fn redact_value(&mut self, value: &mut Value) {
+        match value {
+            Value::String(string) => *string = self.redact_str(string),
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_value(value)),
+            // the availdb is keyed by node id
+            Value::Object(object) => {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.redact_value(&mut value);
+                        (self.redact_str(&key), value)
+                    })
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+
+    fn redact_str(&mut self, value: &str) -> String {
+        let mut redacted = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_hexdigit()) {
+            redacted.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(rest.len());
+            redacted.push_str(&self.redact_hex(&rest[..end]));
+            rest = &rest[end..];
+        }
+        redacted.push_str(rest);
+        redacted
+    }
+
+    fn redact_hex(&mut self, hex: &str) -> String {
+        let is_node_id = hex.len() == 66 && (hex.starts_with("02") || hex.starts_with("03"));
+        if is_node_id {
+            let next = self.node_ids.len() + 1;
+            let prefix = &hex[..2];
+            return self
+                .node_ids
+                .entry(hex.to_string())
+                .or_insert_with(|| format!("{prefix}{next:064x}"))
+                .clone();
+        }
+        if hex.len() == 64 {
+            let next = self.hashes.len() + 1;
+            return self
+                .hashes
+                .entry(hex.to_string())
+                .or_insert_with(|| format!("{:0>64}", format!("f{next:x}")))
+                .clone();
+        }
+        hex.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use super::{load_recordings, method, RecordedCall, RecordedError, Redactor, AVAILDB_FILE};
+
+    const NODE: &str = "02c095d069538f96bf14c5f90f6c0851bdf354a0ec86039a24bf38a73f705adc2c";
+    const TXID: &str = "a5d2a6b1c3e4f5061728394a5b6c7d8e9f0a1b2c3d4e5f60718293a4b5c6d7e8";
+
+    #[test]
+    fn redaction_is_consistent_across_args_and_responses() {
+        let mut redactor = Redactor::default();
+        let mut call = RecordedCall {
+            args: vec!["listpeerchannels".to_string(), NODE.to_string()],
+            response: Some(json!({
+                "channels": [{
+                    "peer_id": NODE,
+                    "funding_txid": TXID,
+                    "short_channel_id": "867798x3251x1",
+                    "account": format!("{TXID}:1"),
+                }]
+            })),
+            error: None,
+        };
+
+        redactor.redact_call(&mut call);
+
+        let node = call.args[1].clone();
+        assert_ne!(node, NODE);
+        assert_eq!(node.len(), 66);
+        assert!(node.starts_with("02"));
+        let channel = &call.response.as_ref().unwrap()["channels"][0];
+        assert_eq!(channel["peer_id"], json!(node));
+        let txid = channel["funding_txid"].as_str().unwrap().to_string();
+        assert_eq!(txid.len(), 64);
+        assert_ne!(txid, TXID);
+        assert_eq!(channel["account"], json!(format!("{txid}:1")));
+        assert_eq!(channel["short_channel_id"], json!("867798x3251x1"));
+
+        // availdb entries are keyed by node id
+        let mut availdb = json!({NODE: {"count": 226, "connected": true, "avail": 1.0}});
+        redactor.redact_value(&mut availdb);
+        assert_eq!(availdb[&node]["avail"], json!(1.0));
+    }
+
+    #[test]
+    fn recordings_are_grouped_by_args_in_recording_order() {
+        let directory = temporary_test_directory();
+        fs::create_dir_all(&directory).unwrap();
+        let calls = [
+            (
+                "0001-getinfo.json",
+                vec!["getinfo"],
+                Some(json!({"id": 1})),
+                None,
+            ),
+            (
+                "0002-getinfo.json",
+                vec!["getinfo"],
+                Some(json!({"id": 2})),
+                None,
+            ),
+            (
+                "0003-getroutes.json",
+                vec!["getroutes", "a", "b"],
+                None,
+                Some(RecordedError {
+                    code: 210,
+                    message: "no route".to_string(),
+                    data: None,
+                }),
+            ),
+        ];
+        for (file_name, args, response, error) in calls {
+            let call = RecordedCall {
+                args: args.into_iter().map(str::to_string).collect(),
+                response,
+                error,
+            };
+            fs::write(
+                directory.join(file_name),
+                serde_json::to_vec(&call).unwrap(),
+            )
+            .unwrap();
+        }
+
+        fs::write(directory.join(AVAILDB_FILE), r#"{"02aa":{"avail":1.0}}"#).unwrap();
+
+        let replayer = load_recordings(&directory).unwrap();
+        let getinfo = &replayer.calls[&vec!["getinfo".to_string()]];
+        assert_eq!(getinfo.len(), 2);
+        assert_eq!(getinfo[0].response, Some(json!({"id": 1})));
+        assert_eq!(replayer.calls.len(), 2);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn method_skips_keyword_flag() {
+        assert_eq!(method(&["-k", "setchannel", "id=1x2x3"]), "setchannel");
+        assert_eq!(method(&["sling-job", "-k", "scid=1x2x3"]), "sling-job");
+    }
+
+    fn temporary_test_directory() -> PathBuf {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!(
+            "lightdash-replay-test-{}-{nonce}",
+            std::process::id()
+        ))
+    }
+}
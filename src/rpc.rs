@@ -0,0 +1,534 @@
+//! Native Core Lightning JSON-RPC 2.0 client talking to the `lightning-rpc`
+//! unix socket, either directly or through an SSH stream-local forward.
+
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{self, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+const TUNNEL_READY_TIMEOUT: Duration = Duration::from_secs(15);
+const TUNNEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// Connecting to, writing to, or reading from the socket failed.
+    Io { method: String, source: io::Error },
+    /// The node did not answer within the configured inactivity timeout.
+    Timeout { method: String, timeout: Duration },
+    /// The response was not valid JSON.
+    Json {
+        method: String,
+        source: serde_json::Error,
+    },
+    /// The response was valid JSON but not a JSON-RPC response to our request.
+    Protocol { method: String, message: String },
+    /// Core Lightning answered the request with a JSON-RPC error object.
+    Rpc {
+        method: String,
+        code: i64,
+        message: String,
+        data: Option<Value>,
+    },
+}
+
+impl RpcError {
+    /// The JSON error object `lightning-cli` prints for a failed command.
+    pub fn to_error_object(&self) -> Option<Value> {
+        let RpcError::Rpc {
+            code,
+            message,
+            data,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let mut object = Map::new();
+        object.insert("code".to_string(), Value::from(*code));
+        object.insert("message".to_string(), Value::from(message.as_str()));
+        if let Some(data) = data {
+            object.insert("data".to_string(), data.clone());
+        }
+        Some(Value::Object(object))
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Io { method, source } => write!(f, "`{method}` socket I/O failed: {source}"),
+            RpcError::Timeout { method, timeout } => write!(
+                f,
+                "`{method}` received no data for {:.0}s",
+                timeout.as_secs_f64()
+            ),
+            RpcError::Json { method, source } => {
+                write!(f, "`{method}` returned invalid JSON: {source}")
+            }
+            RpcError::Protocol { method, message } => {
+                write!(f, "`{method}` violated JSON-RPC: {message}")
+            }
+            RpcError::Rpc {
+                method,
+                code,
+                message,
+                ..
+            } => write!(f, "`{method}` failed with code {code}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Io { source, .. } => Some(source),
+            RpcError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// JSON-RPC client for one `lightning-rpc` socket.
+///
+/// Every call opens its own connection, so a client can be shared between
+/// threads without serializing requests.
+pub struct RpcClient {
+    socket_path: PathBuf,
+    timeout: Duration,
+    next_id: AtomicU64,
+}
+
+impl RpcClient {
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            timeout: DEFAULT_TIMEOUT,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Send one request and return its `result` member.
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let id = format!(
+            "lightdash:{method}#{}/{}",
+            std::process::id(),
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        );
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        let io_error = |source: io::Error| self.io_error(method, source);
+
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(io_error)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(io_error)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(io_error)?;
+        serde_json::to_writer(&mut stream, &request).map_err(|source| RpcError::Json {
+            method: method.to_string(),
+            source,
+        })?;
+        stream.flush().map_err(io_error)?;
+
+        let messages =
+            serde_json::Deserializer::from_reader(BufReader::new(&stream)).into_iter::<Value>();
+        for message in messages {
+            let message = message.map_err(|source| self.json_error(method, source))?;
+            if message.get("id").is_none() && message.get("method").is_some() {
+                // Notifications are only sent when enabled, but never belong to a request.
+                continue;
+            }
+            if message.get("id").and_then(Value::as_str) != Some(id.as_str()) {
+                return Err(RpcError::Protocol {
+                    method: method.to_string(),
+                    message: format!("response id {} does not match `{id}`", message["id"]),
+                });
+            }
+            return parse_response(method, message);
+        }
+
+        Err(RpcError::Protocol {
+            method: method.to_string(),
+            message: "connection closed before a response was received".to_string(),
+        })
+    }
+
+    fn io_error(&self, method: &str, source: io::Error) -> RpcError {
+        if is_timeout(source.kind()) {
+            RpcError::Timeout {
+                method: method.to_string(),
+                timeout: self.timeout,
+            }
+        } else {
+            RpcError::Io {
+                method: method.to_string(),
+                source,
+            }
+        }
+    }
+
+    fn json_error(&self, method: &str, source: serde_json::Error) -> RpcError {
+        match source.io_error_kind() {
+            Some(kind) if is_timeout(kind) => RpcError::Timeout {
+                method: method.to_string(),
+                timeout: self.timeout,
+            },
+            Some(_) if source.is_io() => RpcError::Io {
+                method: method.to_string(),
+                source: io::Error::from(source),
+            },
+            _ => RpcError::Json {
+                method: method.to_string(),
+                source,
+            },
+        }
+    }
+}
+
+fn is_timeout(kind: io::ErrorKind) -> bool {
+    matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn parse_response(method: &str, mut message: Value) -> Result<Value, RpcError> {
+    if let Some(mut error) = message.get_mut("error").map(Value::take) {
+        return Err(RpcError::Rpc {
+            method: method.to_string(),
+            code: error
+                .get("code")
+                .and_then(Value::as_i64)
+                .unwrap_or_default(),
+            message: error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            data: error.get_mut("data").map(Value::take),
+        });
+    }
+    message
+        .get_mut("result")
+        .map(Value::take)
+        .ok_or_else(|| RpcError::Protocol {
+            method: method.to_string(),
+            message: "response has neither `result` nor `error`".to_string(),
+        })
+}
+
+/// Convert `lightning-cli` style arguments into a method name and JSON params.
+///
+/// Mirrors `lightning-cli`: positional arguments become an array, `-k` switches
+/// to `key=value` keyword arguments, and values that are JSON literals
+/// (numbers, booleans, null, arrays, objects, quoted strings) are passed as JSON
+/// while everything else is passed as a string.
+pub fn cli_request(args: &[&str]) -> Result<(String, Value), String> {
+    let keywords = args.iter().any(|arg| matches!(*arg, "-k" | "--keywords"));
+    let mut positional = args
+        .iter()
+        .copied()
+        .filter(|arg| !matches!(*arg, "-k" | "--keywords"));
+    let method = positional
+        .next()
+        .ok_or_else(|| "lightning-cli arguments do not contain a command".to_string())?;
+    if method.starts_with('-') {
+        return Err(format!("unsupported lightning-cli option `{method}`"));
+    }
+
+    let params = if keywords {
+        let mut object = Map::new();
+        for arg in positional {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("keyword argument `{arg}` is not key=value"))?;
+            object.insert(key.to_string(), cli_value(value));
+        }
+        Value::Object(object)
+    } else {
+        Value::Array(positional.map(cli_value).collect())
+    };
+    Ok((method.to_string(), params))
+}
+
+fn cli_value(value: &str) -> Value {
+    let is_literal = matches!(value, "true" | "false" | "null")
+        || value.starts_with(['[', '{', '"'])
+        || value.parse::<f64>().is_ok_and(f64::is_finite);
+    if is_literal {
+        if let Ok(parsed) = serde_json::from_str(value) {
+            return parsed;
+        }
+    }
+    Value::String(value.to_string())
+}
+
+/// An `ssh -L` stream-local forward of a remote `lightning-rpc` socket.
+///
+/// The forward lives as long as this value; dropping it stops SSH and removes
+/// the local socket.
+pub struct SshTunnel {
+    child: Child,
+    local_path: PathBuf,
+}
+
+impl SshTunnel {
+    pub fn open(destination: &str, remote_path: &str) -> Result<Self, String> {
+        let local_path =
+            std::env::temp_dir().join(format!("lightdash-rpc-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&local_path);
+        let forward = format!("{}:{remote_path}", local_path.display());
+        let mut child = Command::new("ssh")
+            .args([
+                "-N",
+                "-o",
+                "ExitOnForwardFailure=yes",
+                "-o",
+                "StreamLocalBindUnlink=yes",
+                "-L",
+                &forward,
+                destination,
+            ])
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("starting `ssh -L {forward} {destination}` failed: {e}"))?;
+
+        let started = Instant::now();
+        while !local_path.exists() {
+            if let Some(status) = child
+                .try_wait()
+                .map_err(|e| format!("waiting for SSH forward failed: {e}"))?
+            {
+                return Err(format!(
+                    "`ssh -L {forward} {destination}` exited with status {status}"
+                ));
+            }
+            if started.elapsed() >= TUNNEL_READY_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "SSH forward to `{remote_path}` was not ready after {}s",
+                    TUNNEL_READY_TIMEOUT.as_secs()
+                ));
+            }
+            std::thread::sleep(TUNNEL_POLL_INTERVAL);
+        }
+        log::debug!(
+            "Forwarding {} to {destination}:{remote_path}",
+            local_path.display()
+        );
+        Ok(Self { child, local_path })
+    }
+
+    pub fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.local_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{BufReader, Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use flate2::read::GzDecoder;
+    use serde_json::{json, Value};
+
+    use super::{cli_request, RpcClient, RpcError};
+    use crate::cmd::{GetInfo, ListFunds};
+
+    /// Stand-in for `lightningd` that answers from the `test-json/` fixtures.
+    fn spawn_fixture_server(socket_path: &Path) {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+                std::thread::spawn(move || {
+                    let request: Value = serde_json::Deserializer::from_reader(BufReader::new(
+                        stream.try_clone().unwrap(),
+                    ))
+                    .into_iter()
+                    .next()
+                    .unwrap()
+                    .unwrap();
+                    let response = match request["method"].as_str().unwrap() {
+                        "getinfo" => json!({"result": fixture("test-json/getinfo")}),
+                        "listfunds" => json!({"result": fixture("test-json/listfunds.gz")}),
+                        "echo" => json!({"result": request["params"].clone()}),
+                        "hang" => {
+                            std::thread::sleep(Duration::from_secs(5));
+                            return;
+                        }
+                        "wrongid" => json!({"id": "someone-else", "result": {}}),
+                        method => json!({
+                            "error": {"code": -32601, "message": format!("Unknown command '{method}'")}
+                        }),
+                    };
+                    let mut response = response;
+                    if response.get("id").is_none() {
+                        response["id"] = request["id"].clone();
+                    }
+                    response["jsonrpc"] = json!("2.0");
+                    serde_json::to_writer(&mut stream, &response).unwrap();
+                    stream.write_all(b"\n\n").unwrap();
+                });
+            }
+        });
+    }
+
+    fn fixture(path: &str) -> Value {
+        let mut content = String::new();
+        let file = File::open(path).unwrap();
+        if path.ends_with(".gz") {
+            GzDecoder::new(file).read_to_string(&mut content).unwrap();
+        } else {
+            BufReader::new(file).read_to_string(&mut content).unwrap();
+        }
+        serde_json::from_str(&content).unwrap()
+    }
+
+    fn temporary_socket() -> PathBuf {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!(
+            "lightdash-rpc-test-{}-{nonce}.sock",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn client_replays_fixture_responses() {
+        let socket = temporary_socket();
+        spawn_fixture_server(&socket);
+        let client = RpcClient::new(&socket);
+
+        let info: GetInfo =
+            serde_json::from_value(client.call("getinfo", json!({})).unwrap()).unwrap();
+        assert_eq!(
+            info.id,
+            "02c095d069538f96bf14c5f90f6c0851bdf354a0ec86039a24bf38a73f705adc2c"
+        );
+        let funds: ListFunds =
+            serde_json::from_value(client.call("listfunds", json!([])).unwrap()).unwrap();
+        assert!(!funds.channels.is_empty());
+
+        fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn rpc_errors_keep_code_and_message() {
+        let socket = temporary_socket();
+        spawn_fixture_server(&socket);
+        let error = RpcClient::new(&socket)
+            .call("nosuchcommand", json!([]))
+            .unwrap_err();
+
+        assert!(matches!(&error, RpcError::Rpc { code: -32601, .. }));
+        assert_eq!(
+            error.to_error_object().unwrap(),
+            json!({"code": -32601, "message": "Unknown command 'nosuchcommand'"})
+        );
+        fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn silent_node_times_out_and_mismatched_ids_are_rejected() {
+        let socket = temporary_socket();
+        spawn_fixture_server(&socket);
+        let client = RpcClient::new(&socket).with_timeout(Duration::from_millis(100));
+
+        assert!(matches!(
+            client.call("hang", json!([])),
+            Err(RpcError::Timeout { .. })
+        ));
+        assert!(matches!(
+            client.call("wrongid", json!([])),
+            Err(RpcError::Protocol { .. })
+        ));
+        fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn missing_socket_is_an_io_error() {
+        assert!(matches!(
+            RpcClient::new(temporary_socket()).call("getinfo", json!({})),
+            Err(RpcError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn cli_arguments_follow_lightning_cli_literal_rules() {
+        assert_eq!(
+            cli_request(&["setchannel", "1x2x3", "1000", "25", "100000msat"]).unwrap(),
+            (
+                "setchannel".to_string(),
+                json!(["1x2x3", 1000, 25, "100000msat"])
+            )
+        );
+        assert_eq!(
+            cli_request(&[
+                "sling-job",
+                "-k",
+                "scid=1x2x3",
+                "target=0.5",
+                "candidates=[\"4x5x6\"]",
+            ])
+            .unwrap(),
+            (
+                "sling-job".to_string(),
+                json!({"scid": "1x2x3", "target": 0.5, "candidates": ["4x5x6"]})
+            )
+        );
+        assert_eq!(
+            cli_request(&["datastore", "-k", "key=[\"a\",\"b\"]", "string=\"12\""]).unwrap(),
+            (
+                "datastore".to_string(),
+                json!({"key": ["a", "b"], "string": "12"})
+            )
+        );
+        assert!(cli_request(&["-k"]).is_err());
+        assert!(cli_request(&["datastore", "-k", "novalue"]).is_err());
+    }
+
+    #[test]
+    fn echoed_params_round_trip() {
+        let socket = temporary_socket();
+        spawn_fixture_server(&socket);
+        let (method, params) = cli_request(&["echo", "hello world", "true"]).unwrap();
+        assert_eq!(
+            RpcClient::new(&socket).call(&method, params).unwrap(),
+            json!(["hello world", true])
+        );
+        fs::remove_file(socket).unwrap();
+    }
+}
@@ -1,7 +1,13 @@
-use crate::cmd::Fund;
+use crate::error::LightdashError;
+use crate::rebalance::RouteSimulator;
+use crate::sling_budget::{BudgetSettings, SpendBudget};
+use crate::sling_jobs::{self, JobChange, SlingJob};
+use crate::sling_review::{self, REVIEW_DAYS};
+use crate::sling_sources::{self, SOURCE_DEMAND_DAYS};
 use crate::store::Store;
 use serde_json::Value;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -27,42 +33,56 @@ const LOW_LOCAL_BOOTSTRAP_AMOUNT_SAT: u64 = crate::fees::DEPLETED_LOCAL_BALANCE_
 const LOW_LOCAL_BOOTSTRAP_MAX_PPM: u64 = BUDGET_PPM_MAX;
 
 const BOOTSTRAP_CAPACITY_DIVISOR: u64 = 20;
-const CANDIDATE_DEPLETE_UP_TO_PERCENT: &str = "0.5";
+pub const CANDIDATE_DEPLETE_UP_TO_PERCENT: f64 = 0.5;
 const CANDIDATE_DEPLETE_UP_TO_AMOUNT_SAT: u64 = 1_000_000;
 const CMD: &str = "lightning-cli";
 /// Minimum balance percentage (our funds / total capacity) for a channel to be used as candidate.
-const MIN_CANDIDATE_BALANCE: f64 = 0.7;
-
-/// Computes candidates with liquidity for a target's Sling rebalance.
-///
-/// Sling's `outppm` parameter filters candidates by ppm but not by balance.
-/// We want to pull from channels where:
-///   1. ppm < source_ppm_max (drain channels cheap enough for this target)
-///   2. balance > MIN_CANDIDATE_BALANCE (only drain channels that have enough liquidity on our side)
-///
-/// Since sling doesn't support filtering by balance natively, we explicitly compute
-/// the candidates list ourselves and pass it via the `candidates` parameter.
-fn compute_candidates<'a>(
-    store: &'a Store,
-    channels: &'a [Fund],
-    source_ppm_max: u64,
-) -> Vec<&'a String> {
-    channels
-        .iter()
-        .filter_map(|ch| {
-            let scid = ch.short_channel_id.as_ref()?;
-            let our = store.get_channel(scid, &store.info.id)?;
-            if our.fee_per_millionth < source_ppm_max && ch.perc_float() > MIN_CANDIDATE_BALANCE {
-                Some(scid)
-            } else {
-                None
-            }
-        })
-        .collect()
+pub const MIN_CANDIDATE_BALANCE: f64 = 0.7;
+
+pub const DEMAND_ROUTED_DAYS: i64 = 7;
+pub const DEMAND_MIN_ROUTED_SAT: u64 = 20_000;
+pub const DEMAND_FORWARDS_DAYS: i64 = 14;
+pub const DEMAND_MIN_FORWARDS: usize = 2;
+/// Local failures over the last 7 days showing the target ran out of liquidity.
+pub const DEMAND_MIN_LOCAL_FAILURES: usize = 1;
+/// Highest fee searched by `--simulate`, the 1% ceiling xpay uses.
+const SIMULATION_MAX_PPM: u64 = 10_000;
+
+/// How much recent demand a target needs, set with `lightdash sling` options.
+#[derive(Clone, Debug)]
+pub struct DemandSettings {
+    pub routed_days: i64,
+    pub min_routed_sat: u64,
+    pub forwards_days: i64,
+    pub min_forwards: usize,
+    pub min_local_failures: usize,
+}
+
+impl Default for DemandSettings {
+    fn default() -> Self {
+        DemandSettings {
+            routed_days: DEMAND_ROUTED_DAYS,
+            min_routed_sat: DEMAND_MIN_ROUTED_SAT,
+            forwards_days: DEMAND_FORWARDS_DAYS,
+            min_forwards: DEMAND_MIN_FORWARDS,
+            min_local_failures: DEMAND_MIN_LOCAL_FAILURES,
+        }
+    }
+}
+
+/// Recent outbound demand of a target channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct TargetDemand {
+    /// Settled amount routed out over `routed_days`.
+    routed_sat: u64,
+    /// Settled forwards out over `forwards_days`.
+    forwards: usize,
+    /// Local failures out of the channel over the last 7 days.
+    local_failures: usize,
 }
 
 /// Formats candidates as a JSON array string for sling's `candidates` parameter.
-fn candidates_to_json(candidates: &[&String]) -> String {
+fn candidates_to_json(candidates: &[String]) -> String {
     format!(
         "[{}]",
         candidates
@@ -73,10 +93,179 @@ fn candidates_to_json(candidates: &[&String]) -> String {
     )
 }
 
+struct SimulatedJobs {
+    jobs: Vec<PlannedJob>,
+    simulated: u64,
+    infeasible: u64,
+}
+
+/// Route every target/candidate pair of the planned jobs at the job amount,
+/// with `route_ppm` returning the fee PPM of the route found, and skip the
+/// jobs without any route within their `maxppm` before they reserve budget.
+fn simulate_jobs(
+    planned: Vec<PlannedJob>,
+    mut route_ppm: impl FnMut(&str, &str, u64) -> Result<Option<u64>, LightdashError>,
+) -> Result<SimulatedJobs, LightdashError> {
+    let mut simulated = SimulatedJobs {
+        jobs: Vec::new(),
+        simulated: 0,
+        infeasible: 0,
+    };
+    for planned_job in planned {
+        let job = &planned_job.job;
+        let mut cheapest: Option<(u64, &str)> = None;
+        for candidate in &job.candidates {
+            let ppm = route_ppm(candidate, &job.scid, job.amount_sat)?;
+            log::debug!(
+                "simulate target:{} source:{candidate} amount:{}s ppm:{}",
+                job.scid,
+                job.amount_sat,
+                ppm.map(|ppm| ppm.to_string())
+                    .unwrap_or_else(|| "no-route".to_string())
+            );
+            if let Some(ppm) = ppm {
+                if cheapest.is_none_or(|(cheapest_ppm, _)| ppm < cheapest_ppm) {
+                    cheapest = Some((ppm, candidate));
+                }
+            }
+        }
+        simulated.simulated += 1;
+        let feasible = cheapest.is_some_and(|(ppm, _)| ppm <= job.maxppm);
+        let result = if feasible { "feasible" } else { "infeasible" };
+        log::info!(
+            "scid:{} amount:{:>6}s maxppm:{:>4} cheapest_ppm:{:>5} via:{} cand:{:>3} result:{result:<12} alias:{}",
+            job.scid,
+            job.amount_sat,
+            job.maxppm,
+            cheapest
+                .map(|(ppm, _)| ppm.to_string())
+                .unwrap_or_else(|| "n/a".to_string()),
+            cheapest.map_or("none", |(_, source)| source),
+            job.candidates.len(),
+            job.alias
+        );
+        if feasible {
+            simulated.jobs.push(planned_job);
+        } else {
+            simulated.infeasible += 1;
+        }
+    }
+    Ok(simulated)
+}
+
+/// An ordinary job before the spend budget is applied.
+struct PlannedJob {
+    job: SlingJob,
+    capacity_sat: u64,
+    local_balance_sat: u64,
+    routed_sat: u64,
+}
+
+struct FittedJobs {
+    jobs: Vec<SlingJob>,
+    scaled: u64,
+    skipped: u64,
+}
+
+/// Reserve the worst-case fee of every planned job, highest routed demand
+/// first. A job that does not fit pulls toward a lower target balance, and is
+/// skipped when less than `MIN_AMOUNT_SAT` would be left to pull.
+fn fit_to_budget(mut planned: Vec<PlannedJob>, budget: &mut SpendBudget) -> FittedJobs {
+    planned.sort_by(|a, b| b.routed_sat.cmp(&a.routed_sat));
+    let mut fitted = FittedJobs {
+        jobs: Vec::new(),
+        scaled: 0,
+        skipped: 0,
+    };
+    for PlannedJob {
+        mut job,
+        capacity_sat,
+        local_balance_sat,
+        ..
+    } in planned
+    {
+        let pull_sat =
+            |target: f64| ((target * capacity_sat as f64) as u64).saturating_sub(local_balance_sat);
+        let missing_sat = pull_sat(job.target);
+        let affordable_sat = budget.affordable_sat(missing_sat, job.maxppm);
+        if affordable_sat < missing_sat {
+            let target = demand_target_balance(capacity_sat, local_balance_sat, affordable_sat);
+            let scaled_sat = pull_sat(target);
+            if scaled_sat < MIN_AMOUNT_SAT {
+                fitted.skipped += 1;
+                let result = "skip-budget";
+                log::info!(
+                    "scid:{} missing:{missing_sat:>7}s affordable:{affordable_sat:>7}s maxppm:{:>4} result:{result:<12} alias:{}",
+                    job.scid,
+                    job.maxppm,
+                    job.alias
+                );
+                continue;
+            }
+            fitted.scaled += 1;
+            let result = "budget-scaled";
+            log::info!(
+                "scid:{} missing:{missing_sat:>7}s affordable:{affordable_sat:>7}s maxppm:{:>4} target:{}->{target} result:{result:<12} alias:{}",
+                job.scid,
+                job.maxppm,
+                job.target,
+                job.alias
+            );
+            job.target = target;
+            job.max_amount_sat = job.max_amount_sat.min(scaled_sat);
+            job.amount_sat = job.amount_sat.min(job.max_amount_sat);
+            budget.reserve(scaled_sat, job.maxppm);
+        } else {
+            budget.reserve(missing_sat, job.maxppm);
+        }
+        fitted.jobs.push(job);
+    }
+    fitted
+}
+
 fn is_target_eligible(balance: f64) -> bool {
     balance <= TARGET_ELIGIBLE_MAX_BALANCE
 }
 
+fn target_demand(store: &Store, settings: &DemandSettings) -> HashMap<String, TargetDemand> {
+    let mut demand: HashMap<String, TargetDemand> = HashMap::new();
+    for forward in store.filter_settled_forwards_by_days(settings.routed_days) {
+        demand.entry(forward.out_channel).or_default().routed_sat += forward.out_sat;
+    }
+    for forward in store.filter_settled_forwards_by_days(settings.forwards_days) {
+        demand.entry(forward.out_channel).or_default().forwards += 1;
+    }
+    for failures in store.local_failed_temp_channel_failure_by_out_channel() {
+        demand
+            .entry(failures.channel_id)
+            .or_default()
+            .local_failures = failures.counts.week;
+    }
+    demand
+}
+
+/// Settled demand, and local failures showing it went unserved.
+fn has_target_demand(demand: &TargetDemand, settings: &DemandSettings) -> bool {
+    demand.routed_sat >= settings.min_routed_sat
+        && demand.forwards >= settings.min_forwards
+        && demand.local_failures >= settings.min_local_failures
+}
+
+/// Balance a job pulls the target toward: the local balance plus the sats
+/// routed out over the demand window, at most `TARGET_REBALANCE_BALANCE`.
+/// Rounded down to the hundredths Sling's `target` is given in.
+fn demand_target_balance(
+    channel_capacity_sat: u64,
+    local_balance_sat: u64,
+    routed_sat: u64,
+) -> f64 {
+    if channel_capacity_sat == 0 {
+        return 0.0;
+    }
+    let balance = local_balance_sat.saturating_add(routed_sat) as f64 / channel_capacity_sat as f64;
+    (balance.min(TARGET_REBALANCE_BALANCE) * 100.0).floor() / 100.0
+}
+
 fn usable_ppm(ppm: Option<f64>) -> Option<f64> {
     ppm.filter(|ppm| ppm.is_finite() && *ppm > 0.0)
 }
@@ -137,8 +326,9 @@ fn compute_base_rebalance_amount(channel_capacity_sat: u64) -> Option<u64> {
 fn compute_capacity_rebalance_amounts(
     channel_capacity_sat: u64,
     local_balance_sat: u64,
+    target_balance: f64,
 ) -> Option<u64> {
-    let target_local_sat = (channel_capacity_sat as f64 * TARGET_REBALANCE_BALANCE) as u64;
+    let target_local_sat = (channel_capacity_sat as f64 * target_balance) as u64;
     let missing_to_target_sat = target_local_sat.saturating_sub(local_balance_sat);
     let bootstrap_amount = compute_base_rebalance_amount(channel_capacity_sat)?;
     let amount = bootstrap_amount.min(missing_to_target_sat);
@@ -192,25 +382,14 @@ fn low_local_bootstrap_args<'a>(
     ]
 }
 
-fn reset_existing_sling_jobs() {
-    log::info!(
-        "EXECUTE_SLING is set, stopping and deleting existing sling jobs before creating new ones"
-    );
-
-    let result = crate::cmd::cmd_result(CMD, &["sling-stop"]);
-    log::debug!("sling-stop return: {result}");
-
-    let result = crate::cmd::cmd_result(CMD, &["sling-deletejob", "all"]);
-    log::debug!("sling-deletejob all return: {result}");
-}
-
-fn start_sling_jobs() {
-    log::info!("EXECUTE_SLING is set, starting created sling jobs with sling-go");
-    let result = crate::cmd::cmd_result(CMD, &["sling-go"]);
+fn start_sling_jobs() -> Result<(), LightdashError> {
+    log::info!("EXECUTE_SLING is set, starting sling jobs with sling-go");
+    let result = crate::cmd::cmd_result(CMD, &["sling-go"])?;
     log::debug!("sling-go return: {result}");
+    Ok(())
 }
 
-fn get_sling_stats(scid: Option<&str>) -> Value {
+fn get_sling_stats(scid: Option<&str>) -> Result<Value, LightdashError> {
     if crate::cmd::using_test_data() {
         match scid {
             Some(_) => {
@@ -229,10 +408,15 @@ fn get_sling_stats(scid: Option<&str>) -> Value {
     }
 }
 
-pub fn current_sling_stats() -> Value {
-    let mut stats = get_sling_stats(None);
-    enrich_sling_stats_with_last_channel_partner(&mut stats, |scid| get_sling_stats(Some(scid)));
-    stats
+pub fn current_sling_stats() -> Result<Value, LightdashError> {
+    let mut stats = get_sling_stats(None)?;
+    enrich_sling_stats_with_last_channel_partner(&mut stats, |scid| {
+        get_sling_stats(Some(scid)).unwrap_or_else(|e| {
+            log::warn!("Fetching sling-stats details for {scid} failed: {e}");
+            Value::Null
+        })
+    });
+    Ok(stats)
 }
 
 fn enrich_sling_stats_with_last_channel_partner(
@@ -268,7 +452,12 @@ fn enrich_sling_stats_with_last_channel_partner(
 }
 
 /// We search empty channels and try to pull sats on them from a list of candidates that are ~full and cheap.
-pub fn run_sling(store: &Store) {
+pub fn run_sling(
+    store: &Store,
+    demand_settings: &DemandSettings,
+    budget_settings: &BudgetSettings,
+    simulate: bool,
+) -> Result<(), LightdashError> {
     let channels = store.normal_channels();
     log::info!(
         "Sling inputs: channels:{} target_eligible_balance<={:.0}% rebalance_target:{:.0}% candidate_balance>=:{:.0}% candidate_fallback_ppm:<{} candidate_target_value_multiplier:{:.0}% min_amount:{}sat depleteuptopercent:{} depleteuptoamount:{}",
@@ -282,18 +471,99 @@ pub fn run_sling(store: &Store) {
         CANDIDATE_DEPLETE_UP_TO_PERCENT,
         CANDIDATE_DEPLETE_UP_TO_AMOUNT_SAT
     );
+    log::info!(
+        "Sling demand: routed>={}sat over {} days, forwards>={} over {} days, local_failures>={} over 7 days",
+        demand_settings.min_routed_sat,
+        demand_settings.routed_days,
+        demand_settings.min_forwards,
+        demand_settings.forwards_days,
+        demand_settings.min_local_failures
+    );
+    let demand_by_channel = target_demand(store, demand_settings);
+    // Sling drains candidates down to depleteuptopercent, the rest is surplus
+    let sources = sling_sources::score_sources(
+        store,
+        &channels,
+        MIN_CANDIDATE_BALANCE,
+        CANDIDATE_DEPLETE_UP_TO_PERCENT,
+    );
+    let explore = |scid: &str| sling_sources::is_exploration_day(scid, store.snapshot_time());
+    for source in &sources {
+        log::debug!(
+            "source scid:{} ppm:{} realized_ppm:{} out_{SOURCE_DEMAND_DAYS}d:{}s in_{SOURCE_DEMAND_DAYS}d:{}s surplus:{}s indirect:{}s opportunity_ppm:{} indirect_ppm:{:.0} explore:{}",
+            source.short_channel_id,
+            source.advertised_ppm,
+            source
+                .realized_ppm
+                .map(|ppm| format!("{ppm:.0}"))
+                .unwrap_or_else(|| "n/a".to_string()),
+            source.recent_out_sat,
+            source.recent_in_sat,
+            source.surplus_sat,
+            source.indirect_fees_sat,
+            source
+                .opportunity_ppm
+                .map(|ppm| format!("{ppm:.0}"))
+                .unwrap_or_else(|| "untested".to_string()),
+            source.indirect_ppm,
+            explore(&source.short_channel_id),
+        );
+    }
+    let untested = sources.iter().filter(|source| !source.is_tested());
+    log::info!(
+        "Sling sources: surplus_channels:{} untested:{} explored_today:{}",
+        sources.len(),
+        untested.clone().count(),
+        untested
+            .filter(|source| explore(&source.short_channel_id))
+            .count()
+    );
+    let mut budget = SpendBudget::from_store(store, budget_settings);
+    log::info!(
+        "Sling budget: limit:{} spent:{}sat earned:{}sat over {} days",
+        budget
+            .limit_msat
+            .map(|msat| format!("{}sat", msat / 1000))
+            .unwrap_or_else(|| "none".to_string()),
+        budget.spent_msat / 1000,
+        budget.earned_msat / 1000,
+        budget_settings.days
+    );
+    if budget.spending_unknown {
+        log::warn!(
+            "Bookkeeper is unavailable: rebalance spending is unknown, the budget counts as {} and the payback review sees no rebalances",
+            if budget.limit_msat.is_some() {
+                "exhausted"
+            } else {
+                "unlimited"
+            }
+        );
+    }
+
+    let review = sling_review::review(store, REVIEW_DAYS, store.snapshot_time());
+    let unprofitable = sling_review::unprofitable_targets(&review);
 
     let execute_sling = std::env::var("EXECUTE_SLING").is_ok();
-    if execute_sling {
-        reset_existing_sling_jobs();
-    }
+    let existing_jobs = match sling_jobs::current_jobs() {
+        Ok(jobs) => jobs,
+        Err(e) if !execute_sling => {
+            log::warn!("Reading sling-jobsettings failed, diffing against no jobs: {e}");
+            BTreeMap::new()
+        }
+        Err(e) => return Err(e),
+    };
+    let mut planned_jobs = Vec::new();
+    // Targets whose sources are only momentarily missing keep their job
+    let mut kept_jobs = HashSet::new();
 
     let mut skipped_balance = 0u64;
     let mut skipped_missing_scid = 0u64;
     let mut targets_without_local_channel_info = 0u64;
     let mut skipped_small_amount = 0u64;
     let mut skipped_no_candidates = 0u64;
-    let mut suggested = 0u64;
+    let mut skipped_unprofitable = 0u64;
+    let mut skipped_no_demand = 0u64;
+    let mut skipped_budget = 0u64;
     let mut bootstrap = 0u64;
 
     for channel in &channels {
@@ -309,6 +579,35 @@ pub fn run_sling(store: &Store) {
         };
 
         let alias = store.get_node_alias(&channel.peer_id);
+        if unprofitable.contains(scid.as_str()) {
+            skipped_unprofitable += 1;
+            let result = "skip-unprof";
+            log::info!(
+                "balance:{:>5.1}% rebalances of the last {REVIEW_DAYS} days did not pay back result:{result:<12} alias:{alias}",
+                balance * 100.0,
+            );
+            continue;
+        }
+        let local_balance_sat = channel.our_amount_msat / 1000;
+        let demand = demand_by_channel.get(scid).copied().unwrap_or_default();
+        // A dust channel cannot route out, its failures are the demand
+        let demanded = if should_bootstrap_low_local(local_balance_sat) {
+            demand.local_failures >= demand_settings.min_local_failures
+        } else {
+            has_target_demand(&demand, demand_settings)
+        };
+        if !demanded {
+            skipped_no_demand += 1;
+            let result = "skip-no-dem";
+            log::info!(
+                "balance:{:>5.1}% routed:{:>7}s fwd:{:>3} local_failures:{:>3} result:{result:<12} alias:{alias}",
+                balance * 100.0,
+                demand.routed_sat,
+                demand.forwards,
+                demand.local_failures,
+            );
+            continue;
+        }
         let my_ppm = store
             .get_channel(scid, &store.info.id)
             .map(|our| our.fee_per_millionth);
@@ -332,10 +631,10 @@ pub fn run_sling(store: &Store) {
             .unwrap_or_else(|| "n/a".to_string());
 
         let channel_capacity_sat = channel.amount_msat / 1000;
-        let local_balance_sat = channel.our_amount_msat / 1000;
 
         if should_bootstrap_low_local(local_balance_sat) {
-            let candidates = compute_candidates(store, &channels, SOURCE_PPM_FALLBACK);
+            let candidates =
+                sling_sources::select_candidates(&sources, SOURCE_PPM_FALLBACK, explore);
             if candidates.is_empty() {
                 skipped_no_candidates += 1;
                 let result = "skip-no-cand";
@@ -347,6 +646,18 @@ pub fn run_sling(store: &Store) {
                 continue;
             }
 
+            if budget.affordable_sat(LOW_LOCAL_BOOTSTRAP_AMOUNT_SAT, LOW_LOCAL_BOOTSTRAP_MAX_PPM)
+                < LOW_LOCAL_BOOTSTRAP_AMOUNT_SAT
+            {
+                skipped_budget += 1;
+                let result = "skip-budget";
+                log::info!(
+                    "balance:{:>5.1}% amount:{LOW_LOCAL_BOOTSTRAP_AMOUNT_SAT:>6}s maxppm:{LOW_LOCAL_BOOTSTRAP_MAX_PPM:>4} result:{result:<12} alias:{alias}",
+                    balance * 100.0,
+                );
+                continue;
+            }
+            budget.reserve(LOW_LOCAL_BOOTSTRAP_AMOUNT_SAT, LOW_LOCAL_BOOTSTRAP_MAX_PPM);
             bootstrap += 1;
             let candidates_arg = format!("candidates={}", candidates_to_json(&candidates));
             let scid_arg = format!("scid={scid}");
@@ -371,16 +682,22 @@ pub fn run_sling(store: &Store) {
             if execute_sling {
                 log::info!("executing `{CMD} sling-once` {alias} scid:{scid}");
 
-                let result = crate::cmd::cmd_result(CMD, &args);
-                log::debug!("cmd return: {result}");
+                match crate::cmd::cmd_result(CMD, &args) {
+                    Ok(result) => log::debug!("cmd return: {result}"),
+                    Err(e) => log::error!("sling-once for {alias} scid:{scid} failed: {e}"),
+                }
             }
             continue;
         }
 
         let source_ppm_max = compute_source_ppm_max(historical_fee_ppm, my_ppm);
-        let Some(amount_hint) =
-            compute_capacity_rebalance_amounts(channel_capacity_sat, local_balance_sat)
-        else {
+        let target_balance =
+            demand_target_balance(channel_capacity_sat, local_balance_sat, demand.routed_sat);
+        let Some(amount_hint) = compute_capacity_rebalance_amounts(
+            channel_capacity_sat,
+            local_balance_sat,
+            target_balance,
+        ) else {
             skipped_small_amount += 1;
             let result = "skip-small";
             log::info!(
@@ -393,7 +710,7 @@ pub fn run_sling(store: &Store) {
         };
 
         let job_amount = compute_job_amount(amount_hint, rebalance_jitter_seed(scid));
-        let candidates = compute_candidates(store, &channels, source_ppm_max);
+        let candidates = sling_sources::select_candidates(&sources, source_ppm_max, explore);
         if candidates.is_empty() {
             skipped_no_candidates += 1;
             let result = "skip-no-cand";
@@ -402,31 +719,10 @@ pub fn run_sling(store: &Store) {
                 balance * 100.0,
                 0,
             );
+            kept_jobs.insert(scid.clone());
             continue;
         }
 
-        let candidates_arg = format!("candidates={}", candidates_to_json(&candidates));
-
-        // Build arguments as a Vec to avoid shell quoting issues.
-        // When calling a program directly (not via shell), we pass raw values
-        // without shell-style quoting like single quotes around the JSON array.
-        let amount_arg = format!("amount={job_amount}");
-        let maxppm_arg = format!("maxppm={budget_ppm}");
-        let target_arg = format!("target={TARGET_REBALANCE_BALANCE}");
-        let deplete_percent_arg = format!("depleteuptopercent={CANDIDATE_DEPLETE_UP_TO_PERCENT}");
-        let deplete_amount_arg = format!("depleteuptoamount={CANDIDATE_DEPLETE_UP_TO_AMOUNT_SAT}");
-        let args = [
-            "sling-job",
-            "-k",
-            &format!("scid={scid}"),
-            "direction=pull",
-            &amount_arg,
-            &maxppm_arg,
-            &target_arg,
-            &candidates_arg,
-            &deplete_percent_arg,
-            &deplete_amount_arg,
-        ];
         let result = "job";
         log::info!(
             "balance:{:>5.1}% amount:{job_amount:>6}s tppm:{tppm_log:>6} hist_fee_ppm:{historical_fee_ppm_log:>6} channel_ppm:{my_ppm_log:>5} maxppm:{budget_ppm:>4} src_ppm_max:<{source_ppm_max:>4} cand:{:>3} result:{result:<12} alias:{alias}",
@@ -434,40 +730,102 @@ pub fn run_sling(store: &Store) {
             candidates.len(),
         );
         log::debug!("{alias} candidates: {candidates:?}");
-        log::debug!("{CMD} {}", args.join(" "));
-        if execute_sling {
-            log::info!("executing `{CMD} sling-job` {alias} scid:{scid}");
-
-            let result = crate::cmd::cmd_result(CMD, &args);
-            log::debug!("cmd return: {result}");
-        }
-        suggested += 1;
+        let job = SlingJob {
+            scid: scid.clone(),
+            alias: alias.to_string(),
+            amount_sat: job_amount,
+            min_amount_sat: MIN_AMOUNT_SAT,
+            max_amount_sat: amount_hint.max(MIN_AMOUNT_SAT),
+            maxppm: budget_ppm,
+            target: target_balance,
+            candidates,
+            depleteuptopercent: CANDIDATE_DEPLETE_UP_TO_PERCENT,
+            depleteuptoamount_sat: CANDIDATE_DEPLETE_UP_TO_AMOUNT_SAT,
+        };
+        planned_jobs.push(PlannedJob {
+            job,
+            capacity_sat: channel_capacity_sat,
+            local_balance_sat,
+            routed_sat: demand.routed_sat,
+        });
     }
 
+    let mut simulated = 0u64;
+    let mut skipped_infeasible = 0u64;
+    if simulate {
+        let simulator = RouteSimulator::new(store)?;
+        let result = simulate_jobs(planned_jobs, |source, target, amount_sat| {
+            simulator.route_ppm(source, target, amount_sat, SIMULATION_MAX_PPM)
+        })?;
+        planned_jobs = result.jobs;
+        simulated = result.simulated;
+        skipped_infeasible = result.infeasible;
+    }
+    let fitted = fit_to_budget(planned_jobs, &mut budget);
+    let desired_jobs = fitted.jobs;
+    skipped_budget += fitted.skipped;
+    if budget.is_exhausted() {
+        // Kept jobs would spend beyond the budget too
+        kept_jobs.clear();
+    }
+    let suggested = desired_jobs.len();
+    let changes = sling_jobs::reconcile(&existing_jobs, &desired_jobs, &kept_jobs);
+    let count = |kind: fn(&JobChange) -> bool| changes.iter().filter(|c| kind(c)).count();
+    let added = count(|c| matches!(c, JobChange::Add(_)));
+    let updated = count(|c| matches!(c, JobChange::Update(..)));
+    let deleted = count(|c| matches!(c, JobChange::Delete(_)));
+    let remaining = existing_jobs.len() + added - deleted;
     log::info!(
-        "Sling summary: suggested:{} bootstrap:{} skipped_balance:{} skipped_small_amount:{} skipped_no_candidates:{} skipped_missing_scid:{} targets_without_local_channel_info:{}",
+        "Sling summary: suggested:{} bootstrap:{} skipped_balance:{} skipped_no_demand:{} skipped_small_amount:{} skipped_no_candidates:{} skipped_unprofitable:{} skipped_budget:{} skipped_infeasible:{} simulated:{} budget_scaled:{} budget_spent:{} budget_reserved:{}sat budget_remaining:{} skipped_missing_scid:{} targets_without_local_channel_info:{} jobs_added:{} jobs_updated:{} jobs_deleted:{} jobs_unchanged:{}",
         suggested,
         bootstrap,
         skipped_balance,
+        skipped_no_demand,
         skipped_small_amount,
         skipped_no_candidates,
+        skipped_unprofitable,
+        skipped_budget,
+        skipped_infeasible,
+        simulated,
+        fitted.scaled,
+        if budget.spending_unknown {
+            "unknown".to_string()
+        } else {
+            format!("{}sat", budget.spent_msat / 1000)
+        },
+        budget.reserved_msat / 1000,
+        budget
+            .remaining_msat()
+            .map(|msat| format!("{}sat", msat / 1000))
+            .unwrap_or_else(|| "unlimited".to_string()),
         skipped_missing_scid,
-        targets_without_local_channel_info
+        targets_without_local_channel_info,
+        added,
+        updated,
+        deleted,
+        remaining - added - updated,
     );
+    if !execute_sling && !changes.is_empty() {
+        log::info!("EXECUTE_SLING is not set, the following job changes are not applied");
+    }
+    sling_jobs::apply(&changes, execute_sling);
 
-    if execute_sling && suggested > 0 {
-        start_sling_jobs();
+    if execute_sling && remaining > 0 {
+        start_sling_jobs()?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
         compute_base_rebalance_amount, compute_budget_ppm, compute_capacity_rebalance_amounts,
-        compute_job_amount, compute_source_ppm_max, enrich_sling_stats_with_last_channel_partner,
-        is_target_eligible, low_local_bootstrap_args, should_bootstrap_low_local,
+        compute_job_amount, compute_source_ppm_max, demand_target_balance,
+        enrich_sling_stats_with_last_channel_partner, fit_to_budget, has_target_demand,
+        is_target_eligible, low_local_bootstrap_args, should_bootstrap_low_local, simulate_jobs,
+        BudgetSettings, DemandSettings, PlannedJob, SlingJob, SpendBudget, TargetDemand,
         BOOTSTRAP_MAX_PPM, BUDGET_PPM_MAX, BUDGET_PPM_MIN, BUDGET_PPM_REALIZED_FEE_MULTIPLIER,
-        SOURCE_PPM_FALLBACK,
+        SOURCE_PPM_FALLBACK, TARGET_REBALANCE_BALANCE,
     };
     use serde_json::Value;
 
@@ -599,14 +957,17 @@ mod tests {
     #[test]
     fn compute_capacity_rebalance_amounts_caps_at_missing_target_balance() {
         assert_eq!(
-            compute_capacity_rebalance_amounts(1_000_000, 490_000),
+            compute_capacity_rebalance_amounts(1_000_000, 490_000, TARGET_REBALANCE_BALANCE),
             Some(10_000)
         );
     }
 
     #[test]
     fn compute_capacity_rebalance_amounts_skips_when_missing_target_is_too_small() {
-        assert_eq!(compute_capacity_rebalance_amounts(1_000_000, 495_000), None);
+        assert_eq!(
+            compute_capacity_rebalance_amounts(1_000_000, 495_000, TARGET_REBALANCE_BALANCE),
+            None
+        );
     }
 
     #[test]
@@ -615,6 +976,137 @@ mod tests {
         assert!(!is_target_eligible(0.31));
     }
 
+    #[test]
+    fn targets_need_settled_demand_and_local_failures() {
+        let settings = DemandSettings::default();
+        let demand = TargetDemand {
+            routed_sat: 20_000,
+            forwards: 2,
+            local_failures: 1,
+        };
+        assert!(has_target_demand(&demand, &settings));
+        for lacking in [
+            TargetDemand {
+                routed_sat: 19_999,
+                ..demand
+            },
+            TargetDemand {
+                forwards: 1,
+                ..demand
+            },
+            TargetDemand {
+                local_failures: 0,
+                ..demand
+            },
+        ] {
+            assert!(!has_target_demand(&lacking, &settings), "{lacking:?}");
+        }
+    }
+
+    #[test]
+    fn demand_caps_the_job_target_balance() {
+        // 100k sat local plus 270k sat routed out of a 1M sat channel
+        assert_eq!(demand_target_balance(1_000_000, 100_000, 270_000), 0.37);
+        assert_eq!(
+            demand_target_balance(1_000_000, 100_000, 900_000),
+            TARGET_REBALANCE_BALANCE
+        );
+        assert_eq!(
+            compute_capacity_rebalance_amounts(1_000_000, 100_000, 0.37),
+            Some(50_000)
+        );
+        assert_eq!(
+            compute_capacity_rebalance_amounts(1_000_000, 365_000, 0.37),
+            None
+        );
+    }
+
+    #[test]
+    fn budget_scales_down_and_skips_the_lowest_demand_jobs() {
+        let planned = |scid: &str, routed_sat| PlannedJob {
+            job: SlingJob {
+                scid: scid.to_string(),
+                alias: scid.to_string(),
+                amount_sat: 40_000,
+                min_amount_sat: 10_000,
+                max_amount_sat: 200_000,
+                maxppm: 500,
+                target: 0.5,
+                candidates: Vec::new(),
+                depleteuptopercent: 0.5,
+                depleteuptoamount_sat: 1_000_000,
+            },
+            capacity_sat: 1_000_000,
+            local_balance_sat: 300_000,
+            routed_sat,
+        };
+        let settings = BudgetSettings {
+            max_sat: Some(150),
+            ..BudgetSettings::default()
+        };
+        // 100 sats pull 200k sats at 500 ppm, the remaining 50 sats pull 100k
+        let mut budget = SpendBudget::new(&settings, 0, 0);
+        let planned_jobs = vec![
+            planned("low", 10_000),
+            planned("high", 900_000),
+            planned("mid", 50_000),
+        ];
+        let fitted = fit_to_budget(planned_jobs, &mut budget);
+        let jobs: Vec<_> = fitted
+            .jobs
+            .iter()
+            .map(|job| (job.scid.as_str(), job.target, job.max_amount_sat))
+            .collect();
+        assert_eq!(jobs, [("high", 0.5, 200_000), ("mid", 0.4, 100_000)]);
+        assert_eq!((fitted.scaled, fitted.skipped), (1, 1));
+        assert!(budget.is_exhausted());
+    }
+
+    #[test]
+    fn simulation_skips_jobs_without_a_route_within_maxppm() {
+        let job = |scid: &str, maxppm| PlannedJob {
+            job: SlingJob {
+                scid: scid.to_string(),
+                alias: scid.to_string(),
+                amount_sat: 40_000,
+                min_amount_sat: 10_000,
+                max_amount_sat: 200_000,
+                maxppm,
+                target: 0.5,
+                candidates: vec!["1x1x1".to_string(), "2x2x2".to_string()],
+                depleteuptopercent: 0.5,
+                depleteuptoamount_sat: 1_000_000,
+            },
+            capacity_sat: 1_000_000,
+            local_balance_sat: 300_000,
+            routed_sat: 100_000,
+        };
+        let jobs = vec![
+            job("cheap", 300),
+            job("pricey", 100),
+            job("unroutable", 300),
+        ];
+        let mut queries = Vec::new();
+        let simulated = simulate_jobs(jobs, |source, target, amount_sat| {
+            queries.push(format!("{source}>{target}:{amount_sat}"));
+            Ok(match (source, target) {
+                (_, "unroutable") | ("1x1x1", _) => None,
+                ("2x2x2", "cheap") => Some(250),
+                _ => Some(400),
+            })
+        })
+        .unwrap();
+        let scids: Vec<_> = simulated
+            .jobs
+            .iter()
+            .map(|planned| planned.job.scid.as_str())
+            .collect();
+        assert_eq!(scids, ["cheap"]);
+        assert_eq!((simulated.simulated, simulated.infeasible), (3, 2));
+        assert_eq!(queries.len(), 6);
+        assert_eq!(queries[1], "2x2x2>cheap:40000");
+    }
+
     #[test]
     fn low_local_bootstrap_uses_sling_once_arguments() {
         assert_eq!(
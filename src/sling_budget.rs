@@ -0,0 +1,174 @@
+// Rolling cap on the fees spent by all rebalances together.
+//
+// Each Sling job only bounds its own `maxppm`. The budget limits the
+// bookkeeper rebalance fees of the last `days`, as a fixed amount of sats, a
+// percentage of the forwarding fees earned over the same days, or the smaller
+// of both. `lightdash sling` reserves the worst-case fee of every job it
+// creates against what is left and scales down or skips the jobs that do not
+// fit. Without bookkeeper data the spending is unknown, and a configured
+// budget counts as exhausted.
+
+use crate::store::Store;
+
+pub const BUDGET_DAYS: i64 = 7;
+
+/// Spend limits, set with `lightdash sling` options. Without limits the budget
+/// is unlimited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BudgetSettings {
+    pub days: i64,
+    pub max_sat: Option<u64>,
+    pub max_fee_percent: Option<f64>,
+}
+
+impl Default for BudgetSettings {
+    fn default() -> Self {
+        BudgetSettings {
+            days: BUDGET_DAYS,
+            max_sat: None,
+            max_fee_percent: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpendBudget {
+    /// None when no limit is configured.
+    pub limit_msat: Option<u64>,
+    /// Rebalance fees paid over the budget days.
+    pub spent_msat: u64,
+    /// Forwarding fees earned over the budget days.
+    pub earned_msat: u64,
+    /// Worst-case fees of the jobs planned by this run.
+    pub reserved_msat: u64,
+    /// Bookkeeper was unavailable, so `spent_msat` is not known.
+    pub spending_unknown: bool,
+}
+
+impl SpendBudget {
+    pub fn new(settings: &BudgetSettings, spent_msat: u64, earned_msat: u64) -> Self {
+        let limit_msat = [
+            settings.max_sat.map(|sat| sat * 1000),
+            settings
+                .max_fee_percent
+                .map(|percent| (earned_msat as f64 * percent / 100.0) as u64),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        SpendBudget {
+            limit_msat,
+            spent_msat,
+            earned_msat,
+            reserved_msat: 0,
+            spending_unknown: false,
+        }
+    }
+
+    /// Budget over the rebalances and forwards of the last `settings.days`.
+    pub fn from_store(store: &Store, settings: &BudgetSettings) -> Self {
+        let spent_msat = store
+            .rebalance_parts_last_days(settings.days)
+            .iter()
+            .map(|part| part.fees_msat)
+            .sum();
+        let earned_msat = store
+            .filter_settled_forwards_by_days(settings.days)
+            .iter()
+            .map(|forward| forward.fee_msat)
+            .sum();
+        SpendBudget {
+            spending_unknown: !store.bookkeeper_loaded(),
+            ..Self::new(settings, spent_msat, earned_msat)
+        }
+    }
+
+    pub fn remaining_msat(&self) -> Option<u64> {
+        self.limit_msat.map(|limit| {
+            if self.spending_unknown {
+                0
+            } else {
+                limit.saturating_sub(self.spent_msat + self.reserved_msat)
+            }
+        })
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining_msat() == Some(0)
+    }
+
+    /// Sats, at most `amount_sat`, whose rebalance at `maxppm` still fits.
+    pub fn affordable_sat(&self, amount_sat: u64, maxppm: u64) -> u64 {
+        match self.remaining_msat() {
+            Some(remaining) if maxppm > 0 => (remaining * 1000 / maxppm).min(amount_sat),
+            _ => amount_sat,
+        }
+    }
+
+    /// Reserve the worst-case fee of rebalancing `amount_sat` at `maxppm`.
+    pub fn reserve(&mut self, amount_sat: u64, maxppm: u64) {
+        self.reserved_msat += amount_sat * maxppm / 1000;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_smaller_limit_applies() {
+        let settings = BudgetSettings {
+            max_sat: Some(1_000),
+            max_fee_percent: Some(20.0),
+            ..BudgetSettings::default()
+        };
+        assert_eq!(
+            SpendBudget::new(&settings, 0, 10_000_000).limit_msat,
+            Some(1_000_000)
+        );
+        assert_eq!(
+            SpendBudget::new(&settings, 0, 2_000_000).limit_msat,
+            Some(400_000)
+        );
+        let unlimited = SpendBudget::new(&BudgetSettings::default(), 5_000_000, 0);
+        assert_eq!(unlimited.remaining_msat(), None);
+        assert_eq!(unlimited.affordable_sat(100_000, 500), 100_000);
+    }
+
+    #[test]
+    fn reservations_use_up_the_remaining_budget() {
+        let settings = BudgetSettings {
+            max_sat: Some(100),
+            ..BudgetSettings::default()
+        };
+        let mut budget = SpendBudget::new(&settings, 40_000, 0);
+        assert_eq!(budget.remaining_msat(), Some(60_000));
+        // 60 sats pay 120,000 sats at 500 ppm
+        assert_eq!(budget.affordable_sat(200_000, 500), 120_000);
+        assert_eq!(budget.affordable_sat(50_000, 500), 50_000);
+        budget.reserve(100_000, 500);
+        assert_eq!(budget.remaining_msat(), Some(10_000));
+        budget.reserve(20_000, 500);
+        assert!(budget.is_exhausted());
+        assert_eq!(budget.affordable_sat(50_000, 500), 0);
+    }
+
+    #[test]
+    fn unknown_spending_exhausts_a_configured_budget() {
+        let settings = BudgetSettings {
+            max_sat: Some(100),
+            ..BudgetSettings::default()
+        };
+        let budget = SpendBudget {
+            spending_unknown: true,
+            ..SpendBudget::new(&settings, 0, 0)
+        };
+        assert!(budget.is_exhausted());
+        assert_eq!(budget.affordable_sat(50_000, 500), 0);
+        let unlimited = SpendBudget {
+            spending_unknown: true,
+            ..SpendBudget::new(&BudgetSettings::default(), 0, 0)
+        };
+        assert_eq!(unlimited.affordable_sat(50_000, 500), 50_000);
+    }
+}
@@ -0,0 +1,366 @@
+// Reconciliation of the persistent Sling jobs with the jobs `lightdash sling`
+// wants.
+//
+// Instead of stopping and deleting every job on each run, the jobs reported by
+// `sling-jobsettings` are compared with the desired ones: missing jobs are
+// added, differing jobs are stopped and replaced, jobs of channels that are no
+// longer targets are deleted, and matching jobs keep running undisturbed.
+//
+// The desired `maxppm` and candidates move a little with every day of
+// history, so small moves do not count as differences: an existing `maxppm`
+// up to `MAXPPM_TOLERANCE_PERCENT` below the desired one is kept, never a
+// higher one, which would exceed the budget reserved for the job, and
+// candidate sets differ only when more than a
+// `1 / CANDIDATE_CHANGE_TOLERANCE` share of the candidates changes.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use serde::Deserialize;
+
+use crate::error::LightdashError;
+
+const CMD: &str = "lightning-cli";
+/// An existing `maxppm` this much below the desired one is kept.
+const MAXPPM_TOLERANCE_PERCENT: u64 = 10;
+/// Candidate sets differ when more than one candidate in this many is added
+/// or removed.
+const CANDIDATE_CHANGE_TOLERANCE: usize = 4;
+
+/// One job as reported by `sling-jobsettings`, keyed by short channel id.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct JobSettings {
+    pub sat_direction: String,
+    pub amount_msat: u64,
+    pub maxppm: u64,
+    #[serde(default)]
+    pub target: Option<f64>,
+    #[serde(default)]
+    pub candidates: Option<Vec<String>>,
+    #[serde(default)]
+    pub depleteuptopercent: Option<f64>,
+    #[serde(default)]
+    pub depleteuptoamount: Option<u64>,
+}
+
+/// A pull job wanted for a target channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlingJob {
+    pub scid: String,
+    pub alias: String,
+    pub amount_sat: u64,
+    /// Operation amounts from `min_amount_sat` to `max_amount_sat` are
+    /// acceptable; an existing job keeps its amount within them.
+    pub min_amount_sat: u64,
+    pub max_amount_sat: u64,
+    pub maxppm: u64,
+    pub target: f64,
+    pub candidates: Vec<String>,
+    pub depleteuptopercent: f64,
+    pub depleteuptoamount_sat: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobChange {
+    Add(SlingJob),
+    /// The job is stopped and replaced; the strings describe what differs.
+    Update(SlingJob, Vec<String>),
+    Delete(String),
+}
+
+impl SlingJob {
+    /// Arguments of the `sling-job` call creating the job.
+    pub fn args(&self) -> Vec<String> {
+        // Raw values without shell quoting: the program is called directly.
+        vec![
+            "sling-job".to_string(),
+            "-k".to_string(),
+            format!("scid={}", self.scid),
+            "direction=pull".to_string(),
+            format!("amount={}", self.amount_sat),
+            format!("maxppm={}", self.maxppm),
+            format!("target={}", self.target),
+            format!(
+                "candidates={}",
+                serde_json::to_string(&self.candidates).expect("candidates serialize")
+            ),
+            format!("depleteuptopercent={}", self.depleteuptopercent),
+            format!("depleteuptoamount={}", self.depleteuptoamount_sat),
+        ]
+    }
+
+    /// What differs between `existing` and this job. An acceptable existing
+    /// amount is not a difference.
+    fn differences(&self, existing: &JobSettings) -> Vec<String> {
+        let mut differences = Vec::new();
+        if !existing.sat_direction.eq_ignore_ascii_case("pull") {
+            differences.push(format!("direction:{}->pull", existing.sat_direction));
+        }
+        if !self.accepts_amount(existing.amount_msat) {
+            differences.push(format!(
+                "amount:{}->{}",
+                existing.amount_msat / 1000,
+                self.amount_sat
+            ));
+        }
+        if existing.maxppm > self.maxppm
+            || (self.maxppm - existing.maxppm) * 100 > self.maxppm * MAXPPM_TOLERANCE_PERCENT
+        {
+            differences.push(format!("maxppm:{}->{}", existing.maxppm, self.maxppm));
+        }
+        if !existing
+            .target
+            .is_some_and(|target| (target - self.target).abs() < 0.005)
+        {
+            differences.push(format!(
+                "target:{}->{}",
+                option_display(existing.target),
+                self.target
+            ));
+        }
+        let existing_candidates: BTreeSet<&str> = existing
+            .candidates
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        let candidates: BTreeSet<&str> = self.candidates.iter().map(String::as_str).collect();
+        let added = candidates.difference(&existing_candidates).count();
+        let removed = existing_candidates.difference(&candidates).count();
+        if (added + removed) * CANDIDATE_CHANGE_TOLERANCE > candidates.len() {
+            differences.push(format!("candidates:+{added}-{removed}"));
+        }
+        if !existing
+            .depleteuptopercent
+            .is_some_and(|percent| (percent - self.depleteuptopercent).abs() < 1e-9)
+        {
+            differences.push(format!(
+                "depleteuptopercent:{}->{}",
+                option_display(existing.depleteuptopercent),
+                self.depleteuptopercent
+            ));
+        }
+        if existing.depleteuptoamount != Some(self.depleteuptoamount_sat * 1000) {
+            differences.push(format!(
+                "depleteuptoamount:{}->{}",
+                option_display(existing.depleteuptoamount.map(|msat| msat / 1000)),
+                self.depleteuptoamount_sat
+            ));
+        }
+        differences
+    }
+
+    fn accepts_amount(&self, amount_msat: u64) -> bool {
+        (self.min_amount_sat * 1000..=self.max_amount_sat * 1000).contains(&amount_msat)
+    }
+}
+
+fn option_display<T: ToString>(value: Option<T>) -> String {
+    value.map_or("none".to_string(), |value| value.to_string())
+}
+
+/// Jobs currently configured in Sling.
+pub fn current_jobs() -> Result<BTreeMap<String, JobSettings>, LightdashError> {
+    let v = if crate::cmd::using_test_data() {
+        crate::cmd::cmd_result("cat", &["test-json/sling-jobsettings"])?
+    } else {
+        crate::cmd::cmd_result(CMD, &["sling-jobsettings"])?
+    };
+    serde_json::from_value(v).map_err(|e| LightdashError::json("sling-jobsettings", e))
+}
+
+/// Changes turning the `existing` jobs into the `desired` ones. Existing jobs
+/// of channels in `keep` are left alone even when not desired.
+pub fn reconcile(
+    existing: &BTreeMap<String, JobSettings>,
+    desired: &[SlingJob],
+    keep: &HashSet<String>,
+) -> Vec<JobChange> {
+    let mut changes = Vec::new();
+    for job in desired {
+        match existing.get(&job.scid) {
+            None => changes.push(JobChange::Add(job.clone())),
+            Some(settings) => {
+                let differences = job.differences(settings);
+                if !differences.is_empty() {
+                    let mut job = job.clone();
+                    if job.accepts_amount(settings.amount_msat) {
+                        job.amount_sat = settings.amount_msat / 1000;
+                    }
+                    changes.push(JobChange::Update(job, differences));
+                }
+            }
+        }
+    }
+    let desired_scids: HashSet<&str> = desired.iter().map(|job| job.scid.as_str()).collect();
+    for scid in existing.keys() {
+        if !desired_scids.contains(scid.as_str()) && !keep.contains(scid) {
+            changes.push(JobChange::Delete(scid.clone()));
+        }
+    }
+    changes
+}
+
+/// Log the changes and, when `execute`, run them. Failed calls are logged
+/// and the remaining changes still run.
+pub fn apply(changes: &[JobChange], execute: bool) {
+    for change in changes {
+        let calls: Vec<Vec<String>> = match change {
+            JobChange::Add(job) => {
+                log::info!(
+                    "sling-job ADD {} amount:{}s maxppm:{} target:{} cand:{} alias:{}",
+                    job.scid,
+                    job.amount_sat,
+                    job.maxppm,
+                    job.target,
+                    job.candidates.len(),
+                    job.alias
+                );
+                vec![job.args()]
+            }
+            JobChange::Update(job, differences) => {
+                log::info!(
+                    "sling-job UPD {} {} alias:{}",
+                    job.scid,
+                    differences.join(" "),
+                    job.alias
+                );
+                vec![vec!["sling-stop".to_string(), job.scid.clone()], job.args()]
+            }
+            JobChange::Delete(scid) => {
+                log::info!("sling-job DEL {scid}");
+                vec![vec!["sling-deletejob".to_string(), scid.clone()]]
+            }
+        };
+        for args in calls {
+            log::debug!("{CMD} {}", args.join(" "));
+            if !execute {
+                continue;
+            }
+            match crate::cmd::cmd_result(CMD, &args) {
+                Ok(result) => log::debug!("cmd return: {result}"),
+                Err(e) => {
+                    log::error!("`{CMD} {}` failed: {e}", args[0]);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobsettings_fixture_parses() {
+        let content = std::fs::read_to_string("test-json/sling-jobsettings").unwrap();
+        let jobs: BTreeMap<String, JobSettings> = serde_json::from_str(&content).unwrap();
+        assert_eq!(jobs.len(), 9);
+        let job = &jobs["848864x399x0"];
+        assert_eq!(job.sat_direction, "Push");
+        assert_eq!(job.depleteuptoamount, Some(100_000_000));
+    }
+
+    #[test]
+    fn only_differing_jobs_change() {
+        let unchanged = job("1x1x1");
+        let mut changed = job("2x2x2");
+        changed.maxppm = 400;
+        let added = job("3x3x3");
+        let existing = BTreeMap::from([
+            ("1x1x1".to_string(), settings(&unchanged)),
+            ("2x2x2".to_string(), settings(&job("2x2x2"))),
+            ("4x4x4".to_string(), settings(&job("4x4x4"))),
+            ("5x5x5".to_string(), settings(&job("5x5x5"))),
+        ]);
+        let keep = HashSet::from(["5x5x5".to_string()]);
+
+        let changes = reconcile(
+            &existing,
+            &[unchanged, changed.clone(), added.clone()],
+            &keep,
+        );
+        assert_eq!(
+            changes,
+            [
+                JobChange::Update(changed, vec!["maxppm:500->400".to_string()]),
+                JobChange::Add(added),
+                JobChange::Delete("4x4x4".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn small_maxppm_and_candidate_moves_keep_the_job() {
+        let mut desired = job("1x1x1");
+        desired.candidates = (1..=8).map(|n| format!("{n}x0x0")).collect();
+        let mut existing = settings(&desired);
+        existing.maxppm = 460;
+        existing.candidates.as_mut().unwrap().pop();
+        let existing = BTreeMap::from([("1x1x1".to_string(), existing)]);
+        assert!(reconcile(&existing, &[desired.clone()], &HashSet::new()).is_empty());
+
+        // a higher maxppm than reserved, or a bigger candidate change, is not
+        desired.maxppm = 450;
+        desired.candidates.truncate(6);
+        let changes = reconcile(&existing, &[desired.clone()], &HashSet::new());
+        let [JobChange::Update(_, differences)] = changes.as_slice() else {
+            panic!("expected an update, got {changes:?}");
+        };
+        assert_eq!(differences, &["maxppm:460->450", "candidates:+1-2"]);
+        desired.maxppm = 520;
+        desired.candidates.push("9x0x0".to_string());
+        let changes = reconcile(&existing, &[desired], &HashSet::new());
+        let [JobChange::Update(_, differences)] = changes.as_slice() else {
+            panic!("expected an update, got {changes:?}");
+        };
+        assert_eq!(differences, &["maxppm:460->520", "candidates:+2-2"]);
+    }
+
+    #[test]
+    fn an_acceptable_existing_amount_is_kept() {
+        let mut desired = job("1x1x1");
+        desired.amount_sat = 20_000;
+        let mut existing = settings(&job("1x1x1"));
+        existing.amount_msat = 80_000_000;
+        let existing = BTreeMap::from([("1x1x1".to_string(), existing)]);
+        assert!(reconcile(&existing, &[desired.clone()], &HashSet::new()).is_empty());
+
+        desired.maxppm = 300;
+        desired.max_amount_sat = 40_000;
+        let changes = reconcile(&existing, &[desired.clone()], &HashSet::new());
+        let [JobChange::Update(job, differences)] = changes.as_slice() else {
+            panic!("expected an update, got {changes:?}");
+        };
+        assert_eq!(job.amount_sat, 20_000);
+        assert_eq!(differences, &["amount:80000->20000", "maxppm:500->300"]);
+    }
+
+    fn job(scid: &str) -> SlingJob {
+        SlingJob {
+            scid: scid.to_string(),
+            alias: "alias".to_string(),
+            amount_sat: 40_000,
+            min_amount_sat: 10_000,
+            max_amount_sat: 160_000,
+            maxppm: 500,
+            target: 0.5,
+            candidates: vec!["9x9x9".to_string(), "8x8x8".to_string()],
+            depleteuptopercent: 0.5,
+            depleteuptoamount_sat: 1_000_000,
+        }
+    }
+
+    fn settings(job: &SlingJob) -> JobSettings {
+        JobSettings {
+            sat_direction: "Pull".to_string(),
+            amount_msat: job.amount_sat * 1000,
+            maxppm: job.maxppm,
+            target: Some(job.target),
+            // Sling may report the candidates in another order
+            candidates: Some(job.candidates.iter().rev().cloned().collect()),
+            depleteuptopercent: Some(job.depleteuptopercent),
+            depleteuptoamount: Some(job.depleteuptoamount_sat * 1000),
+        }
+    }
+}
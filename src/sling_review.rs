@@ -0,0 +1,360 @@
+// Payback of past rebalances, reported by `lightdash sling review` and used
+// by `lightdash sling` to pause targets whose rebalances do not pay back.
+//
+// Every rebalance part credited to a target is a lot of sats that cost its
+// fee. Settled forwards out of the target after the rebalance spend the lots
+// first in, first out, and the forwarding fee of the spent share is earned by
+// the lot. Liquidity the channel already had is only used once the lots are
+// spent, so the earnings credited to rebalances are an upper bound.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::cmd::SettledForward;
+use crate::store::{RebalancePart, Store};
+
+/// Rebalances older than this are not reviewed.
+pub const REVIEW_DAYS: i64 = 90;
+/// Rebalances younger than this are not yet expected to have paid back.
+pub const PAYBACK_GRACE_DAYS: i64 = 14;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payback {
+    /// The earnings cover the fees paid.
+    Profitable,
+    /// Not paid back yet, but only rebalances within the grace period are
+    /// behind.
+    Pending,
+    /// Rebalances older than the grace period earned less than they cost.
+    Unprofitable,
+}
+
+impl Payback {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Profitable => "profitable",
+            Self::Pending => "pending",
+            Self::Unprofitable => "unprofitable",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SlingReview {
+    /// RFC 3339 time the review was computed.
+    pub generated_at: String,
+    pub days: i64,
+    pub grace_days: i64,
+    pub targets: Vec<TargetReview>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TargetReview {
+    pub short_channel_id: String,
+    pub alias: String,
+    /// Distinct rebalance payments credited to the channel.
+    pub rebalances: usize,
+    pub pushed_in_msat: u64,
+    pub fees_paid_msat: u64,
+    /// Rebalanced msat forwarded out again.
+    pub spent_msat: u64,
+    /// Forwarding fees earned on the rebalanced msat.
+    pub fees_earned_msat: u64,
+    pub net_msat: i64,
+    /// RFC 3339 time of the first reviewed rebalance.
+    pub first_rebalance_at: String,
+    /// RFC 3339 time the earnings covered every reviewed rebalance fee.
+    pub break_even_at: Option<String>,
+    pub days_to_break_even: Option<f64>,
+    pub payback: Payback,
+}
+
+struct Lot {
+    timestamp: DateTime<Utc>,
+    remaining_msat: u64,
+    fees_msat: u64,
+    earned_msat: u64,
+}
+
+/// Review the rebalances of the last `days` of every target channel.
+pub fn review(store: &Store, days: i64, now: DateTime<Utc>) -> SlingReview {
+    let since = now - Duration::days(days);
+    let mut parts_by_target: BTreeMap<&str, Vec<&RebalancePart>> = BTreeMap::new();
+    for part in store.rebalance_parts() {
+        let (Some(target), Some(timestamp)) = (part.target_channel_id.as_deref(), part.timestamp)
+        else {
+            continue;
+        };
+        if DateTime::from_timestamp(timestamp as i64, 0).is_some_and(|time| time >= since) {
+            parts_by_target.entry(target).or_default().push(part);
+        }
+    }
+    let settled = store.settled_forwards();
+
+    let targets = parts_by_target
+        .into_iter()
+        .filter_map(|(short_channel_id, parts)| {
+            let forwards: Vec<_> = settled
+                .iter()
+                .filter(|forward| forward.out_channel == short_channel_id)
+                .collect();
+            let alias = store
+                .get_fund(short_channel_id)
+                .map(|fund| store.get_node_alias(&fund.peer_id))
+                .unwrap_or_else(|| "closed".to_string());
+            review_target(short_channel_id, alias, &parts, &forwards, now)
+        })
+        .collect();
+
+    SlingReview {
+        generated_at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        days,
+        grace_days: PAYBACK_GRACE_DAYS,
+        targets,
+    }
+}
+
+/// Targets whose matured rebalances did not pay back.
+pub fn unprofitable_targets(review: &SlingReview) -> HashSet<&str> {
+    review
+        .targets
+        .iter()
+        .filter(|target| target.payback == Payback::Unprofitable)
+        .map(|target| target.short_channel_id.as_str())
+        .collect()
+}
+
+fn review_target(
+    short_channel_id: &str,
+    alias: String,
+    parts: &[&RebalancePart],
+    forwards: &[&SettledForward],
+    now: DateTime<Utc>,
+) -> Option<TargetReview> {
+    let mut lots: Vec<Lot> = parts
+        .iter()
+        .filter_map(|part| {
+            Some(Lot {
+                timestamp: DateTime::from_timestamp(part.timestamp? as i64, 0)?,
+                remaining_msat: part.credit_msat,
+                fees_msat: part.fees_msat,
+                earned_msat: 0,
+            })
+        })
+        .collect();
+    lots.sort_by_key(|lot| lot.timestamp);
+    let first_rebalance_at = lots.first()?.timestamp;
+    let fees_paid_msat: u64 = lots.iter().map(|lot| lot.fees_msat).sum();
+
+    let mut forwards: Vec<_> = forwards
+        .iter()
+        .filter(|forward| forward.resolved_time >= first_rebalance_at)
+        .collect();
+    forwards.sort_by_key(|forward| forward.resolved_time);
+
+    let mut spent_msat = 0u64;
+    let mut fees_earned_msat = 0u64;
+    let mut break_even_at = None;
+    let mut oldest = 0;
+    for forward in forwards {
+        let mut unassigned_msat = forward.out_msat;
+        for lot in lots[oldest..]
+            .iter_mut()
+            .take_while(|lot| lot.timestamp <= forward.resolved_time)
+        {
+            if unassigned_msat == 0 {
+                break;
+            }
+            let spent = lot.remaining_msat.min(unassigned_msat);
+            let earned =
+                (forward.fee_msat as u128 * spent as u128 / forward.out_msat.max(1) as u128) as u64;
+            lot.remaining_msat -= spent;
+            lot.earned_msat += earned;
+            unassigned_msat -= spent;
+            spent_msat += spent;
+            fees_earned_msat += earned;
+        }
+        while lots.get(oldest).is_some_and(|lot| lot.remaining_msat == 0) {
+            oldest += 1;
+        }
+        if break_even_at.is_none() && fees_paid_msat > 0 && fees_earned_msat >= fees_paid_msat {
+            break_even_at = Some(forward.resolved_time);
+        }
+    }
+
+    let matured = now - Duration::days(PAYBACK_GRACE_DAYS);
+    let (matured_paid_msat, matured_earned_msat) = lots
+        .iter()
+        .filter(|lot| lot.timestamp <= matured)
+        .fold((0, 0), |(paid, earned), lot| {
+            (paid + lot.fees_msat, earned + lot.earned_msat)
+        });
+    let payback = if fees_earned_msat >= fees_paid_msat {
+        Payback::Profitable
+    } else if matured_earned_msat < matured_paid_msat {
+        Payback::Unprofitable
+    } else {
+        Payback::Pending
+    };
+
+    Some(TargetReview {
+        short_channel_id: short_channel_id.to_string(),
+        alias,
+        rebalances: parts
+            .iter()
+            .map(|part| part.payment_id.as_str())
+            .collect::<HashSet<_>>()
+            .len(),
+        pushed_in_msat: parts.iter().map(|part| part.credit_msat).sum(),
+        fees_paid_msat,
+        spent_msat,
+        fees_earned_msat,
+        net_msat: fees_earned_msat as i64 - fees_paid_msat as i64,
+        first_rebalance_at: first_rebalance_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        break_even_at: break_even_at.map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        days_to_break_even: break_even_at
+            .map(|time| (time - first_rebalance_at).num_seconds() as f64 / 86_400.0),
+        payback,
+    })
+}
+
+/// Log the payback of every target and optionally write the review as JSON.
+pub fn run_review(store: &Store, days: i64, output: Option<&str>) -> Result<(), String> {
+    let review = review(store, days, store.snapshot_time());
+    for target in &review.targets {
+        log::info!(
+            "{:<12} {} pushed:{}sat paid:{}sat spent:{}sat earned:{}sat net:{}sat break_even_days:{} alias:{}",
+            target.payback.as_str(),
+            target.short_channel_id,
+            target.pushed_in_msat / 1000,
+            target.fees_paid_msat / 1000,
+            target.spent_msat / 1000,
+            target.fees_earned_msat / 1000,
+            target.net_msat / 1000,
+            target
+                .days_to_break_even
+                .map_or("n/a".to_string(), |days| format!("{days:.1}")),
+            target.alias
+        );
+    }
+    let count = |payback| {
+        review
+            .targets
+            .iter()
+            .filter(|target| target.payback == payback)
+            .count()
+    };
+    log::info!(
+        "Sling review over {days} days: targets:{} profitable:{} pending:{} unprofitable:{}",
+        review.targets.len(),
+        count(Payback::Profitable),
+        count(Payback::Pending),
+        count(Payback::Unprofitable)
+    );
+    if let Some(path) = output {
+        let json = serde_json::to_string_pretty(&review)
+            .map_err(|e| format!("serializing sling review failed: {e}"))?;
+        fs::write(path, json + "\n")
+            .map_err(|e| format!("writing sling review `{path}` failed: {e}"))?;
+        log::info!("Wrote sling review to {path}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86_400;
+    const NOW: i64 = 100 * DAY;
+
+    #[test]
+    fn forwards_spend_rebalanced_sats_first_in_first_out() {
+        // two rebalances of 100k sat at 1000 ppm each
+        let parts = [
+            part("a", 10 * DAY, 100_000_000),
+            part("b", 20 * DAY, 100_000_000),
+        ];
+        let forwards = [
+            // before any rebalance: ignored
+            forward(5 * DAY, 50_000_000, 50_000),
+            // spends the first lot and half of the second
+            forward(30 * DAY, 150_000_000, 150_000),
+        ];
+        let review = review_parts(&parts, &forwards).unwrap();
+        assert_eq!(review.rebalances, 2);
+        assert_eq!(review.fees_paid_msat, 200_000);
+        assert_eq!(review.spent_msat, 150_000_000);
+        assert_eq!(review.fees_earned_msat, 150_000);
+        assert_eq!(review.net_msat, -50_000);
+        assert_eq!(review.break_even_at, None);
+        // the first lot paid back, the second is at half
+        assert_eq!(review.payback, Payback::Unprofitable);
+    }
+
+    #[test]
+    fn payback_is_pending_within_the_grace_period() {
+        let parts = [part("a", NOW - DAY, 100_000_000)];
+        let review = review_parts(&parts, &[]).unwrap();
+        assert_eq!(review.payback, Payback::Pending);
+    }
+
+    #[test]
+    fn break_even_is_reached_when_earnings_cover_the_fees() {
+        let parts = [part("a", 10 * DAY, 100_000_000)];
+        let forwards = [
+            forward(11 * DAY, 50_000_000, 40_000),
+            forward(13 * DAY, 50_000_000, 80_000),
+        ];
+        let review = review_parts(&parts, &forwards).unwrap();
+        assert_eq!(review.payback, Payback::Profitable);
+        assert_eq!(review.net_msat, 20_000);
+        assert_eq!(review.days_to_break_even, Some(3.0));
+    }
+
+    fn review_parts(parts: &[RebalancePart], forwards: &[SettledForward]) -> Option<TargetReview> {
+        let parts: Vec<_> = parts.iter().collect();
+        let forwards: Vec<_> = forwards.iter().collect();
+        review_target(
+            "1x1x1",
+            "alias".to_string(),
+            &parts,
+            &forwards,
+            DateTime::from_timestamp(NOW, 0).unwrap(),
+        )
+    }
+
+    fn part(payment_id: &str, timestamp: i64, credit_msat: u64) -> RebalancePart {
+        RebalancePart {
+            payment_id: payment_id.to_string(),
+            part_id: 0,
+            source_account: "source".to_string(),
+            target_account: "target".to_string(),
+            source_channel_id: Some("2x2x2".to_string()),
+            target_channel_id: Some("1x1x1".to_string()),
+            debit_msat: credit_msat + credit_msat / 1000,
+            credit_msat,
+            fees_msat: credit_msat / 1000,
+            timestamp: Some(timestamp as u64),
+        }
+    }
+
+    fn forward(resolved_time: i64, out_msat: u64, fee_msat: u64) -> SettledForward {
+        let time = DateTime::from_timestamp(resolved_time, 0).unwrap();
+        SettledForward {
+            in_channel: "3x3x3".to_string(),
+            out_channel: "1x1x1".to_string(),
+            fee_msat,
+            out_msat,
+            fee_sat: fee_msat / 1000,
+            out_sat: out_msat / 1000,
+            fee_ppm: fee_msat * 1_000_000 / out_msat,
+            resolved_time: time,
+            received_time: time,
+        }
+    }
+}
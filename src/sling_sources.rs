@@ -0,0 +1,227 @@
+// Opportunity cost of the source channels Sling may drain.
+//
+// Draining a source gives up the outbound forwards its surplus would have
+// routed. A source with realized outbound history is valued at its realized
+// PPM, scaled by how much of its surplus recent outbound demand would use:
+//
+//     opportunity_ppm = realized_ppm * min(1, recent_out_sat / surplus_sat)
+//
+// where the surplus is the local balance above Sling's depletion floor. A
+// source routing little compared with its surplus costs little to drain; one
+// whose recent demand would use its whole surplus costs its full realized PPM.
+//
+// Draining also moves liquidity to the peer's side, which is the side forwards
+// coming in from that peer need. Those forwards earn the source's indirect
+// fees, so its realized inbound PPM, scaled the same way by recent inbound
+// demand, is credited against the cost:
+//
+//     indirect_ppm = realized_inbound_ppm * min(1, recent_in_sat / surplus_sat)
+//     cost_ppm = opportunity_ppm - indirect_ppm
+//
+// A source without outbound history is untested: its opportunity cost is
+// unknown, and no traffic is no proof that its liquidity is idle while its fee
+// is still being discovered. Untested sources are therefore only included on
+// exploration days, about one day in `SOURCE_EXPLORATION_DAYS` per source.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use chrono::{DateTime, Utc};
+
+use crate::cmd::Fund;
+use crate::store::Store;
+
+/// Days of settled forwards counted as a source's recent outbound and inbound
+/// demand.
+pub const SOURCE_DEMAND_DAYS: i64 = 7;
+/// An untested source is included about one day in this many.
+pub const SOURCE_EXPLORATION_DAYS: u64 = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceScore {
+    pub short_channel_id: String,
+    pub advertised_ppm: u64,
+    /// Realized outbound PPM, None when the source never routed out.
+    pub realized_ppm: Option<f64>,
+    pub recent_out_sat: u64,
+    pub recent_in_sat: u64,
+    pub surplus_sat: u64,
+    pub indirect_fees_sat: u64,
+    /// None for an untested source.
+    pub opportunity_ppm: Option<f64>,
+    /// Indirect fees the drained liquidity would earn coming back in.
+    pub indirect_ppm: f64,
+}
+
+impl SourceScore {
+    pub fn is_tested(&self) -> bool {
+        self.opportunity_ppm.is_some()
+    }
+
+    /// Cost used to filter and rank: the opportunity cost, or the advertised
+    /// PPM of an untested source, less the indirect fees.
+    fn cost_ppm(&self) -> f64 {
+        self.opportunity_ppm.unwrap_or(self.advertised_ppm as f64) - self.indirect_ppm
+    }
+}
+
+pub fn opportunity_ppm(
+    realized_ppm: Option<f64>,
+    recent_out_sat: u64,
+    surplus_sat: u64,
+) -> Option<f64> {
+    let realized_ppm = realized_ppm.filter(|ppm| ppm.is_finite() && *ppm >= 0.0)?;
+    let demand_share = if surplus_sat == 0 {
+        1.0
+    } else {
+        (recent_out_sat as f64 / surplus_sat as f64).min(1.0)
+    };
+    Some(realized_ppm * demand_share)
+}
+
+/// Score the channels above `min_balance` that can be drained down to
+/// `floor_balance`.
+pub fn score_sources(
+    store: &Store,
+    channels: &[Fund],
+    min_balance: f64,
+    floor_balance: f64,
+) -> Vec<SourceScore> {
+    let mut recent_out_by_channel: HashMap<String, u64> = HashMap::new();
+    let mut recent_in_by_channel: HashMap<String, u64> = HashMap::new();
+    for forward in store.filter_settled_forwards_by_days(SOURCE_DEMAND_DAYS) {
+        *recent_in_by_channel.entry(forward.in_channel).or_default() +=
+            forward.out_sat + forward.fee_sat;
+        *recent_out_by_channel
+            .entry(forward.out_channel)
+            .or_default() += forward.out_sat;
+    }
+    channels
+        .iter()
+        .filter(|ch| ch.perc_float() > min_balance)
+        .filter_map(|ch| {
+            let scid = ch.short_channel_id.as_ref()?;
+            let our = store.get_channel(scid, &store.info.id)?;
+            let capacity_sat = ch.amount_msat / 1000;
+            let floor_sat = (capacity_sat as f64 * floor_balance) as u64;
+            let surplus_sat = (ch.our_amount_msat / 1000).saturating_sub(floor_sat);
+            let realized_ppm = store.get_channel_effective_fee_ppm(scid);
+            let recent_out_sat = recent_out_by_channel.get(scid).copied().unwrap_or_default();
+            let recent_in_sat = recent_in_by_channel.get(scid).copied().unwrap_or_default();
+            let inbound_ppm = store.get_channel_indirect_fee_ppm(scid);
+            Some(SourceScore {
+                short_channel_id: scid.clone(),
+                advertised_ppm: our.fee_per_millionth,
+                realized_ppm,
+                recent_out_sat,
+                recent_in_sat,
+                surplus_sat,
+                indirect_fees_sat: store.get_channel_indirect_fees(scid),
+                opportunity_ppm: opportunity_ppm(realized_ppm, recent_out_sat, surplus_sat),
+                indirect_ppm: opportunity_ppm(inbound_ppm, recent_in_sat, surplus_sat)
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Sources cheaper than `source_ppm_max`, cheapest first. Untested sources are
+/// only included when `explore` accepts them.
+pub fn select_candidates(
+    sources: &[SourceScore],
+    source_ppm_max: u64,
+    explore: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut selected: Vec<&SourceScore> = sources
+        .iter()
+        .filter(|source| source.cost_ppm() < source_ppm_max as f64)
+        .filter(|source| source.is_tested() || explore(&source.short_channel_id))
+        .collect();
+    selected.sort_by(|a, b| a.cost_ppm().total_cmp(&b.cost_ppm()));
+    selected
+        .into_iter()
+        .map(|source| source.short_channel_id.clone())
+        .collect()
+}
+
+/// Whether an untested source is explored on the day of `now`. Stable within
+/// a day, so repeated runs do not churn the jobs' candidate sets.
+pub fn is_exploration_day(short_channel_id: &str, now: DateTime<Utc>) -> bool {
+    let mut hasher = DefaultHasher::new();
+    short_channel_id.hash(&mut hasher);
+    now.timestamp().div_euclid(86_400).hash(&mut hasher);
+    hasher.finish() % SOURCE_EXPLORATION_DAYS == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_surplus_costs_little() {
+        assert_eq!(opportunity_ppm(Some(800.0), 0, 500_000), Some(0.0));
+        assert_eq!(opportunity_ppm(Some(800.0), 125_000, 500_000), Some(200.0));
+        assert_eq!(opportunity_ppm(Some(800.0), 900_000, 500_000), Some(800.0));
+        assert_eq!(opportunity_ppm(None, 900_000, 500_000), None);
+        assert_eq!(opportunity_ppm(Some(f64::NAN), 0, 500_000), None);
+    }
+
+    #[test]
+    fn candidates_are_ranked_and_untested_sources_explored() {
+        let source = |scid: &str, opportunity_ppm, indirect_ppm| SourceScore {
+            short_channel_id: scid.to_string(),
+            advertised_ppm: 100,
+            realized_ppm: opportunity_ppm,
+            recent_out_sat: 0,
+            recent_in_sat: 0,
+            surplus_sat: 500_000,
+            indirect_fees_sat: 0,
+            opportunity_ppm,
+            indirect_ppm,
+        };
+        let sources = [
+            source("expensive", Some(400.0), 0.0),
+            source("cheap", Some(20.0), 0.0),
+            source("incoming", Some(60.0), 50.0),
+            source("untested", None, 0.0),
+        ];
+        // the indirect fees make a dearer source the cheapest
+        assert_eq!(
+            select_candidates(&sources, 300, |_| false),
+            ["incoming", "cheap"]
+        );
+        assert_eq!(
+            select_candidates(&sources, 300, |_| true),
+            ["incoming", "cheap", "untested"]
+        );
+        // an untested source still has to advertise below the ceiling
+        assert_eq!(
+            select_candidates(&sources, 100, |_| true),
+            ["incoming", "cheap"]
+        );
+        // and they can bring a source under the ceiling
+        let sources = [source("expensive-incoming", Some(400.0), 150.0)];
+        assert_eq!(
+            select_candidates(&sources, 300, |_| false),
+            ["expensive-incoming"]
+        );
+    }
+
+    #[test]
+    fn exploration_is_stable_within_a_day() {
+        let morning = DateTime::from_timestamp(86_400 * 20_000 + 3_600, 0).unwrap();
+        let evening = DateTime::from_timestamp(86_400 * 20_000 + 80_000, 0).unwrap();
+        let explored_days = (0..400)
+            .filter(|day| {
+                let now = DateTime::from_timestamp(86_400 * day, 0).unwrap();
+                is_exploration_day("1x1x1", now)
+            })
+            .count();
+        assert_eq!(
+            is_exploration_day("1x1x1", morning),
+            is_exploration_day("1x1x1", evening)
+        );
+        assert!((50..=150).contains(&explored_days), "{explored_days}");
+    }
+}
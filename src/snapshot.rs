@@ -6,14 +6,16 @@ use std::path::Path;
 use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::availability::AvailabilityState;
 use crate::cmd::{self, ClosedChannel, Forward, Fund};
 use crate::common::channel_balance_target_stddev_percentage_points;
+use crate::fees::FeeChange;
 use crate::history;
 use crate::routes;
 use crate::snapshot_metadata::{build_dataset_metadata, DatasetCounts, DatasetMetadata};
 use crate::store::{RebalancePart, Store};
 
-pub(crate) const SCHEMA_VERSION: u32 = 24;
+pub(crate) const SCHEMA_VERSION: u32 = 26;
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct SnapshotManifest {
@@ -34,6 +36,7 @@ pub(crate) struct SnapshotFiles {
     pub other_forwards: String,
     pub rebalances: String,
     pub rebalance_status: String,
+    pub fee_changes: String,
     pub history_manifest: Option<String>,
     pub routes_manifest: Option<String>,
 }
@@ -109,6 +112,8 @@ pub(crate) struct ChannelSnapshot {
     pub local_balance_percent: Option<f64>,
     pub age_days: Option<i64>,
     pub uptime_ratio: Option<f64>,
+    pub availability_state: Option<AvailabilityState>,
+    pub availability_state_since: Option<String>,
     pub outbound_fee_ppm: Option<u64>,
     pub inbound_fee_ppm: Option<u64>,
     pub outbound_base_fee_msat: Option<u64>,
@@ -229,6 +234,13 @@ struct RebalanceStatusSnapshot {
     last_success_at: Option<String>,
 }
 
+#[derive(Serialize)]
+struct FeeChangeSnapshot<'a> {
+    #[serde(flatten)]
+    change: &'a FeeChange,
+    peer_alias: String,
+}
+
 #[derive(Default)]
 struct ChannelForwardMetrics {
     settled_forward_count: usize,
@@ -263,6 +275,7 @@ pub fn run_snapshot(
         other_forwards: "other-forwards.jsonl".to_string(),
         rebalances: "rebalances.jsonl".to_string(),
         rebalance_status: "rebalance-status.json".to_string(),
+        fee_changes: "fee-changes.jsonl".to_string(),
         history_manifest: None,
         routes_manifest: None,
     };
@@ -277,6 +290,7 @@ pub fn run_snapshot(
             other_forwards: store.forwards_len() - settled_forward_count,
             rebalances: store.rebalance_parts().count(),
             rebalance_status: rebalance_status.len(),
+            fee_changes: store.fee_changes().len(),
         },
     );
     let include_history =
@@ -379,6 +393,13 @@ pub fn run_snapshot(
             .map(|part| build_rebalance_snapshot(store, part)),
     )?;
     write_json(directory.join("rebalance-status.json"), &rebalance_status)?;
+    write_json_lines(
+        directory.join("fee-changes.jsonl"),
+        store
+            .fee_changes()
+            .iter()
+            .map(|change| build_fee_change_snapshot(store, change)),
+    )?;
 
     log::info!("Snapshot generated successfully in {}", directory.display());
     Ok(())
@@ -567,6 +588,7 @@ fn build_channel_snapshot(
         .unwrap_or(&empty_rebalances);
     let age_days = short_channel_id.and_then(|scid| store.get_channel_age_days(scid));
     let lease_fees = store.lease_fee_totals_for_account(&channel.channel_id);
+    let availability = store.get_availability_record(&channel.peer_id);
     let gross_routing_revenue_msat = forwards.forwarding_fees_sat as i64 * 1000;
     let net_routing_revenue_msat = gross_routing_revenue_msat - rebalances.target_cost_msat as i64;
     let gross_revenue_msat = gross_routing_revenue_msat as i128 + lease_fees.earned_msat as i128;
@@ -607,6 +629,8 @@ fn build_channel_snapshot(
         },
         age_days,
         uptime_ratio: store.avail_map.get(&channel.peer_id).copied(),
+        availability_state: availability.map(|record| record.state),
+        availability_state_since: availability.map(|record| record.since.clone()),
         outbound_fee_ppm: local_update
             .map(|update| update.fee_proportional_millionths)
             .or_else(|| {
@@ -903,8 +927,15 @@ fn build_rebalance_snapshot<'a>(store: &Store, part: &'a RebalancePart) -> Rebal
     }
 }
 
+fn build_fee_change_snapshot<'a>(store: &Store, change: &'a FeeChange) -> FeeChangeSnapshot<'a> {
+    FeeChangeSnapshot {
+        change,
+        peer_alias: store.get_node_alias(&change.peer_id),
+    }
+}
+
 fn build_rebalance_status_snapshot(store: &Store) -> io::Result<Vec<RebalanceStatusSnapshot>> {
-    let raw: Vec<RawRebalanceStatus> = serde_json::from_value(crate::sling::current_sling_stats())
+    let raw: Vec<RawRebalanceStatus> = serde_json::from_value(crate::sling::current_sling_stats()?)
         .map_err(|e| io::Error::other(format!("parsing current Sling status failed: {e}")))?;
 
     raw.into_iter()
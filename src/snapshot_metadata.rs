@@ -41,6 +41,7 @@ pub(crate) struct DatasetCounts {
     pub other_forwards: usize,
     pub rebalances: usize,
     pub rebalance_status: usize,
+    pub fee_changes: usize,
 }
 
 pub(crate) fn build_dataset_metadata(
@@ -132,6 +133,18 @@ pub(crate) fn build_dataset_metadata(
                 rebalance_status_fields(),
             ),
         ),
+        (
+            "fee_changes".to_string(),
+            dataset(
+                &files.fee_changes,
+                "fee-changes.schema.json",
+                "jsonl",
+                "Channel policy updates applied by `lightdash fees`, with the controller inputs behind each one.",
+                counts.fee_changes,
+                None,
+                fee_change_fields(),
+            ),
+        ),
     ])
 }
 
@@ -143,6 +156,7 @@ pub(crate) fn field_tooltip(dataset_name: &str, field_name: &str) -> Option<Stri
         "settled_forwards" | "other_forwards" => forward_fields(),
         "rebalances" => rebalance_fields(),
         "rebalance_status" => rebalance_status_fields(),
+        "fee_changes" => fee_change_fields(),
         _ => return None,
     };
     let metadata = fields.get(field_name)?;
@@ -275,6 +289,8 @@ fn channel_fields() -> BTreeMap<String, FieldMetadata> {
         ("local_balance_percent".into(), formula(field("number", true, Some("percent"), "Local balance as a percentage of full channel capacity."), "local_balance_msat / capacity_msat * 100")),
         ("age_days".into(), warning(formula(field("integer", true, Some("day"), "Approximate channel age inferred from the short channel ID opening block."), "(snapshot_block_height - opening_block_height) / 144"), "Uses an assumed average of 144 Bitcoin blocks per day.")),
         ("uptime_ratio".into(), source(field("number", true, Some("ratio_0_to_1"), "Peer availability ratio measured by the Summars availability database."), "Summars availdb avail field")),
+        ("availability_state".into(), source(field("string", true, None, "Peer availability state of the fee controller: healthy, degraded (maximum HTLC capped in proportion to availability), or disabled (1 msat HTLC limits). Null until a fee run records a transition."), "datastore lightdash/availability/<peer_id>.state")),
+        ("availability_state_since".into(), source(field("string", true, Some("rfc3339_utc"), "Time the peer entered its current availability state."), "datastore lightdash/availability/<peer_id>.since")),
         ("outbound_fee_ppm".into(), source(field("integer", true, Some("ppm"), "Current proportional fee configured by the local node for outbound forwarding."), "listpeerchannels local update (or legacy top-level fee), falling back to the listchannels direction sourced by the local node")),
         ("inbound_fee_ppm".into(), source(field("integer", true, Some("ppm"), "Current proportional fee advertised by the remote peer toward the local node."), "listpeerchannels remote update, falling back to the listchannels direction sourced by the peer")),
         ("outbound_base_fee_msat".into(), source(field("integer", true, Some("msat"), "Current fixed fee configured by the local node for outbound forwarding."), "listpeerchannels local update (or legacy top-level fee), falling back to the listchannels direction sourced by the local node")),
@@ -513,6 +529,30 @@ fn rebalance_status_fields() -> BTreeMap<String, FieldMetadata> {
     ])
 }
 
+fn fee_change_fields() -> BTreeMap<String, FieldMetadata> {
+    const SOURCE: &str = "datastore lightdash/fee_changes/<short_channel_id>";
+    BTreeMap::from([
+        ("changed_at".into(), source(field("string", false, Some("rfc3339_utc"), "Time the `setchannel` command succeeded."), SOURCE)),
+        ("short_channel_id".into(), source(field("string", false, None, "Channel whose outbound policy changed."), SOURCE)),
+        ("peer_id".into(), source(field("string", false, None, "Public key of the channel peer."), SOURCE)),
+        ("peer_alias".into(), source(field("string", false, None, "Gossip alias of the peer, or an abbreviated peer ID when no alias is advertised."), "listnodes joined through peer_id")),
+        ("action".into(), source(field("string", false, None, "Controller decision: EQU keeps the ppm and changes only HTLC limits, INC and DEC move the ppm, DIS disables forwarding for low peer availability."), SOURCE)),
+        ("old_base_fee_msat".into(), source(field("integer", false, Some("msat"), "Base fee before the change."), SOURCE)),
+        ("new_base_fee_msat".into(), source(field("integer", false, Some("msat"), "Base fee set by the change."), SOURCE)),
+        ("old_fee_ppm".into(), source(field("integer", false, Some("ppm"), "Proportional fee before the change."), SOURCE)),
+        ("new_fee_ppm".into(), source(field("integer", false, Some("ppm"), "Proportional fee set by the change."), SOURCE)),
+        ("old_htlc_min_msat".into(), source(field("integer", false, Some("msat"), "Minimum HTLC before the change."), SOURCE)),
+        ("new_htlc_min_msat".into(), source(field("integer", false, Some("msat"), "Minimum HTLC set by the change."), SOURCE)),
+        ("old_htlc_max_msat".into(), source(field("integer", false, Some("msat"), "Maximum HTLC before the change."), SOURCE)),
+        ("new_htlc_max_msat".into(), source(field("integer", false, Some("msat"), "Maximum HTLC set by the change."), SOURCE)),
+        ("state".into(), source(field("string", false, None, "Controller channel state: bootstrap, normal, or depleted."), SOURCE)),
+        ("activity".into(), source(field("string", false, None, "Grade of the settled outbound forwards counted by the step: none, below_minimum, light, moderate, or strong."), SOURCE)),
+        ("routed_since_watermark_msat".into(), source(field("integer", false, Some("msat"), "Settled outbound amount counted by the step: since the channel's forward watermark, or over the previous 24 hours without one."), SOURCE)),
+        ("availability".into(), source(field("number", true, Some("ratio_0_to_1"), "Peer availability ratio from the Summars availability database at decision time, when known."), SOURCE)),
+        ("lightdash_version".into(), source(field("string", false, None, "Lightdash version that applied the change."), SOURCE)),
+    ])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
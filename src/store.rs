@@ -1,5 +1,9 @@
+use crate::availability::AvailabilityRecord;
 use crate::cmd::{self, datastore_string, DatastoreMode, Forward, SettledForward};
 use crate::common::ChannelFee;
+use crate::error::LightdashError;
+use crate::fees::{self, FeeChange};
+use crate::forwards;
 use chrono::{DateTime, Datelike, Utc};
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
@@ -29,6 +33,7 @@ pub struct Store {
     pub nodes: cmd::ListNodes,
     pub closed_channels: cmd::ListClosedChannels,
     rebalance_parts: Vec<RebalancePart>,
+    bookkeeper_loaded: bool,
     income_events: Vec<cmd::BkprIncomeEvent>,
     // Cached computed data
     nodes_by_id: HashMap<String, cmd::Node>,
@@ -37,6 +42,10 @@ pub struct Store {
     forward_cache: ForwardCache,
     peer_notes: HashMap<String, String>,
     setchannel_timestamps: HashMap<String, i64>,
+    fee_changes: Vec<FeeChange>,
+    fee_watermarks: HashMap<String, f64>,
+    inbound_discounts: HashMap<String, u64>,
+    availability_records: HashMap<String, AvailabilityRecord>,
     now: DateTime<Utc>,
     pub avail_map: HashMap<String, f64>,
 }
@@ -110,6 +119,7 @@ struct ChannelForwardMetrics {
     settled_count: usize,
     outbound_fees_sat: u64,
     indirect_fees_sat: u64,
+    routed_in_sat: u64,
     routed_out_sat: u64,
     weighted_tppm_fees_msat: f64,
     weighted_tppm_routed_msat: f64,
@@ -144,6 +154,7 @@ fn build_forward_cache(forwards: &cmd::ListForwards, now: DateTime<Utc>) -> Forw
             .or_default();
         incoming.settled_count += 1;
         incoming.indirect_fees_sat += forward.fee_sat;
+        incoming.routed_in_sat += forward.out_sat + forward.fee_sat;
         settled_indices_by_channel
             .entry(forward.in_channel.clone())
             .or_default()
@@ -278,23 +289,93 @@ pub(crate) fn match_rebalance_parts(
         .collect()
 }
 
+/// Run one fetch of `Store::new`, logging how long it took.
+fn timed<T>(name: &str, fetch: impl FnOnce() -> T) -> T {
+    let started = std::time::Instant::now();
+    let result = fetch();
+    log::info!("Fetched {name} in {:.2}s", started.elapsed().as_secs_f64());
+    result
+}
+
+fn join<T>(handle: std::thread::ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
 impl Store {
     /// Create a new Store by fetching all data from the Lightning node
-    pub fn new(availdb: Option<String>) -> Self {
+    ///
+    /// Bookkeeper data is optional: when it cannot be fetched, rebalance and
+    /// income metrics are empty instead of failing the whole store.
+    pub fn new(availdb: Option<String>) -> Result<Self, LightdashError> {
         let start_time = std::time::Instant::now();
         log::debug!("Fetching data from Lightning node...");
         let now = Utc::now();
-        let info = cmd::get_info();
-        let channels = cmd::list_channels();
-        let peer_channels = cmd::list_peer_channels();
-        let peers = cmd::list_peers();
-        let funds = cmd::list_funds();
-        let forwards = cmd::list_forwards();
-        let account_events = cmd::bkpr_list_account_events();
-        let income_events = cmd::bkpr_list_income().income_events;
-        let nodes = cmd::list_nodes();
-        let closed_channels = cmd::list_closed_channels();
-        log::debug!("Data fetched successfully");
+        let info = timed("getinfo", cmd::get_info)?;
+        let (
+            channels,
+            peer_channels,
+            peers,
+            funds,
+            forwards,
+            account_events,
+            income,
+            nodes,
+            closed_channels,
+            availdb_json,
+        ) = std::thread::scope(|scope| {
+            let channels = scope.spawn(|| timed("listchannels", cmd::list_channels));
+            let peer_channels = scope.spawn(|| timed("listpeerchannels", cmd::list_peer_channels));
+            let peers = scope.spawn(|| timed("listpeers", cmd::list_peers));
+            let funds = scope.spawn(|| timed("listfunds", cmd::list_funds));
+            let forwards =
+                scope.spawn(|| timed("listforwards", || forwards::list_forwards(&info.id)));
+            let account_events =
+                scope.spawn(|| timed("bkpr-listaccountevents", cmd::bkpr_list_account_events));
+            let income = scope.spawn(|| timed("bkpr-listincome", cmd::bkpr_list_income));
+            let nodes = scope.spawn(|| timed("listnodes", cmd::list_nodes));
+            let closed_channels =
+                scope.spawn(|| timed("listclosedchannels", cmd::list_closed_channels));
+            let availdb_json =
+                scope.spawn(|| timed("availdb", || cmd::read_availdb_json(availdb.as_deref())));
+            (
+                join(channels),
+                join(peer_channels),
+                join(peers),
+                join(funds),
+                join(forwards),
+                join(account_events),
+                join(income),
+                join(nodes),
+                join(closed_channels),
+                join(availdb_json),
+            )
+        });
+        let channels = channels?;
+        let peer_channels = peer_channels?;
+        let peers = peers?;
+        let funds = funds?;
+        let forwards = forwards?;
+        let bookkeeper_loaded = account_events.is_ok();
+        let account_events = account_events.unwrap_or_else(|e| {
+            log::warn!(
+                "Bookkeeper account events are unavailable, skipping rebalance metrics: {e}"
+            );
+            cmd::BkprListAccountEvents::default()
+        });
+        let income_events = income
+            .map(|income| income.income_events)
+            .unwrap_or_else(|e| {
+                log::warn!("Bookkeeper income is unavailable, skipping income metrics: {e}");
+                Vec::new()
+            });
+        let nodes = nodes?;
+        let closed_channels = closed_channels?;
+        log::info!(
+            "Fetched node data in {:.2}s",
+            start_time.elapsed().as_secs_f64()
+        );
         let forward_cache = build_forward_cache(&forwards, now);
         log::info!(
             "Cached {} settled forwards across {} channels",
@@ -302,8 +383,7 @@ impl Store {
             forward_cache.metrics_by_channel.len()
         );
 
-        log::info!("Loading availdb");
-        let avail_map: HashMap<String, f64> = match cmd::read_availdb_json(availdb.as_deref()) {
+        let avail_map: HashMap<String, f64> = match availdb_json {
             Ok(value) => match serde_json::from_value::<HashMap<String, Value>>(value) {
                 Ok(outer) => outer
                     .into_iter()
@@ -401,15 +481,104 @@ impl Store {
             }
         }
 
+        // Query fee change logs from datastore
+        let mut fee_changes = Vec::new();
+        if let Ok(datastore) = cmd::listdatastore(Some(&["lightdash", "fee_changes"])) {
+            for entry in datastore.datastore {
+                // The key format is ["lightdash", "fee_changes", "short_channel_id"]
+                if entry.key.len() == 3
+                    && entry.key[0] == "lightdash"
+                    && entry.key[1] == "fee_changes"
+                {
+                    if let Some(log) = &entry.string {
+                        fee_changes.extend(fees::parse_fee_changes(&entry.key[2], log));
+                    }
+                }
+            }
+            fee_changes.sort_by(|a, b| a.changed_at.cmp(&b.changed_at));
+            log::info!("Loaded {} fee changes from datastore", fee_changes.len());
+        }
+
+        // Query fee watermarks from datastore
+        let mut fee_watermarks = HashMap::new();
+        if let Ok(datastore) = cmd::listdatastore(Some(&["lightdash", "fee_watermark"])) {
+            log::info!(
+                "Loaded {} fee watermarks from datastore",
+                datastore.datastore.len()
+            );
+            for entry in datastore.datastore {
+                // The key format is ["lightdash", "fee_watermark", "short_channel_id"]
+                if entry.key.len() == 3
+                    && entry.key[0] == "lightdash"
+                    && entry.key[1] == "fee_watermark"
+                {
+                    if let Some(watermark) =
+                        entry.string.as_deref().and_then(|value| value.parse().ok())
+                    {
+                        fee_watermarks.insert(entry.key[2].clone(), watermark);
+                    }
+                }
+            }
+        }
+
+        // Query inbound discounts from datastore
+        let mut inbound_discounts = HashMap::new();
+        if let Ok(datastore) = cmd::listdatastore(Some(&["lightdash", "inbound_discount"])) {
+            log::info!(
+                "Loaded {} inbound discounts from datastore",
+                datastore.datastore.len()
+            );
+            for entry in datastore.datastore {
+                // The key format is ["lightdash", "inbound_discount", "short_channel_id"]
+                if entry.key.len() == 3
+                    && entry.key[0] == "lightdash"
+                    && entry.key[1] == "inbound_discount"
+                {
+                    if let Some(discount_ppm) =
+                        entry.string.as_deref().and_then(|value| value.parse().ok())
+                    {
+                        inbound_discounts.insert(entry.key[2].clone(), discount_ppm);
+                    }
+                }
+            }
+        }
+
+        // Query peer availability states from datastore
+        let mut availability_records = HashMap::new();
+        if let Ok(datastore) = cmd::listdatastore(Some(&["lightdash", "availability"])) {
+            log::info!(
+                "Loaded {} peer availability states from datastore",
+                datastore.datastore.len()
+            );
+            for entry in datastore.datastore {
+                // The key format is ["lightdash", "availability", "peer_id"]
+                if entry.key.len() == 3
+                    && entry.key[0] == "lightdash"
+                    && entry.key[1] == "availability"
+                {
+                    match entry.string.as_deref().map(serde_json::from_str) {
+                        Some(Ok(record)) => {
+                            availability_records.insert(entry.key[2].clone(), record);
+                        }
+                        Some(Err(e)) => log::warn!(
+                            "Skipping invalid availability state of {}: {e}",
+                            entry.key[2]
+                        ),
+                        None => {}
+                    }
+                }
+            }
+        }
+
         let timestamp = Utc::now().timestamp().to_string();
-        let result = datastore_string(
+        match datastore_string(
             &["lightdash", "last_run", &timestamp],
             &timestamp,
             DatastoreMode::CreateOrReplace,
-        )
-        .unwrap();
-
-        log::info!("Last run timestamp saved: {:?}", result);
+        ) {
+            Ok(result) => log::info!("Last run timestamp saved: {:?}", result),
+            Err(e) => log::warn!("Saving last run timestamp failed: {e}"),
+        }
 
         let store = Self {
             info,
@@ -421,6 +590,7 @@ impl Store {
             nodes,
             closed_channels,
             rebalance_parts,
+            bookkeeper_loaded,
             income_events,
             nodes_by_id,
             channels_by_id,
@@ -428,6 +598,10 @@ impl Store {
             forward_cache,
             peer_notes,
             setchannel_timestamps,
+            fee_changes,
+            fee_watermarks,
+            inbound_discounts,
+            availability_records,
             now,
             avail_map,
         };
@@ -438,7 +612,7 @@ impl Store {
             duration.as_secs_f64()
         );
 
-        store
+        Ok(store)
     }
 
     /// Get normal channels (channels in CHANNELD_NORMAL state)
@@ -456,11 +630,14 @@ impl Store {
         self.forward_cache.settled.clone()
     }
 
-    pub fn settled_out_channel_ids(&self) -> impl Iterator<Item = &str> {
-        self.forward_cache
-            .settled
-            .iter()
-            .map(|forward| forward.out_channel.as_str())
+    /// Lifetime settled amount forwarded out of each channel.
+    pub fn settled_out_msat_by_channel(&self) -> HashMap<&str, u64> {
+        let mut totals = HashMap::new();
+        for forward in &self.forward_cache.settled {
+            let total: &mut u64 = totals.entry(forward.out_channel.as_str()).or_default();
+            *total = total.saturating_add(forward.out_msat);
+        }
+        totals
     }
 
     /// Filter settled forwards to only include those resolved within the last N days
@@ -522,6 +699,12 @@ impl Store {
         self.rebalance_parts.iter()
     }
 
+    /// Whether the bookkeeper account events loaded. Without them no
+    /// rebalance is known, however many were paid.
+    pub fn bookkeeper_loaded(&self) -> bool {
+        self.bookkeeper_loaded
+    }
+
     pub fn snapshot_time(&self) -> DateTime<Utc> {
         self.now
     }
@@ -881,6 +1064,26 @@ impl Store {
         self.setchannel_timestamps.get(short_channel_id).copied()
     }
 
+    /// Resolved time of the last settled forward counted by a fee step
+    pub fn get_fee_watermark(&self, short_channel_id: &str) -> Option<f64> {
+        self.fee_watermarks.get(short_channel_id).copied()
+    }
+
+    /// Inbound discount in ppm last set by `lightdash fees`
+    pub fn get_inbound_discount(&self, short_channel_id: &str) -> Option<u64> {
+        self.inbound_discounts.get(short_channel_id).copied()
+    }
+
+    /// Availability state of a peer saved by the last applied fee run
+    pub fn get_availability_record(&self, peer_id: &str) -> Option<&AvailabilityRecord> {
+        self.availability_records.get(peer_id)
+    }
+
+    /// Applied fee changes recorded in the datastore, oldest first
+    pub fn fee_changes(&self) -> &[FeeChange] {
+        &self.fee_changes
+    }
+
     /// Get fee distribution data for a specific peer based on its channels
     pub fn get_peer_fee_distribution(&self, peer_id: &str) -> FeeDistribution {
         let ppm_ranges = Self::generate_ppm_ranges();
@@ -1038,6 +1241,20 @@ impl Store {
             .unwrap_or_default()
     }
 
+    /// Get historical indirect fee rate in ppm of a channel as the incoming
+    /// side: indirect fees divided by the amount routed in through it.
+    pub fn get_channel_indirect_fee_ppm(&self, short_channel_id: &str) -> Option<f64> {
+        let metrics = self
+            .forward_cache
+            .metrics_by_channel
+            .get(short_channel_id)?;
+        if metrics.routed_in_sat == 0 {
+            return None;
+        }
+
+        Some(metrics.indirect_fees_sat as f64 * 1_000_000.0 / metrics.routed_in_sat as f64)
+    }
+
     pub fn get_channel_forwarding_fee_totals(&self, short_channel_id: &str) -> (u64, u64) {
         self.forward_cache
             .metrics_by_channel
@@ -1549,19 +1766,6 @@ impl Store {
 
         (average, median)
     }
-
-    pub(crate) fn filter_forwards_by_hours(&self, hours: i64) -> Vec<Forward> {
-        self.forwards
-            .forwards
-            .iter()
-            .filter(move |f| {
-                let received_time =
-                    DateTime::from_timestamp(f.received_time as i64, 0).unwrap_or(self.now);
-                self.now.signed_duration_since(received_time).num_hours() <= hours
-            })
-            .cloned()
-            .collect()
-    }
 }
 
 fn feature_bit_is_set(features: &str, bit: usize) -> bool {
@@ -1654,6 +1858,8 @@ mod tests {
         resolved_time: i64,
     ) -> cmd::Forward {
         cmd::Forward {
+            created_index: None,
+            updated_index: None,
             in_channel: in_channel.to_string(),
             out_channel: Some(out_channel.to_string()),
             fee_msat: Some(fee_msat),
@@ -1664,6 +1870,7 @@ mod tests {
             resolved_time: Some(resolved_time as f64),
             failreason: None,
             failcode: None,
+            payment_hash: None,
         }
     }
 
@@ -1732,6 +1939,7 @@ mod tests {
                 closedchannels: vec![],
             },
             rebalance_parts,
+            bookkeeper_loaded: true,
             income_events: vec![],
             nodes_by_id: HashMap::new(),
             channels_by_id: HashMap::new(),
@@ -1739,6 +1947,10 @@ mod tests {
             forward_cache,
             peer_notes: HashMap::new(),
             setchannel_timestamps: HashMap::new(),
+            fee_changes: Vec::new(),
+            fee_watermarks: HashMap::new(),
+            inbound_discounts: HashMap::new(),
+            availability_records: HashMap::new(),
             now,
             avail_map: HashMap::new(),
         }
@@ -2278,7 +2490,7 @@ mod tests {
     #[cfg(feature = "large-fixture-tests")]
     #[test]
     fn gz_bkpr_fixture_matches_expected_rebalance_parts() {
-        let events = cmd::bkpr_list_account_events();
+        let events = cmd::bkpr_list_account_events().unwrap();
         let parts = match_rebalance_parts(&events.events, &HashMap::new());
 
         assert_eq!(parts.len(), 1827);